@@ -0,0 +1,169 @@
+use crate::{cidr::Network, log, stats};
+use std::{
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Largest request head the stats server reads before giving up on a request
+const MAX_REQUEST_HEAD: usize = 1024;
+
+/// How long the stats server gives a client to send its whole request, and separately to read the
+/// response, before dropping it
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves the proxy's statistics, active connections, and health on `listener` from a thread of
+/// its own, identifying itself with `server_name` in each response's `Server` header. A `POST` to
+/// `/connections/ID/close` closes the active connection with that id. Only clients in `controllers`,
+/// or on loopback if it's empty, may list or close connections; others are refused with a 403.
+///
+/// Each client is served on a thread of its own with a minimal parser, so one that is slow to send
+/// its request can't hold up health checks, and nothing sent here goes near the forwarding path. Once `shutting_down` is set, health checks fail so load balancers
+/// stop sending traffic, while the statistics can still be watched as connections drain.
+pub fn spawn(
+    listener: TcpListener,
+    server_name: String,
+    controllers: Vec<Network>,
+    shutting_down: &'static AtomicBool,
+) {
+    let shared = Arc::new((server_name, controllers));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::write_line(&format!("Stats server: {e}"));
+                    continue;
+                }
+            };
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || {
+                let (server_name, controllers) = &*shared;
+                let result = stream
+                    .set_write_timeout(Some(CLIENT_TIMEOUT))
+                    .and_then(|()| answer(&mut stream, controllers, shutting_down))
+                    .and_then(|(status, body)| respond(&mut stream, status, server_name, &body));
+                if let Err(e) = result {
+                    log::write_line(&format!("Stats server: {e}"));
+                }
+            });
+        }
+    });
+}
+
+/// Reads a request from `stream`, returning the status and body to answer it with
+fn answer(
+    stream: &mut TcpStream,
+    controllers: &[Network],
+    shutting_down: &AtomicBool,
+) -> io::Result<(&'static str, String)> {
+    // the whole request must arrive in time, however slowly it trickles in
+    let deadline = Instant::now() + CLIENT_TIMEOUT;
+    let mut head = Vec::new();
+    let mut chunk = [0; 256];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_HEAD {
+            return Ok((
+                "431 Request Header Fields Too Large",
+                "Request too large\n".to_owned(),
+            ));
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Request not received in time",
+            ));
+        }
+        stream.set_read_timeout(Some(remaining))?;
+        match stream.read(&mut chunk)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            bytes => head.extend_from_slice(&chunk[..bytes]),
+        }
+    }
+
+    let line = head.split(|&byte| byte == b'\r').next().unwrap_or_default();
+    let mut parts = line.split(|&byte| byte == b' ');
+    let (Some(method), Some(path), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Ok(("400 Bad Request", "Malformed request line\n".to_owned()));
+    };
+    if !version.starts_with(b"HTTP/1.") {
+        return Ok(("400 Bad Request", "Malformed request line\n".to_owned()));
+    }
+
+    // connections show where clients are browsing and can be closed from here, so they're kept
+    // from anyone who can merely reach the health checks
+    let client = stream.peer_addr()?.ip();
+    let controls = if controllers.is_empty() {
+        client.is_loopback()
+    } else {
+        controllers.iter().any(|network| network.contains(client))
+    };
+    let forbidden = || {
+        Ok((
+            "403 Forbidden",
+            "Connections may not be listed or closed from this address\n".to_owned(),
+        ))
+    };
+
+    let close_id = path
+        .strip_prefix(b"/connections/")
+        .and_then(|rest| rest.strip_suffix(b"/close"))
+        .and_then(|id| std::str::from_utf8(id).ok()?.parse().ok());
+    if let Some(id) = close_id {
+        if !controls {
+            return forbidden();
+        }
+        if method != b"POST" {
+            return Ok((
+                "405 Method Not Allowed",
+                "Connections are closed with POST\n".to_owned(),
+            ));
+        }
+        return Ok(if stats::close(id) {
+            ("200 OK", "closing\n".to_owned())
+        } else {
+            ("404 Not Found", "No such connection\n".to_owned())
+        });
+    }
+    if method != b"GET" {
+        return Ok((
+            "405 Method Not Allowed",
+            "Only GET is supported\n".to_owned(),
+        ));
+    }
+
+    Ok(match path {
+        b"/health" if shutting_down.load(Ordering::SeqCst) => {
+            ("503 Service Unavailable", "draining\n".to_owned())
+        }
+        b"/health" => ("200 OK", "ok\n".to_owned()),
+        b"/stats" => ("200 OK", snapshot()),
+        b"/connections" if !controls => return forbidden(),
+        b"/connections" => ("200 OK", stats::connections()),
+        _ => ("404 Not Found", "Not found\n".to_owned()),
+    })
+}
+
+/// The current statistics as `name value` lines
+fn snapshot() -> String {
+    stats::counters()
+        .iter()
+        .map(|counter| format!("{} {}\n", counter.name, counter.value))
+        .collect()
+}
+
+fn respond(stream: &mut TcpStream, status: &str, server_name: &str, body: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {status}\r\nServer: {server_name}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes())
+}
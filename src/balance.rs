@@ -0,0 +1,124 @@
+use crate::clock::{Clock, SystemClock};
+use std::{
+    collections::HashMap,
+    io,
+    net::{SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// How long an address that could not be connected to is tried only after the others
+const FAILED_ADDR_PENALTY: Duration = Duration::from_secs(30);
+
+/// Spreads new server connections across all the addresses a host resolves to, instead of always
+/// using the first that can be reached.
+///
+/// Each connection starts from the address after the one the last started from, so an address
+/// listed more than once gets a proportionally larger share. Addresses that recently failed are
+/// tried last.
+pub struct Balancer<C: Clock = SystemClock> {
+    clock: C,
+    next: AtomicUsize,
+    /// when each address that could not be connected to last failed
+    failed: Mutex<HashMap<SocketAddr, Instant>>,
+}
+
+impl Balancer {
+    pub fn new() -> Balancer {
+        Balancer::with_clock(SystemClock)
+    }
+}
+
+impl<C: Clock> Balancer<C> {
+    /// A balancer timing how long failed addresses are tried last by `clock`
+    pub fn with_clock(clock: C) -> Balancer<C> {
+        Balancer {
+            clock,
+            next: AtomicUsize::new(0),
+            failed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Connects to one of `addrs`, trying each of them once in turn
+    pub fn connect(
+        &self,
+        addrs: &[SocketAddr],
+        timeout: Option<Duration>,
+    ) -> io::Result<TcpStream> {
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Address resolved to nothing",
+            ));
+        }
+
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % addrs.len();
+        let mut order: Vec<SocketAddr> = Vec::new();
+        for addr in addrs[start..].iter().chain(&addrs[..start]) {
+            if !order.contains(addr) {
+                order.push(*addr);
+            }
+        }
+        {
+            let now = self.clock.now();
+            let mut failed = self.failed.lock().unwrap();
+            failed.retain(|_, since| now.duration_since(*since) < FAILED_ADDR_PENALTY);
+            // a stable sort keeps the rotation among the healthy addresses
+            order.sort_by_key(|addr| failed.contains_key(addr));
+        }
+
+        let mut last_error = None;
+        for addr in order {
+            match crate::connect(addr, timeout) {
+                Ok(stream) => {
+                    self.failed.lock().unwrap().remove(&addr);
+                    return Ok(stream);
+                }
+                Err(e) => {
+                    self.failed.lock().unwrap().insert(addr, self.clock.now());
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::net::TcpListener;
+
+    #[test]
+    fn rotates_across_addresses_and_tries_failed_ones_last_until_the_penalty_ends() {
+        let clock = ManualClock::new();
+        let balancer = Balancer::with_clock(&clock);
+        let listeners: Vec<_> = (0..2)
+            .map(|_| TcpListener::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let addrs: Vec<_> = listeners
+            .iter()
+            .map(|listener| listener.local_addr().unwrap())
+            .collect();
+        let connect = || balancer.connect(&addrs, None).unwrap().peer_addr().unwrap();
+        assert_eq!(connect(), addrs[0]);
+        assert_eq!(connect(), addrs[1]);
+
+        // fail the first address, then bring it back
+        drop(listeners);
+        let _second = TcpListener::bind(addrs[1]).unwrap();
+        assert_eq!(connect(), addrs[1]);
+        let _first = TcpListener::bind(addrs[0]).unwrap();
+        assert_eq!(connect(), addrs[1]);
+        // it would be the first address's turn, but it failed too recently
+        assert_eq!(connect(), addrs[1]);
+
+        clock.advance(FAILED_ADDR_PENALTY);
+        assert_eq!(connect(), addrs[1]);
+        assert_eq!(connect(), addrs[0]);
+    }
+}
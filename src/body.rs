@@ -0,0 +1,448 @@
+use crate::http::{Header, Response};
+use std::io::{self, Read, Write};
+
+/// Longest chunk-size or trailer line accepted in a chunked body
+const MAX_LINE_LENGTH: usize = 4096;
+
+/// How the end of a message body is found
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BodyLength {
+    /// the message has no body
+    Empty,
+    /// the body is exactly this many bytes, as given by `Content-Length`
+    Fixed(u64),
+    /// the body is in chunked transfer coding and ends with a zero-size chunk
+    Chunked,
+    /// the body is everything the server sends until it closes the connection
+    UntilClose,
+}
+
+impl BodyLength {
+    /// Determines the length of a request body from the request's headers
+    pub fn of_request(headers: &[Header]) -> Result<BodyLength, String> {
+        if let Some(coding) = final_transfer_coding(headers) {
+            // chunked must be the final coding of a request, otherwise its length is unknowable
+            return if coding.eq_ignore_ascii_case(b"chunked") {
+                Ok(BodyLength::Chunked)
+            } else {
+                Err("Request transfer coding does not end in chunked".to_owned())
+            };
+        }
+
+        match content_length(headers)? {
+            None | Some(0) => Ok(BodyLength::Empty),
+            Some(length) => Ok(BodyLength::Fixed(length)),
+        }
+    }
+
+    /// Determines the length of the body of `response`, sent in answer to a request with `method`
+    pub fn of_response(method: &str, response: &Response) -> Result<BodyLength, String> {
+        let status = response.status;
+        if method == "HEAD" || (100..200).contains(&status) || status == 204 || status == 304 {
+            return Ok(BodyLength::Empty);
+        }
+
+        if let Some(coding) = final_transfer_coding(&response.headers) {
+            return if coding.eq_ignore_ascii_case(b"chunked") {
+                Ok(BodyLength::Chunked)
+            } else {
+                Ok(BodyLength::UntilClose)
+            };
+        }
+
+        match content_length(&response.headers)? {
+            None => Ok(BodyLength::UntilClose),
+            Some(0) => Ok(BodyLength::Empty),
+            Some(length) => Ok(BodyLength::Fixed(length)),
+        }
+    }
+}
+
+/// Returns the last transfer coding listed in `Transfer-Encoding`, if the header is present
+fn final_transfer_coding(headers: &[Header]) -> Option<&[u8]> {
+    headers
+        .iter()
+        .filter(|header| header.name.eq_ignore_ascii_case("Transfer-Encoding"))
+        .flat_map(|header| header.value.split(|&byte| byte == b','))
+        .last()
+        .map(<[u8]>::trim_ascii)
+}
+
+/// Returns the length given by the `Content-Length` headers, failing if they are invalid or
+/// disagree with each other
+fn content_length(headers: &[Header]) -> Result<Option<u64>, String> {
+    let mut lengths = headers
+        .iter()
+        .filter(|header| header.name.eq_ignore_ascii_case("Content-Length"))
+        .map(|header| {
+            std::str::from_utf8(&header.value)
+                .ok()
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .ok_or("Invalid Content-Length")
+        });
+    let Some(length) = lengths.next().transpose()? else {
+        return Ok(None);
+    };
+    if lengths.any(|other| other != Ok(length)) {
+        return Err("Conflicting Content-Length headers".to_owned());
+    }
+    Ok(Some(length))
+}
+
+/// Bytes read from a stream but not yet consumed, in front of the rest of the stream
+struct Source<'a, R> {
+    reader: &'a mut R,
+    buffered: Vec<u8>,
+}
+
+impl<R: Read> Source<'_, R> {
+    /// Reads more of the stream into the buffer, failing if the stream has ended
+    fn fill(&mut self) -> io::Result<()> {
+        let mut chunk = [0; 4096];
+        match self.reader.read(&mut chunk)? {
+            0 => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Stream closed before the end of the body",
+            )),
+            bytes => {
+                self.buffered.extend_from_slice(&chunk[..bytes]);
+                Ok(())
+            }
+        }
+    }
+
+    /// Passes the next `length` bytes of the stream on to `out`
+    fn copy(&mut self, out: &mut impl Write, mut length: u64) -> io::Result<()> {
+        while length > 0 {
+            if self.buffered.is_empty() {
+                self.fill()?;
+            }
+            let take = self
+                .buffered
+                .len()
+                .min(usize::try_from(length).unwrap_or(usize::MAX));
+            out.write_all(&self.buffered[..take])?;
+            self.buffered.drain(..take);
+            length -= take as u64;
+        }
+        Ok(())
+    }
+
+    /// Removes and returns the next CRLF-terminated line, including the CRLF
+    fn line(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            let end = self.buffered.windows(2).position(|pair| pair == b"\r\n");
+            // the limit holds however much of the line arrived in one read
+            if end.unwrap_or(self.buffered.len()) > MAX_LINE_LENGTH {
+                return Err(invalid_data("Chunked body line too long"));
+            }
+            if let Some(end) = end {
+                return Ok(self.buffered.drain(..end + 2).collect());
+            }
+            self.fill()?;
+        }
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Parses the size from a chunk-size line, checking but otherwise ignoring any chunk extensions.
+/// Only hex digits are accepted, since servers that read signs or leading whitespace differently
+/// can be made to disagree with the proxy on where the body ends. Extensions may not hold control
+/// characters, and are only as long as `MAX_LINE_LENGTH` leaves room for.
+fn chunk_size(line: &[u8]) -> io::Result<u64> {
+    let line = &line[..line.len() - 2];
+    let (size, extensions) = line.split_at(
+        line.iter()
+            .position(|&byte| byte == b';')
+            .unwrap_or(line.len()),
+    );
+    // a bare CR or LF here could be read as the end of the line by the server
+    if extensions
+        .iter()
+        .any(|&byte| (byte < b' ' && byte != b'\t') || byte == 0x7f)
+    {
+        return Err(invalid_data("Chunk extension contains control characters"));
+    }
+
+    // only spaces and tabs may come between the size and its extensions
+    let digits = size
+        .iter()
+        .rposition(|&byte| byte != b' ' && byte != b'\t')
+        .map_or(0, |last| last + 1);
+    let size = &size[..digits];
+    if size.is_empty() || !size.iter().all(u8::is_ascii_hexdigit) {
+        return Err(invalid_data("Invalid chunk size"));
+    }
+    std::str::from_utf8(size)
+        .ok()
+        .and_then(|size| u64::from_str_radix(size, 16).ok())
+        .ok_or_else(|| invalid_data("Chunk size too large to represent"))
+}
+
+/// Passes a body of the given `length` from `reader` to `writer` unchanged.
+///
+/// `buffered` holds bytes already read from `reader`, which are sent first. Once the body has been
+/// forwarded, it holds whatever was read past the end of the body. Returns the number of bytes
+/// forwarded, including any chunked framing. A chunked body declaring a chunk of more than
+/// `max_chunk_size` bytes of data fails with `InvalidData` before the chunk is passed on, like a
+/// malformed one.
+pub fn forward(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+    buffered: &mut Vec<u8>,
+    length: BodyLength,
+    max_chunk_size: Option<u64>,
+) -> io::Result<u64> {
+    let mut source = Source {
+        reader,
+        buffered: std::mem::take(buffered),
+    };
+
+    let forwarded = match length {
+        BodyLength::Empty => 0,
+        BodyLength::Fixed(length) => {
+            source.copy(writer, length)?;
+            length
+        }
+        BodyLength::Chunked => {
+            let mut forwarded = 0;
+            loop {
+                let line = source.line()?;
+                let size = chunk_size(&line)?;
+                if max_chunk_size.is_some_and(|max| size > max) {
+                    return Err(invalid_data("Chunk larger than the maximum chunk size"));
+                }
+                writer.write_all(&line)?;
+                forwarded += line.len() as u64;
+
+                if size == 0 {
+                    // the last chunk is followed by optional trailer fields and an empty line
+                    loop {
+                        let line = source.line()?;
+                        writer.write_all(&line)?;
+                        forwarded += line.len() as u64;
+                        if line == b"\r\n" {
+                            break;
+                        }
+                    }
+                    break forwarded;
+                }
+
+                source.copy(writer, size)?;
+                if source.line()? != b"\r\n" {
+                    return Err(invalid_data("Chunk data longer than its size"));
+                }
+                writer.write_all(b"\r\n")?;
+                forwarded += size + 2;
+            }
+        }
+        BodyLength::UntilClose => {
+            writer.write_all(&source.buffered)?;
+            let buffered = source.buffered.len() as u64;
+            source.buffered.clear();
+            buffered + io::copy(source.reader, writer)?
+        }
+    };
+
+    *buffered = source.buffered;
+    Ok(forwarded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(head: &str) -> Vec<Header> {
+        head.lines()
+            .filter_map(|line| line.split_once(": "))
+            .map(|(name, value)| Header {
+                name: name.to_owned(),
+                value: value.as_bytes().to_vec(),
+            })
+            .collect()
+    }
+
+    fn response(head: &str) -> Response {
+        Response::parse(head.as_bytes().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn request_length_comes_from_transfer_encoding_or_content_length() {
+        let length = |head| BodyLength::of_request(&headers(head));
+        assert_eq!(length(""), Ok(BodyLength::Empty));
+        assert_eq!(length("Content-Length: 0"), Ok(BodyLength::Empty));
+        assert_eq!(length("Content-Length: 12"), Ok(BodyLength::Fixed(12)));
+        assert_eq!(
+            length("Content-Length: 12\nContent-Length: 12"),
+            Ok(BodyLength::Fixed(12))
+        );
+        assert_eq!(
+            length("Transfer-Encoding: gzip, Chunked\nContent-Length: 12"),
+            Ok(BodyLength::Chunked)
+        );
+        assert!(length("Transfer-Encoding: chunked, gzip").is_err());
+        assert!(length("Content-Length: 12\nContent-Length: 13").is_err());
+        assert!(length("Content-Length: twelve").is_err());
+    }
+
+    #[test]
+    fn response_length_depends_on_the_method_and_status() {
+        let fixed = response("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n");
+        assert_eq!(
+            BodyLength::of_response("GET", &fixed),
+            Ok(BodyLength::Fixed(5))
+        );
+        assert_eq!(
+            BodyLength::of_response("HEAD", &fixed),
+            Ok(BodyLength::Empty)
+        );
+        for status in [
+            "101 Switching Protocols",
+            "204 No Content",
+            "304 Not Modified",
+        ] {
+            let response = response(&format!("HTTP/1.1 {status}\r\nContent-Length: 5\r\n\r\n"));
+            assert_eq!(
+                BodyLength::of_response("GET", &response),
+                Ok(BodyLength::Empty)
+            );
+        }
+
+        let unframed = response("HTTP/1.1 200 OK\r\n\r\n");
+        assert_eq!(
+            BodyLength::of_response("GET", &unframed),
+            Ok(BodyLength::UntilClose)
+        );
+        let gzipped = response("HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip\r\n\r\n");
+        assert_eq!(
+            BodyLength::of_response("GET", &gzipped),
+            Ok(BodyLength::UntilClose)
+        );
+        let chunked = response("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n");
+        assert_eq!(
+            BodyLength::of_response("GET", &chunked),
+            Ok(BodyLength::Chunked)
+        );
+    }
+
+    /// Forwards a body of `length` from `stream`, returning what was written, the count returned,
+    /// and the bytes left over
+    fn forwarded(
+        buffered: &[u8],
+        stream: &[u8],
+        length: BodyLength,
+    ) -> io::Result<(Vec<u8>, u64, Vec<u8>)> {
+        let mut written = Vec::new();
+        let mut buffered = buffered.to_vec();
+        let count = forward(&mut &stream[..], &mut written, &mut buffered, length, None)?;
+        Ok((written, count, buffered))
+    }
+
+    #[test]
+    fn fixed_body_is_passed_on_and_the_rest_left_buffered() {
+        let (written, count, rest) = forwarded(b"hel", b"lo world", BodyLength::Fixed(5)).unwrap();
+        assert_eq!(written, b"hello");
+        assert_eq!(count, 5);
+        assert_eq!(rest, b" world");
+
+        let error = forwarded(b"", b"hel", BodyLength::Fixed(5)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn chunked_body_is_passed_on_with_its_framing_and_trailers() {
+        let body = b"4;ext=1\r\nWiki\r\n5\r\npedia\r\n0\r\nExpires: never\r\n\r\n";
+        let (written, count, rest) = forwarded(
+            &body[..6],
+            &[&body[6..], &b"GET /next"[..]].concat(),
+            BodyLength::Chunked,
+        )
+        .unwrap();
+        assert_eq!(written, body);
+        assert_eq!(count, body.len() as u64);
+        assert_eq!(rest, b"GET /next");
+    }
+
+    #[test]
+    fn chunk_longer_than_its_size_is_rejected() {
+        let error =
+            forwarded(b"", b"4\r\nWikipedia\r\n0\r\n\r\n", BodyLength::Chunked).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn chunk_over_the_maximum_size_is_rejected_before_it_is_passed_on() {
+        let mut written = Vec::new();
+        let error = forward(
+            &mut &b"11\r\n"[..],
+            &mut written,
+            &mut Vec::new(),
+            BodyLength::Chunked,
+            Some(16),
+        )
+        .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(written.is_empty());
+    }
+
+    #[test]
+    fn body_until_close_takes_everything() {
+        let (written, count, rest) = forwarded(b"ab", b"cdef", BodyLength::UntilClose).unwrap();
+        assert_eq!(written, b"abcdef");
+        assert_eq!(count, 6);
+        assert!(rest.is_empty());
+    }
+
+    fn size(line: &str) -> io::Result<u64> {
+        chunk_size(line.as_bytes())
+    }
+
+    #[test]
+    fn chunk_size_reads_hex_and_skips_extensions() {
+        assert_eq!(size("1a\r\n").unwrap(), 0x1a);
+        assert_eq!(size("0\r\n").unwrap(), 0);
+        assert_eq!(size("Ff \t;name=value\r\n").unwrap(), 0xff);
+        assert_eq!(size("4;a;b=\"c d\"\r\n").unwrap(), 4);
+    }
+
+    #[test]
+    fn chunk_size_rejects_malformed_sizes() {
+        for line in [
+            "\r\n",
+            ";ext\r\n",
+            "+4\r\n",
+            "-4\r\n",
+            " 4\r\n",
+            "0x4\r\n",
+            "4g\r\n",
+            "4\r\r\n",
+            "4\x0b\r\n",
+        ] {
+            let error = size(line).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{line:?}");
+        }
+        assert!(size("10000000000000000\r\n").is_err());
+    }
+
+    #[test]
+    fn chunk_size_rejects_control_characters_in_extensions() {
+        for line in ["4;a\rb\r\n", "4;a\nb\r\n", "4;a\0\r\n", "4;\x7f\r\n"] {
+            let error = size(line).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{line:?}");
+        }
+        assert_eq!(size("4;a=\tb\r\n").unwrap(), 4);
+    }
+
+    #[test]
+    fn chunk_extensions_are_only_limited_by_the_line_length() {
+        let fits = format!("4;{}\r\nabcd\r\n0\r\n\r\n", "x".repeat(1000));
+        assert!(forwarded(b"", fits.as_bytes(), BodyLength::Chunked).is_ok());
+
+        let too_long = format!("4;{}\r\nabcd\r\n0\r\n\r\n", "x".repeat(MAX_LINE_LENGTH));
+        let error = forwarded(b"", too_long.as_bytes(), BodyLength::Chunked).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,142 @@
+use crate::stats;
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{atomic::Ordering, Mutex},
+};
+
+/// Size of each buffer data is relayed through
+pub const BUFFER_SIZE: usize = 4096;
+
+/// Default number of free buffers kept for reuse
+pub const DEFAULT_MAX_FREE: usize = 256;
+
+/// Relay buffers kept between connections, so that connection churn doesn't mean allocating new
+/// ones for every connection.
+///
+/// Only the buffers kept while no connection uses them are limited. Every connection still gets
+/// its buffers however many are in use, so memory is bounded by the connection limits instead.
+pub struct BufferPool {
+    /// most free buffers kept, beyond which returned buffers are freed; buffers in use aren't
+    /// counted
+    pub max_free: usize,
+    free: Mutex<Vec<Box<[u8]>>>,
+}
+
+/// A buffer taken from a `BufferPool`, returned to it when dropped
+pub struct Buffer<'a> {
+    pool: &'a BufferPool,
+    data: Box<[u8]>,
+}
+
+impl BufferPool {
+    pub fn new() -> BufferPool {
+        BufferPool {
+            max_free: DEFAULT_MAX_FREE,
+            free: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Takes a zeroed buffer from the pool, allocating one if none are free
+    pub fn take(&self) -> Buffer<'_> {
+        let mut free = self.free.lock().unwrap();
+        let data = free
+            .pop()
+            .unwrap_or_else(|| vec![0; BUFFER_SIZE].into_boxed_slice());
+        stats::POOLED_BUFFERS.store(free.len(), Ordering::SeqCst);
+        Buffer { pool: self, data }
+    }
+}
+
+impl Deref for Buffer<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl DerefMut for Buffer<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+impl Drop for Buffer<'_> {
+    fn drop(&mut self) {
+        let mut free = self.pool.free.lock().unwrap();
+        if free.len() < self.pool.max_free {
+            // one connection's data must never show up in another's buffer
+            self.data.fill(0);
+            free.push(std::mem::take(&mut self.data));
+        }
+        stats::POOLED_BUFFERS.store(free.len(), Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn returned_buffers_are_zeroed_and_reused() {
+        let pool = BufferPool::new();
+        let mut buffer = pool.take();
+        buffer[..5].copy_from_slice(b"hello");
+        let address = buffer.as_ptr();
+        drop(buffer);
+
+        let buffer = pool.take();
+        assert_eq!(buffer.as_ptr(), address);
+        assert!(buffer.iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn only_free_buffers_are_capped() {
+        let pool = BufferPool {
+            max_free: 1,
+            ..BufferPool::new()
+        };
+        let taken: Vec<_> = (0..3).map(|_| pool.take()).collect();
+        assert!(taken.iter().all(|buffer| buffer.len() == BUFFER_SIZE));
+        drop(taken);
+        assert_eq!(pool.free.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn released_buffers_go_back_to_the_pool() {
+        let pool = BufferPool::new();
+        let first = pool.take();
+        let second = pool.take();
+        assert_eq!(pool.free.lock().unwrap().len(), 0);
+        drop(first);
+        assert_eq!(pool.free.lock().unwrap().len(), 1);
+        drop(second);
+        assert_eq!(pool.free.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn reader_waiting_for_data_picks_up_a_released_buffer() {
+        let pool = BufferPool::new();
+        let (data_tx, data_rx) = std::sync::mpsc::channel::<&[u8]>();
+        let pool = &pool;
+        std::thread::scope(|scope| {
+            // like a relay gone quiet, the reader holds no buffer until data arrives
+            let reader = scope.spawn(move || {
+                let data = data_rx.recv().unwrap();
+                let mut buffer = pool.take();
+                buffer[..data.len()].copy_from_slice(data);
+                (buffer.as_ptr() as usize, buffer[..data.len()].to_vec())
+            });
+
+            let released = pool.take();
+            let address = released.as_ptr() as usize;
+            drop(released);
+            data_tx.send(b"resumed").unwrap();
+
+            let (reused, read) = reader.join().unwrap();
+            assert_eq!(reused, address);
+            assert_eq!(read, b"resumed");
+        });
+        assert_eq!(pool.free.lock().unwrap().len(), 1);
+    }
+}
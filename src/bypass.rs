@@ -0,0 +1,155 @@
+use crate::cidr::Network;
+use std::net::{IpAddr, SocketAddr};
+
+/// One entry of a no-proxy list
+enum Entry {
+    /// `*`, matching every host
+    Any,
+    /// a domain, matching itself and its subdomains, with any leading dot removed
+    Domain(String),
+    /// an address range in CIDR notation, matching ip address hosts within it
+    Network(Network),
+}
+
+/// Hosts connected to directly rather than through the upstream proxies, following the
+/// conventions of the `NO_PROXY` environment variable
+#[derive(Default)]
+pub struct NoProxy(Vec<Entry>);
+
+impl NoProxy {
+    /// Parses a comma-separated list of domains (`example.com` or `.example.com`), ip
+    /// addresses, CIDR ranges (`10.0.0.0/8`), or `*`
+    pub fn parse(list: &str) -> Result<NoProxy, String> {
+        let mut entries = Vec::new();
+        for entry in list
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            entries.push(if entry == "*" {
+                Entry::Any
+            } else if entry.contains('/') {
+                Entry::Network(
+                    Network::parse(entry).ok_or(format!("Invalid no-proxy network: {entry}"))?,
+                )
+            } else if let Some(network) = Network::parse(unbracketed(entry)) {
+                Entry::Network(network)
+            } else {
+                let domain = entry.trim_start_matches('.');
+                if domain.is_empty() || domain.contains('*') {
+                    return Err(format!("Invalid no-proxy host: {entry}"));
+                }
+                Entry::Domain(domain.to_ascii_lowercase())
+            });
+        }
+        Ok(NoProxy(entries))
+    }
+
+    /// Whether connections to `host`, a domain or ip address, skip the upstream proxies
+    pub fn matches(&self, host: &str) -> bool {
+        let ip = unbracketed(host).parse::<IpAddr>().ok();
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+
+        self.0.iter().any(|entry| match entry {
+            Entry::Any => true,
+            Entry::Domain(domain) => {
+                host == *domain
+                    || host
+                        .strip_suffix(domain.as_str())
+                        .is_some_and(|subdomain| subdomain.ends_with('.'))
+            }
+            Entry::Network(network) => ip.is_some_and(|ip| network.contains(ip)),
+        })
+    }
+
+    /// Generates a Proxy Auto-Config file sending browsers through the proxy at `proxy_addr`,
+    /// except for the hosts on this list, which they reach directly
+    pub fn pac_file(&self, proxy_addr: SocketAddr) -> String {
+        let mut pac = "function FindProxyForURL(url, host) {\n".to_owned();
+        for entry in &self.0 {
+            let condition = match entry {
+                Entry::Any => "true".to_owned(),
+                Entry::Domain(domain) => {
+                    format!("host == \"{domain}\" || dnsDomainIs(host, \".{domain}\")")
+                }
+                Entry::Network(Network {
+                    addr: IpAddr::V4(network),
+                    prefix,
+                }) => {
+                    let mask = u32::MAX.checked_shl(32 - u32::from(*prefix)).unwrap_or(0);
+                    format!(
+                        "isInNet(host, \"{network}\", \"{}\")",
+                        std::net::Ipv4Addr::from(mask)
+                    )
+                }
+                // PAC files have no standard way to match ipv6 ranges
+                Entry::Network(Network {
+                    addr: IpAddr::V6(_),
+                    ..
+                }) => continue,
+            };
+            pac.push_str(&format!("    if ({condition}) return \"DIRECT\";\n"));
+        }
+        pac.push_str(&format!("    return \"PROXY {proxy_addr}\";\n}}\n"));
+        pac
+    }
+}
+
+fn unbracketed(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_domains_and_their_subdomains() {
+        let no_proxy = NoProxy::parse(".Example.com, internal").unwrap();
+        assert!(no_proxy.matches("example.com"));
+        assert!(no_proxy.matches("WWW.example.com."));
+        assert!(no_proxy.matches("internal"));
+        assert!(!no_proxy.matches("notexample.com"));
+        assert!(!no_proxy.matches("example.com.evil"));
+    }
+
+    #[test]
+    fn matches_addresses_in_ranges() {
+        let no_proxy = NoProxy::parse("10.0.0.0/8, 192.0.2.1, [::1]").unwrap();
+        assert!(no_proxy.matches("10.20.30.40"));
+        assert!(no_proxy.matches("192.0.2.1"));
+        assert!(no_proxy.matches("[::1]"));
+        assert!(!no_proxy.matches("192.0.2.2"));
+        // host names aren't resolved to be checked against ranges
+        assert!(!no_proxy.matches("localhost"));
+    }
+
+    #[test]
+    fn wildcard_matches_everything_and_empty_lists_nothing() {
+        assert!(NoProxy::parse("*").unwrap().matches("example.com"));
+        assert!(!NoProxy::parse("").unwrap().matches("example.com"));
+        assert!(!NoProxy::default().matches("example.com"));
+    }
+
+    #[test]
+    fn parse_rejects_invalid_entries() {
+        assert!(NoProxy::parse("*.example.com").is_err());
+        assert!(NoProxy::parse(".").is_err());
+        assert!(NoProxy::parse("10.0.0.0/40").is_err());
+    }
+
+    #[test]
+    fn pac_file_goes_direct_for_the_list_and_through_the_proxy_otherwise() {
+        let no_proxy = NoProxy::parse("example.com, 10.0.0.0/8, 2001:db8::/32").unwrap();
+        let pac = no_proxy.pac_file("192.0.2.1:8080".parse().unwrap());
+        assert_eq!(
+            pac,
+            "function FindProxyForURL(url, host) {\n    \
+             if (host == \"example.com\" || dnsDomainIs(host, \".example.com\")) return \"DIRECT\";\n    \
+             if (isInNet(host, \"10.0.0.0\", \"255.0.0.0\")) return \"DIRECT\";\n    \
+             return \"PROXY 192.0.2.1:8080\";\n}\n"
+        );
+    }
+}
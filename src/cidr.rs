@@ -0,0 +1,94 @@
+use std::net::IpAddr;
+
+/// A range of ip addresses, written in CIDR notation
+#[derive(Clone, Copy)]
+pub struct Network {
+    pub addr: IpAddr,
+    /// number of leading bits of `addr` shared by every address in the range
+    pub prefix: u8,
+}
+
+impl Network {
+    /// Parses `addr/prefix`, or a lone ip address as a range holding only itself
+    pub fn parse(network: &str) -> Option<Network> {
+        let (addr, prefix) = match network.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (network, None),
+        };
+        let addr: IpAddr = addr.parse().ok()?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|&prefix| prefix <= max_prefix)?,
+            None => max_prefix,
+        };
+        Some(Network { addr, prefix })
+    }
+
+    /// Whether `ip` is in the range, treating ipv4-mapped ipv6 addresses as the ipv4 addresses
+    /// they carry
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (ip, network, bits) = match (ip.to_canonical(), self.addr) {
+            (IpAddr::V4(ip), IpAddr::V4(network)) => {
+                (u32::from(ip) as u128, u32::from(network) as u128, 32)
+            }
+            (IpAddr::V6(ip), IpAddr::V6(network)) => (u128::from(ip), u128::from(network), 128),
+            _ => return false,
+        };
+        let mask = match self.prefix {
+            0 => 0,
+            prefix => u128::MAX << (bits - u32::from(prefix)),
+        };
+        // a v4 mask also covers the bits above the address, which are zero in both values
+        ip & mask == network & mask
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contains(network: &str, ip: &str) -> bool {
+        Network::parse(network)
+            .unwrap()
+            .contains(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn parse_accepts_ranges_and_lone_addresses() {
+        let network = Network::parse("10.1.0.0/16").unwrap();
+        assert_eq!(network.addr, IpAddr::from([10, 1, 0, 0]));
+        assert_eq!(network.prefix, 16);
+        assert_eq!(Network::parse("192.0.2.7").unwrap().prefix, 32);
+        assert_eq!(Network::parse("2001:db8::/32").unwrap().prefix, 32);
+        assert_eq!(Network::parse("::1").unwrap().prefix, 128);
+
+        for invalid in [
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/",
+            "example.com",
+            "10.0.0/8",
+        ] {
+            assert!(Network::parse(invalid).is_none(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn contains_compares_the_prefix_bits() {
+        assert!(contains("10.1.0.0/16", "10.1.255.3"));
+        assert!(!contains("10.1.0.0/16", "10.2.0.1"));
+        assert!(contains("192.0.2.7", "192.0.2.7"));
+        assert!(!contains("192.0.2.7", "192.0.2.8"));
+        assert!(contains("0.0.0.0/0", "203.0.113.9"));
+        assert!(contains("2001:db8::/32", "2001:db8:ffff::1"));
+        assert!(!contains("2001:db8::/32", "2001:db9::1"));
+        assert!(contains("::/0", "::1"));
+    }
+
+    #[test]
+    fn contains_treats_mapped_addresses_as_ipv4() {
+        assert!(contains("10.0.0.0/8", "::ffff:10.0.0.1"));
+        assert!(!contains("10.0.0.0/8", "2001:db8::1"));
+        assert!(!contains("::/0", "10.0.0.1"));
+    }
+}
@@ -0,0 +1,50 @@
+use std::time::Instant;
+#[cfg(test)]
+use std::{sync::Mutex, time::Duration};
+
+/// Where time-based limits read the current time from, so tests can move it on themselves
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+/// The system's monotonic clock
+#[derive(Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for &C {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+}
+
+/// A clock that stands still until it's advanced
+#[cfg(test)]
+pub struct ManualClock {
+    now: Mutex<Instant>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock {
+            now: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
@@ -0,0 +1,1381 @@
+use crate::{
+    balance::Balancer,
+    buffer::BufferPool,
+    bypass::NoProxy,
+    cidr::Network,
+    http::{ErrorFormat, ForwardedHeader},
+    limit::{Budget, HostLimits, Semaphore},
+    log::LogFormat,
+    pool::Pool,
+    resolve::{Family, Resolver},
+    rlimit::parse_ratio,
+    route::{Mode, Route},
+    source,
+    tee::{Tee, TeeDirection},
+    upstream::{UpstreamPolicy, UpstreamProxies},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    net::ToSocketAddrs,
+    time::{Duration, Instant},
+};
+
+const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:8080";
+
+/// Default maximum number of pending connections queued by the kernel for `accept`
+const DEFAULT_BACKLOG: i32 = 1024;
+
+/// A command-line option the proxy accepts
+struct Opt {
+    name: &'static str,
+    /// placeholder for the option's value in the usage text, or `None` for a flag without a value
+    value: Option<&'static str>,
+    /// whether the option may be given more than once
+    repeatable: bool,
+    help: &'static str,
+}
+
+const OPTIONS: &[Opt] = &[
+    Opt {
+        name: "--listen",
+        value: Some("ADDR"),
+        repeatable: false,
+        help: "Address to accept proxy connections on [default: 127.0.0.1:8080]",
+    },
+    Opt {
+        name: "--stats-listen",
+        value: Some("ADDR"),
+        repeatable: false,
+        help: "Address to serve GET /stats, GET /connections, POST /connections/ID/close, and \
+               GET /health on",
+    },
+    Opt {
+        name: "--stats-allow-cidr",
+        value: Some("NETWORK"),
+        repeatable: true,
+        help: "Only let clients in this CIDR range or at this ip address list or close \
+               connections through the stats server [default: loopback clients only]",
+    },
+    Opt {
+        name: "--server-name",
+        value: Some("NAME"),
+        repeatable: false,
+        help: "Server header sent by the stats server [default: proxy_server/VERSION]",
+    },
+    Opt {
+        name: "--backlog",
+        value: Some("N"),
+        repeatable: false,
+        help: "Maximum number of connections waiting to be accepted (Linux only) [default: 1024]",
+    },
+    Opt {
+        name: "--allow-client-cidr",
+        value: Some("NETWORK"),
+        repeatable: true,
+        help: "Only accept connections from clients in this CIDR range or at this ip address, \
+               closing others unread [default: accept every client]",
+    },
+    Opt {
+        name: "--egress-cidr",
+        value: Some("NETWORK"),
+        repeatable: true,
+        help: "Only connect to servers whose addresses are in this CIDR range or at this ip \
+               address, answering 403 for others [default: connect to any server]",
+    },
+    Opt {
+        name: "--max-header-value-size",
+        value: Some("BYTES"),
+        repeatable: false,
+        help: "Answer 431 to requests with any header value longer than this [default: only \
+               limited by the 8 KiB request head]",
+    },
+    Opt {
+        name: "--max-chunk-size",
+        value: Some("BYTES"),
+        repeatable: false,
+        help: "Answer 400 to forwarded chunked request bodies, and cut off chunked responses, \
+               declaring a chunk with more than this many bytes of data; chunk size lines, \
+               extensions included, are separately limited to 4096 bytes [default: no limit]",
+    },
+    Opt {
+        name: "--max-connections",
+        value: Some("N"),
+        repeatable: false,
+        help: "Maximum number of connections handled at once",
+    },
+    Opt {
+        name: "--overflow",
+        value: Some("POLICY"),
+        repeatable: false,
+        help: "What to do with connections beyond --max-connections: block to leave them \
+               waiting, or reject to answer 503 [default: block]",
+    },
+    Opt {
+        name: "--accept-rate",
+        value: Some("N"),
+        repeatable: false,
+        help: "Most new connections accepted per second, allowing bursts of up to a second's \
+               worth",
+    },
+    Opt {
+        name: "--accept-rate-policy",
+        value: Some("POLICY"),
+        repeatable: false,
+        help: "What to do with connections beyond --accept-rate: block to leave them waiting, \
+               or reject to answer 503 [default: block]",
+    },
+    Opt {
+        name: "--max-tunnels",
+        value: Some("N"),
+        repeatable: false,
+        help: "Maximum number of CONNECT tunnels open at once, answering 503 to more",
+    },
+    Opt {
+        name: "--max-forwards",
+        value: Some("N"),
+        repeatable: false,
+        help: "Maximum number of plain http requests handled at once, answering 503 to more",
+    },
+    Opt {
+        name: "--max-conns-per-host",
+        value: Some("N"),
+        repeatable: false,
+        help: "Maximum number of connections to any one server host at once",
+    },
+    Opt {
+        name: "--per-host-overflow",
+        value: Some("POLICY"),
+        repeatable: false,
+        help: "What to do with requests beyond --max-conns-per-host: block to wait up to 5 \
+               seconds for a connection to the host to finish, or reject to answer 503 \
+               [default: block]",
+    },
+    Opt {
+        name: "--max-hosts-per-conn",
+        value: Some("N"),
+        repeatable: false,
+        help: "Close client connections that send requests for more than this many different \
+               hosts [default: unlimited]",
+    },
+    Opt {
+        name: "--fd-warn-ratio",
+        value: Some("RATIO"),
+        repeatable: false,
+        help: "Warn once connections hold this fraction of the open file limit [default: 0.8]",
+    },
+    Opt {
+        name: "--fd-reject-ratio",
+        value: Some("RATIO"),
+        repeatable: false,
+        help: "Turn away new connections with 503 once connections hold this fraction of the \
+               open file limit",
+    },
+    Opt {
+        name: "--max-memory",
+        value: Some("MB"),
+        repeatable: false,
+        help: "Stop accepting connections, leaving them queued, while the proxy's resident \
+               memory is at least this many megabytes (Linux only)",
+    },
+    Opt {
+        name: "--connect-timeout",
+        value: Some("SECS"),
+        repeatable: false,
+        help: "Give up connecting to a server after this long",
+    },
+    Opt {
+        name: "--idle-timeout",
+        value: Some("SECS"),
+        repeatable: false,
+        help: "Close connections that move no data in either direction for this long",
+    },
+    Opt {
+        name: "--tunnel-idle-timeout",
+        value: Some("SECS"),
+        repeatable: false,
+        help: "Close CONNECT tunnels, and connections upgraded to another protocol, that move \
+               no data for this long instead [default: --idle-timeout]",
+    },
+    Opt {
+        name: "--response-timeout",
+        value: Some("SECS"),
+        repeatable: false,
+        help: "Give up on a server that has not started responding this long after a request",
+    },
+    Opt {
+        name: "--max-duration",
+        value: Some("SECS"),
+        repeatable: false,
+        help: "Give up on a plain http request whose response has not been forwarded this long \
+               after the proxy began connecting to send it; connecting and waiting for the \
+               response are cut short to fit",
+    },
+    Opt {
+        name: "--max-tunnel-duration",
+        value: Some("SECS"),
+        repeatable: false,
+        help: "Close CONNECT tunnels, and connections upgraded to another protocol, once they \
+               have been open this long",
+    },
+    Opt {
+        name: "--max-concurrent-resolves",
+        value: Some("N"),
+        repeatable: false,
+        help: "Queue DNS lookups beyond this many in progress at once",
+    },
+    Opt {
+        name: "--max-concurrent-setups",
+        value: Some("N"),
+        repeatable: false,
+        help: "Queue lookups and connects for new server connections beyond this many in \
+               progress at once, leaving established connections unaffected",
+    },
+    Opt {
+        name: "--pool-max-idle-per-host",
+        value: Some("N"),
+        repeatable: false,
+        help: "Most idle server connections kept open for reuse per server, or 0 to close \
+               every connection after its response [default: 8]",
+    },
+    Opt {
+        name: "--pool-max-idle-total",
+        value: Some("N"),
+        repeatable: false,
+        help: "Most idle server connections kept open for reuse across all servers \
+               [default: 256]",
+    },
+    Opt {
+        name: "--pool-idle-timeout",
+        value: Some("SECS"),
+        repeatable: false,
+        help: "Close server connections left idle in the pool for this long [default: 60]",
+    },
+    Opt {
+        name: "--max-free-buffers",
+        value: Some("N"),
+        repeatable: false,
+        help: "Most idle relay buffers kept for reuse by later connections; buffers in use \
+               aren't limited [default: 256]",
+    },
+    Opt {
+        name: "--host-override",
+        value: Some("HOST=IP[,...]"),
+        repeatable: true,
+        help: "Connect to these addresses for HOST instead of looking it up",
+    },
+    Opt {
+        name: "--remap",
+        value: Some("FROM=TO[:PORT]"),
+        repeatable: true,
+        help: "Connect to host TO, and port PORT if given, for requests and tunnels to host \
+               FROM, leaving the Host header the client sent unchanged",
+    },
+    Opt {
+        name: "--upstream-family",
+        value: Some("FAMILY"),
+        repeatable: false,
+        help: "Only connect to servers and upstream proxies over v4 or v6 addresses, or any \
+               [default: any]",
+    },
+    Opt {
+        name: "--source-port-range",
+        value: Some("START-END"),
+        repeatable: false,
+        help: "Make connections to servers and upstream proxies from a local port in this \
+               range, trying the next port when one is in use (Linux only)",
+    },
+    Opt {
+        name: "--hosts-file",
+        value: Some("PATH"),
+        repeatable: false,
+        help: "Read host addresses to use instead of looking them up from a file in the \
+               /etc/hosts format",
+    },
+    Opt {
+        name: "--balance-resolved",
+        value: None,
+        repeatable: false,
+        help: "Spread connections to a server across all the addresses it resolves to, trying \
+               recently failed ones last; an address given more than once with --host-override \
+               gets a larger share",
+    },
+    Opt {
+        name: "--upstream-proxy",
+        value: Some("HOST:PORT[,...]"),
+        repeatable: false,
+        help: "Forward all connections through these parent proxies",
+    },
+    Opt {
+        name: "--upstream-policy",
+        value: Some("POLICY"),
+        repeatable: false,
+        help: "Order to try upstream proxies in: failover or round-robin [default: failover]",
+    },
+    Opt {
+        name: "--fallback-direct",
+        value: None,
+        repeatable: false,
+        help: "Connect to servers directly when no upstream proxy can be reached, for tunnels \
+               and http URLs, within the --egress-cidr networks",
+    },
+    Opt {
+        name: "--no-proxy",
+        value: Some("HOST[,...]"),
+        repeatable: false,
+        help: "Connect directly instead of through the upstream proxies to these domains and \
+               their subdomains, ip addresses, CIDR ranges, or * for every host, and have the \
+               --serve-pac file send browsers to them directly",
+    },
+    Opt {
+        name: "--route",
+        value: Some("KEY=VALUE[,...]"),
+        repeatable: true,
+        help: "Settings for matching hosts: host=PATTERN (required), name=NAME, \
+               mode=forward|tunnel, connect-timeout=SECS, idle-timeout=SECS, \
+               tunnel-idle-timeout=SECS, response-timeout=SECS, max-duration=SECS, \
+               max-tunnel-duration=SECS, and rewrite=FROM=TO (repeatable) mapping request paths \
+               matching FROM, where * matches anything, to TO, where $1 to $9 stand for what the \
+               *s matched, in forward mode",
+    },
+    Opt {
+        name: "--default-mode",
+        value: Some("MODE"),
+        repeatable: false,
+        help: "Handling of plain http requests without a route: forward or tunnel \
+               [default: forward]",
+    },
+    Opt {
+        name: "--forwarded-header",
+        value: Some("STYLE"),
+        repeatable: false,
+        help: "Tell servers which client forwarded requests came from: standard for an RFC \
+               7239 Forwarded header, xforwarded for X-Forwarded-For and X-Forwarded-Proto, or \
+               both",
+    },
+    Opt {
+        name: "--serve-pac",
+        value: None,
+        repeatable: false,
+        help: "Answer GET /proxy.pac with a Proxy Auto-Config file pointing browsers at the \
+               proxy, except for the --no-proxy hosts",
+    },
+    Opt {
+        name: "--reject-on-missing-host",
+        value: None,
+        repeatable: false,
+        help: "Answer 400 to plain http requests with neither an absolute-form target naming a \
+               host nor a Host header",
+    },
+    Opt {
+        name: "--require-user-agent",
+        value: None,
+        repeatable: false,
+        help: "Answer 403 to plain http requests without a non-empty User-Agent header; \
+               CONNECT requests are let through",
+    },
+    Opt {
+        name: "--enable-ftp",
+        value: None,
+        repeatable: false,
+        help: "Answer GET and HEAD requests for ftp:// URLs by retrieving the file over ftp, \
+               anonymously unless the URL gives credentials",
+    },
+    Opt {
+        name: "--enable-trace",
+        value: None,
+        repeatable: false,
+        help: "Answer TRACE requests addressed to the proxy by echoing them, without their \
+               cookies and credentials",
+    },
+    Opt {
+        name: "--log-format",
+        value: Some("FORMAT"),
+        repeatable: false,
+        help: "What to log for each connection: default, or combined for Apache Combined Log \
+               Format access lines [default: default]",
+    },
+    Opt {
+        name: "--log-server-addr",
+        value: None,
+        repeatable: false,
+        help: "Log the address each connection to a server or upstream proxy was made to, as \
+               an extra quoted field on combined lines",
+    },
+    Opt {
+        name: "--log-tls-clienthello",
+        value: None,
+        repeatable: false,
+        help: "Log the server name, ALPN protocols, and TLS versions each CONNECT tunnel's \
+               client offers in its TLS ClientHello, which is passed on untouched",
+    },
+    Opt {
+        name: "--log-connections",
+        value: None,
+        repeatable: false,
+        help: "Log a line for each client connection once it ends, with how it ended: served, \
+               rejected-source, rejected-limit, denied-acl, protocol-error, client-gone, or \
+               error, and what closed it: client-eof, server-eof, idle-timeout, total-timeout, \
+               response-timeout, rate-limit, acl-deny, upstream-connect-fail, dns-fail, \
+               no-usable-address, protocol-error, admin-closed, shutdown, or error",
+    },
+    Opt {
+        name: "--log-route",
+        value: None,
+        repeatable: false,
+        help: "Log the name of the route each request matched, as an extra quoted field on \
+               combined lines after any server address",
+    },
+    Opt {
+        name: "--error-format",
+        value: Some("FORMAT"),
+        repeatable: false,
+        help: "Body of error responses from the proxy: text, or json, which is also used for \
+               clients that accept application/json [default: text]",
+    },
+    Opt {
+        name: "--stats-interval",
+        value: Some("SECS"),
+        repeatable: false,
+        help: "Log the statistics served on /stats every this many seconds, with how much the \
+               counts have grown since the last time",
+    },
+    Opt {
+        name: "--log-file",
+        value: Some("PATH"),
+        repeatable: false,
+        help: "Append the log to this file instead of stdout, reopening it on SIGHUP",
+    },
+    Opt {
+        name: "--tee",
+        value: Some("PATH|ADDR"),
+        repeatable: false,
+        help: "Copy proxied traffic to this file, or to this ip:port over TCP, dropping what \
+               the sink is too slow to take",
+    },
+    Opt {
+        name: "--tee-direction",
+        value: Some("DIRECTION"),
+        repeatable: false,
+        help: "Traffic to copy to the --tee sink: request for client to server only, or both \
+               [default: request]",
+    },
+    Opt {
+        name: "--drain-timeout",
+        value: Some("SECS"),
+        repeatable: false,
+        help: "On SIGINT or SIGTERM, wait up to this long for open connections to finish before \
+               exiting, failing health checks meanwhile [default: 30]",
+    },
+    Opt {
+        name: "--linger-seconds",
+        value: Some("SECS"),
+        repeatable: false,
+        help: "Make closing a client or server connection wait up to this long for unsent data \
+               to be delivered, or with 0 reset the connection and discard it; by default close \
+               returns at once and the data is delivered in the background",
+    },
+    Opt {
+        name: "--help",
+        value: None,
+        repeatable: false,
+        help: "Print this help and exit",
+    },
+    Opt {
+        name: "--version",
+        value: None,
+        repeatable: false,
+        help: "Print the version and exit",
+    },
+];
+
+/// What is done with new connections while the proxy, or a request's host, is at its connection
+/// limit
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// wait until a connection finishes
+    Block,
+    /// turn the connection away immediately
+    Reject,
+}
+
+impl OverflowPolicy {
+    fn parse(policy: &str) -> Result<OverflowPolicy, String> {
+        match policy {
+            "block" => Ok(OverflowPolicy::Block),
+            "reject" => Ok(OverflowPolicy::Reject),
+            _ => Err(format!("Unknown overflow policy: {policy}")),
+        }
+    }
+}
+
+/// Default time the proxy waits for connections to finish when shutting down
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default fraction of the open file limit at which the proxy warns that it is running out
+const DEFAULT_FD_WARN_RATIO: f64 = 0.8;
+
+/// Shortest timeout `Timeouts::within` leaves, however little time remains
+const MIN_REMAINING: Duration = Duration::from_millis(1);
+
+/// Limits on how long each phase of a connection may take
+#[derive(Clone, Copy, Default)]
+pub struct Timeouts {
+    /// how long to wait for a connection to the server to be established
+    pub connect: Option<Duration>,
+    /// how long a connection may go without data moving in either direction
+    pub idle: Option<Duration>,
+    /// how long a `CONNECT` tunnel, or a connection upgraded to another protocol, may go
+    /// without data moving, if not `idle`
+    pub tunnel_idle: Option<Duration>,
+    /// how long to wait for the first byte of a response once a request has been sent
+    pub response: Option<Duration>,
+    /// how long a plain http request may take, from connecting to the server to the end of its
+    /// response
+    pub duration: Option<Duration>,
+    /// how long a `CONNECT` tunnel, or a connection upgraded to another protocol, may stay open
+    pub tunnel_duration: Option<Duration>,
+}
+
+impl Timeouts {
+    /// Fills in the timeouts not set here from `fallback`
+    pub fn or(self, fallback: Timeouts) -> Timeouts {
+        Timeouts {
+            connect: self.connect.or(fallback.connect),
+            idle: self.idle.or(fallback.idle),
+            tunnel_idle: self.tunnel_idle.or(fallback.tunnel_idle),
+            response: self.response.or(fallback.response),
+            duration: self.duration.or(fallback.duration),
+            tunnel_duration: self.tunnel_duration.or(fallback.tunnel_duration),
+        }
+    }
+
+    /// The timeouts for a step that must be over by `deadline`, with the connect and response
+    /// timeouts that are set cut to the time left before it
+    pub fn within(self, deadline: Option<Instant>) -> Timeouts {
+        let Some(remaining) = remaining(deadline) else {
+            return self;
+        };
+        let clamp = |timeout: Option<Duration>| timeout.map(|timeout| timeout.min(remaining));
+        Timeouts {
+            connect: clamp(self.connect),
+            response: clamp(self.response),
+            ..self
+        }
+    }
+}
+
+/// The time left before `deadline`, if there is one
+pub fn remaining(deadline: Option<Instant>) -> Option<Duration> {
+    // a zero timeout is refused by sockets, so a passed deadline still leaves a moment
+    deadline.map(|deadline| {
+        deadline
+            .saturating_duration_since(Instant::now())
+            .max(MIN_REMAINING)
+    })
+}
+
+/// Parses the value of the option `name` as a whole number of seconds
+pub fn parse_seconds(name: &str, value: &str) -> Result<Duration, String> {
+    value
+        .parse()
+        .map(Duration::from_secs)
+        .map_err(|_| format!("{name} must be a number of seconds"))
+}
+
+pub struct Config {
+    /// address the proxy accepts connections on
+    pub listen_addr: String,
+    /// address the stats and health server listens on, if it runs
+    pub stats_listen_addr: Option<String>,
+    /// source addresses that may list and close connections through the stats server, or empty
+    /// to allow only loopback clients
+    pub stats_allowed_clients: Vec<Network>,
+    /// how the stats server identifies itself
+    pub server_name: String,
+    /// maximum number of pending connections queued by the kernel for `accept`
+    pub backlog: i32,
+    /// source addresses clients may connect from, or empty to allow every client
+    pub allowed_clients: Vec<Network>,
+    /// ranges a server's address must be in for the proxy to connect to it directly, or empty
+    /// to allow any server
+    pub egress_networks: Vec<Network>,
+    /// longest header value accepted in a request
+    pub max_header_value_size: Option<usize>,
+    /// largest chunk a forwarded chunked body may declare
+    pub max_chunk_size: Option<u64>,
+    /// maximum number of connections handled at once
+    pub max_connections: Option<usize>,
+    /// handling of connections beyond `max_connections`
+    pub overflow_policy: OverflowPolicy,
+    /// most new connections accepted per second
+    pub accept_rate: Option<f64>,
+    /// handling of connections beyond `accept_rate`
+    pub accept_rate_policy: OverflowPolicy,
+    /// cap on the `CONNECT` tunnels open at once
+    pub tunnel_budget: Option<Budget>,
+    /// cap on the plain http requests handled at once
+    pub forward_budget: Option<Budget>,
+    /// limits on connections to each server host
+    pub host_limits: Option<HostLimits>,
+    /// handling of requests to hosts at their connection limit
+    pub per_host_overflow: OverflowPolicy,
+    /// most different hosts one client connection may send requests for
+    pub max_hosts_per_connection: Option<usize>,
+    /// fraction of the open file limit in use at which a warning is logged
+    pub fd_warn_ratio: f64,
+    /// fraction of the open file limit in use at which new connections are rejected
+    pub fd_reject_ratio: Option<f64>,
+    /// local ports connections to servers are made from, if restricted
+    pub source_ports: Option<(u16, u16)>,
+    /// resident memory in bytes at which new connections stop being accepted
+    pub max_memory: Option<u64>,
+    /// how long closing a connection waits for unsent data, if not the system's default
+    pub linger: Option<Duration>,
+    /// timeouts for connections to hosts without a route overriding them
+    pub timeouts: Timeouts,
+    /// bounds the number of server connections being looked up and connected at once, if set
+    pub setup_limit: Option<Semaphore>,
+    /// looks up the addresses of servers and upstream proxies
+    pub resolver: Resolver,
+    /// spreads server connections across resolved addresses, if they are not just made to the
+    /// first that can be reached
+    pub balancer: Option<Balancer>,
+    /// idle server connections kept for later plain http requests
+    pub pool: Pool,
+    /// buffers that relayed data passes through
+    pub buffers: BufferPool,
+    /// parent proxies to forward all connections through
+    pub upstream_proxies: Option<UpstreamProxies>,
+    /// whether servers are connected to directly when no upstream proxy can be reached
+    pub fallback_direct: bool,
+    /// hosts connected to directly even when there are upstream proxies
+    pub no_proxy: NoProxy,
+    /// per-host handling of plain http requests, checked in order
+    pub routes: Vec<Route>,
+    /// handling of plain http requests to hosts without a matching route
+    pub default_mode: Mode,
+    /// headers identifying the client added to forwarded requests, if any
+    pub forwarded_header: Option<ForwardedHeader>,
+    /// whether requests for `/proxy.pac` addressed to the proxy are answered with a PAC file
+    pub serve_pac: bool,
+    /// whether requests for `ftp://` URLs are answered by retrieving the file over ftp
+    pub enable_ftp: bool,
+    /// whether `TRACE` requests addressed to the proxy itself are answered with an echo
+    pub enable_trace: bool,
+    /// whether plain http requests that don't say which host they are for are rejected with a
+    /// `400` rather than attempted
+    pub reject_on_missing_host: bool,
+    /// whether plain http requests without a non-empty `User-Agent` are rejected with a `403`
+    pub require_user_agent: bool,
+    /// what is logged for each connection
+    pub log_format: LogFormat,
+    /// whether the address connected to for each request is logged
+    pub log_server_addr: bool,
+    /// whether the route each request matched is logged
+    pub log_route: bool,
+    /// whether a line is logged for each client connection that ends
+    pub log_connections: bool,
+    /// whether what clients offer at the start of TLS sessions through tunnels is logged
+    pub log_tls_client_hello: bool,
+    /// how error responses from the proxy are written for clients that don't ask for JSON
+    pub error_format: ErrorFormat,
+    /// how often the statistics are logged, if they are
+    pub stats_interval: Option<Duration>,
+    /// file the log is appended to instead of stdout
+    pub log_file: Option<String>,
+    /// longest the proxy waits for connections to finish once asked to shut down
+    pub drain_timeout: Duration,
+    /// where a copy of proxied traffic is sent, if anywhere
+    pub tee: Option<Tee>,
+}
+
+impl Config {
+    /// Returns the upstream proxies that connections to `host` go through, if any
+    pub fn upstream_for(&self, host: &str) -> Option<&UpstreamProxies> {
+        self.upstream_proxies
+            .as_ref()
+            .filter(|_| !self.no_proxy.matches(host))
+    }
+
+    /// Describes the settings the proxy runs with, one `Name: settings` line per area. None of
+    /// them are secret, since the proxy takes no credentials.
+    pub fn summary(&self) -> Vec<String> {
+        let seconds = |duration: Option<Duration>| {
+            duration.map_or("none".to_owned(), |duration| {
+                format!("{}s", duration.as_secs())
+            })
+        };
+        let count = |max: Option<usize>| max.map_or("none".to_owned(), |max| max.to_string());
+        let policy = |policy: OverflowPolicy| match policy {
+            OverflowPolicy::Block => "block",
+            OverflowPolicy::Reject => "reject",
+        };
+
+        let mut lines = vec![format!(
+            "Listening: {} (backlog {}), stats {}",
+            self.listen_addr,
+            self.backlog,
+            self.stats_listen_addr.as_deref().unwrap_or("off")
+        )];
+        lines.push(format!(
+            "Timeouts: connect={} idle={} tunnel-idle={} response={} max-duration={} \
+             max-tunnel-duration={} drain={}s linger={}",
+            seconds(self.timeouts.connect),
+            seconds(self.timeouts.idle),
+            seconds(self.timeouts.tunnel_idle.or(self.timeouts.idle)),
+            seconds(self.timeouts.response),
+            seconds(self.timeouts.duration),
+            seconds(self.timeouts.tunnel_duration),
+            self.drain_timeout.as_secs(),
+            self.linger.map_or("default".to_owned(), |linger| format!(
+                "{}s",
+                linger.as_secs()
+            ))
+        ));
+        lines.push(format!(
+            "Limits: connections={} ({}) accept-rate={} tunnels={} forwards={} per-host={} \
+             hosts-per-conn={}",
+            count(self.max_connections),
+            policy(self.overflow_policy),
+            self.accept_rate.map_or("none".to_owned(), |rate| format!(
+                "{rate}/s ({})",
+                policy(self.accept_rate_policy)
+            )),
+            count(self.tunnel_budget.as_ref().map(|budget| budget.max)),
+            count(self.forward_budget.as_ref().map(|budget| budget.max)),
+            count(self.host_limits.as_ref().map(|limits| limits.max_per_host)),
+            count(self.max_hosts_per_connection)
+        ));
+        lines.push(format!(
+            "Access: {} allowed client networks, {} egress networks, {} routes",
+            self.allowed_clients.len(),
+            self.egress_networks.len(),
+            self.routes.len()
+        ));
+        lines.push(match &self.upstream_proxies {
+            Some(upstreams) if self.fallback_direct => format!(
+                "Upstream: {}, falling back to direct connections",
+                upstreams.addrs().join(", ")
+            ),
+            Some(upstreams) => format!("Upstream: {}", upstreams.addrs().join(", ")),
+            None => "Upstream: none, connecting directly".to_owned(),
+        });
+        lines.push("TLS: not terminated; https is tunneled with CONNECT".to_owned());
+        lines.push(format!(
+            "Logging: {} format to {}",
+            match self.log_format {
+                LogFormat::Default => "default",
+                LogFormat::Combined => "combined",
+            },
+            self.log_file.as_deref().unwrap_or("stdout")
+        ));
+        lines
+    }
+}
+
+/// What the command line asks the proxy to do
+pub enum Command {
+    Run(Box<Config>),
+    Help,
+    Version,
+}
+
+/// Parses the command-line arguments following the program name
+pub fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Command, String> {
+    let mut config = Config {
+        listen_addr: DEFAULT_LISTEN_ADDR.to_owned(),
+        stats_listen_addr: None,
+        server_name: format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        backlog: DEFAULT_BACKLOG,
+        allowed_clients: Vec::new(),
+        stats_allowed_clients: Vec::new(),
+        egress_networks: Vec::new(),
+        max_header_value_size: None,
+        max_chunk_size: None,
+        max_connections: None,
+        overflow_policy: OverflowPolicy::Block,
+        accept_rate: None,
+        accept_rate_policy: OverflowPolicy::Block,
+        tunnel_budget: None,
+        forward_budget: None,
+        host_limits: None,
+        per_host_overflow: OverflowPolicy::Block,
+        max_hosts_per_connection: None,
+        fd_warn_ratio: DEFAULT_FD_WARN_RATIO,
+        fd_reject_ratio: None,
+        max_memory: None,
+        linger: None,
+        source_ports: None,
+        timeouts: Timeouts::default(),
+        setup_limit: None,
+        resolver: Resolver {
+            limit: None,
+            overrides: HashMap::new(),
+            remaps: HashMap::new(),
+            family: Family::Any,
+        },
+        balancer: None,
+        pool: Pool::new(),
+        buffers: BufferPool::new(),
+        upstream_proxies: None,
+        fallback_direct: false,
+        no_proxy: NoProxy::default(),
+        routes: Vec::new(),
+        default_mode: Mode::Forward,
+        forwarded_header: None,
+        serve_pac: false,
+        enable_ftp: false,
+        enable_trace: false,
+        reject_on_missing_host: false,
+        require_user_agent: false,
+        log_format: LogFormat::Default,
+        log_server_addr: false,
+        log_route: false,
+        log_connections: false,
+        log_tls_client_hello: false,
+        error_format: ErrorFormat::Text,
+        stats_interval: None,
+        log_file: None,
+        drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+        tee: None,
+    };
+    let mut upstream_list = None;
+    let mut upstream_policy = UpstreamPolicy::Failover;
+    let mut tee_target = None;
+    let mut tee_direction = TeeDirection::Requests;
+
+    // every problem with the arguments is reported at once rather than one per attempt
+    let mut errors = Vec::new();
+    let mut seen = HashSet::new();
+    while let Some(arg) = args.next() {
+        let mut parse_arg = || -> Result<Option<Command>, String> {
+            // values may be given either as the next argument or after an `=`
+            let (name, inline_value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_owned())),
+                None => (arg.as_str(), None),
+            };
+            let name = match name {
+                "-h" => "--help",
+                "-V" => "--version",
+                name => name,
+            };
+            let opt = OPTIONS
+                .iter()
+                .find(|opt| opt.name == name)
+                .ok_or(format!("Unknown argument: {arg}"))?;
+
+            // the value is taken first so a repeated option's value isn't read as an option
+            let value = match (opt.value, inline_value) {
+                (Some(_), Some(value)) => value,
+                (Some(_), None) => args.next().ok_or(format!("Missing value for {name}"))?,
+                (None, Some(_)) => return Err(format!("{name} does not take a value")),
+                (None, None) => String::new(),
+            };
+            if !seen.insert(opt.name) && !opt.repeatable {
+                return Err(format!("{name} may only be given once"));
+            }
+
+            match name {
+                "--help" => return Ok(Some(Command::Help)),
+                "--version" => return Ok(Some(Command::Version)),
+                "--listen" => config.listen_addr = value,
+                "--stats-listen" => config.stats_listen_addr = Some(value),
+                "--server-name" => {
+                    if value.is_empty() || value.chars().any(char::is_control) {
+                        return Err(
+                            "--server-name must be non-empty without control characters".to_owned()
+                        );
+                    }
+                    config.server_name = value
+                }
+                "--backlog" if cfg!(not(target_os = "linux")) => {
+                    return Err("--backlog is only supported on Linux".to_owned())
+                }
+                "--backlog" => {
+                    config.backlog = value
+                        .parse()
+                        .ok()
+                        .filter(|&backlog| backlog > 0)
+                        .ok_or("--backlog must be a positive integer")?
+                }
+                "--max-header-value-size" => {
+                    config.max_header_value_size = Some(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|&max| max > 0)
+                            .ok_or("--max-header-value-size must be a positive integer")?,
+                    )
+                }
+                "--max-chunk-size" => {
+                    config.max_chunk_size = Some(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|&max| max > 0)
+                            .ok_or("--max-chunk-size must be a positive integer")?,
+                    )
+                }
+                "--max-connections" => {
+                    config.max_connections = Some(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|&max| max > 0)
+                            .ok_or("--max-connections must be a positive integer")?,
+                    )
+                }
+                "--overflow" => config.overflow_policy = OverflowPolicy::parse(&value)?,
+                "--accept-rate" => {
+                    config.accept_rate = Some(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|&rate: &f64| rate.is_finite() && rate > 0.0)
+                            .ok_or("--accept-rate must be a positive number")?,
+                    )
+                }
+                "--accept-rate-policy" => {
+                    config.accept_rate_policy = OverflowPolicy::parse(&value)?
+                }
+                "--max-tunnels" | "--max-forwards" => {
+                    let budget = Some(Budget::new(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|&max| max > 0)
+                            .ok_or(format!("{name} must be a positive integer"))?,
+                    ));
+                    if name == "--max-tunnels" {
+                        config.tunnel_budget = budget;
+                    } else {
+                        config.forward_budget = budget;
+                    }
+                }
+                "--max-conns-per-host" => {
+                    config.host_limits = Some(HostLimits::new(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|&max| max > 0)
+                            .ok_or("--max-conns-per-host must be a positive integer")?,
+                    ))
+                }
+                "--per-host-overflow" => config.per_host_overflow = OverflowPolicy::parse(&value)?,
+                "--max-hosts-per-conn" => {
+                    config.max_hosts_per_connection = Some(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|&max| max > 0)
+                            .ok_or("--max-hosts-per-conn must be a positive integer")?,
+                    )
+                }
+                "--fd-warn-ratio" => config.fd_warn_ratio = parse_ratio(name, &value)?,
+                "--fd-reject-ratio" => config.fd_reject_ratio = Some(parse_ratio(name, &value)?),
+                "--max-memory" => {
+                    config.max_memory = Some(
+                        value
+                            .parse::<u64>()
+                            .ok()
+                            .filter(|&max| max > 0)
+                            .and_then(|max| max.checked_mul(1024 * 1024))
+                            .ok_or("--max-memory must be a positive integer")?,
+                    )
+                }
+                "--connect-timeout" => config.timeouts.connect = Some(parse_seconds(name, &value)?),
+                "--idle-timeout" => config.timeouts.idle = Some(parse_seconds(name, &value)?),
+                "--tunnel-idle-timeout" => {
+                    config.timeouts.tunnel_idle = Some(parse_seconds(name, &value)?)
+                }
+                "--response-timeout" => {
+                    config.timeouts.response = Some(parse_seconds(name, &value)?)
+                }
+                "--max-duration" => config.timeouts.duration = Some(parse_seconds(name, &value)?),
+                "--max-tunnel-duration" => {
+                    config.timeouts.tunnel_duration = Some(parse_seconds(name, &value)?)
+                }
+                "--max-concurrent-resolves" => {
+                    let limit = value
+                        .parse()
+                        .ok()
+                        .filter(|&limit| limit > 0)
+                        .ok_or("--max-concurrent-resolves must be a positive integer")?;
+                    config.resolver.limit = Some(Semaphore::new(limit));
+                }
+                "--max-concurrent-setups" => {
+                    let limit = value
+                        .parse()
+                        .ok()
+                        .filter(|&limit| limit > 0)
+                        .ok_or("--max-concurrent-setups must be a positive integer")?;
+                    config.setup_limit = Some(Semaphore::new(limit));
+                }
+                "--pool-max-idle-per-host" => {
+                    config.pool.max_idle_per_host = value
+                        .parse()
+                        .map_err(|_| "--pool-max-idle-per-host must be a non-negative integer")?
+                }
+                "--pool-max-idle-total" => {
+                    config.pool.max_idle_total = value
+                        .parse()
+                        .map_err(|_| "--pool-max-idle-total must be a non-negative integer")?
+                }
+                "--pool-idle-timeout" => config.pool.idle_timeout = parse_seconds(name, &value)?,
+                "--max-free-buffers" => {
+                    config.buffers.max_free = value
+                        .parse()
+                        .map_err(|_| "--max-free-buffers must be a non-negative integer")?
+                }
+                "--host-override" => config.resolver.add_override(&value)?,
+                "--remap" => config.resolver.add_remap(&value)?,
+                // refused at startup rather than failing every connection to a server later
+                "--source-port-range" if cfg!(not(target_os = "linux")) => {
+                    return Err("--source-port-range is only supported on Linux".to_owned())
+                }
+                "--source-port-range" => config.source_ports = Some(source::parse_range(&value)?),
+                "--upstream-family" => config.resolver.family = Family::parse(&value)?,
+                "--hosts-file" => config.resolver.load_hosts_file(&value)?,
+                "--balance-resolved" => config.balancer = Some(Balancer::new()),
+                "--upstream-proxy" => upstream_list = Some(value),
+                "--upstream-policy" => upstream_policy = UpstreamPolicy::parse(&value)?,
+                "--fallback-direct" => config.fallback_direct = true,
+                "--no-proxy" => config.no_proxy = NoProxy::parse(&value)?,
+                "--route" => config.routes.push(Route::parse(&value)?),
+                "--default-mode" => config.default_mode = Mode::parse(&value)?,
+                "--serve-pac" => config.serve_pac = true,
+                "--enable-ftp" => config.enable_ftp = true,
+                "--enable-trace" => config.enable_trace = true,
+                "--reject-on-missing-host" => config.reject_on_missing_host = true,
+                "--require-user-agent" => config.require_user_agent = true,
+                "--log-format" => config.log_format = LogFormat::parse(&value)?,
+                "--log-server-addr" => config.log_server_addr = true,
+                "--log-route" => config.log_route = true,
+                "--log-connections" => config.log_connections = true,
+                "--log-tls-clienthello" => config.log_tls_client_hello = true,
+                "--forwarded-header" => {
+                    config.forwarded_header = Some(ForwardedHeader::parse(&value)?)
+                }
+                "--stats-allow-cidr" => config.stats_allowed_clients.push(
+                    Network::parse(&value)
+                        .ok_or(format!("Invalid stats client network: {value}"))?,
+                ),
+                "--allow-client-cidr" => config.allowed_clients.push(
+                    Network::parse(&value).ok_or(format!("Invalid client network: {value}"))?,
+                ),
+                "--egress-cidr" => config.egress_networks.push(
+                    Network::parse(&value).ok_or(format!("Invalid egress network: {value}"))?,
+                ),
+                "--error-format" => config.error_format = ErrorFormat::parse(&value)?,
+                "--stats-interval" => {
+                    config.stats_interval = Some(
+                        parse_seconds(name, &value)
+                            .ok()
+                            .filter(|interval| !interval.is_zero())
+                            .ok_or("--stats-interval must be a positive number of seconds")?,
+                    )
+                }
+                "--drain-timeout" => config.drain_timeout = parse_seconds(name, &value)?,
+                "--linger-seconds" => config.linger = Some(parse_seconds(name, &value)?),
+                "--log-file" => config.log_file = Some(value),
+                "--tee" => tee_target = Some(value),
+                "--tee-direction" => tee_direction = TeeDirection::parse(&value)?,
+                _ => unreachable!("{name} is listed in OPTIONS but not handled"),
+            }
+            Ok(None)
+        };
+        match parse_arg() {
+            Ok(Some(command)) => return Ok(command),
+            Ok(None) => {}
+            Err(e) => errors.push(e),
+        }
+    }
+
+    if seen.contains("--per-host-overflow") && config.host_limits.is_none() {
+        errors.push("--per-host-overflow requires --max-conns-per-host".to_owned());
+    }
+    if seen.contains("--server-name") && config.stats_listen_addr.is_none() {
+        errors.push("--server-name requires --stats-listen".to_owned());
+    }
+    if seen.contains("--stats-allow-cidr") && config.stats_listen_addr.is_none() {
+        errors.push("--stats-allow-cidr requires --stats-listen".to_owned());
+    }
+    if seen.contains("--overflow") && config.max_connections.is_none() {
+        errors.push("--overflow requires --max-connections".to_owned());
+    }
+    if seen.contains("--accept-rate-policy") && config.accept_rate.is_none() {
+        errors.push("--accept-rate-policy requires --accept-rate".to_owned());
+    }
+    if seen.contains("--upstream-policy") && upstream_list.is_none() {
+        errors.push("--upstream-policy requires --upstream-proxy".to_owned());
+    }
+
+    // the list also picks the hosts the PAC file sends browsers to directly, so it means
+    // something without a parent proxy as long as the PAC file is served
+    if seen.contains("--no-proxy") && upstream_list.is_none() && !config.serve_pac {
+        errors.push("--no-proxy requires --upstream-proxy or --serve-pac".to_owned());
+    }
+    if seen.contains("--fallback-direct") && upstream_list.is_none() {
+        errors.push("--fallback-direct requires --upstream-proxy".to_owned());
+    }
+    if seen.contains("--tee-direction") && tee_target.is_none() {
+        errors.push("--tee-direction requires --tee".to_owned());
+    }
+
+    if let Some(list) = upstream_list {
+        match UpstreamProxies::parse(&list, upstream_policy) {
+            Ok(upstreams) => config.upstream_proxies = Some(upstreams),
+            Err(e) => errors.push(e),
+        }
+    }
+    errors.extend(check_addresses(&config));
+
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+
+    // the sink is only opened once nothing stands in the way of starting
+    if let Some(target) = tee_target {
+        config.tee = Some(Tee::open(&target, tee_direction)?);
+    }
+
+    Ok(Command::Run(Box::new(config)))
+}
+
+/// Checks that the addresses the proxy will need can be resolved now, rather than leaving a bad
+/// one to fail every connection that uses it
+fn check_addresses(config: &Config) -> Vec<String> {
+    let mut errors = Vec::new();
+    let listen_addrs = std::iter::once(("--listen", &config.listen_addr)).chain(
+        config
+            .stats_listen_addr
+            .iter()
+            .map(|addr| ("--stats-listen", addr)),
+    );
+    for (name, addr) in listen_addrs {
+        if let Err(e) = addr.to_socket_addrs() {
+            errors.push(format!("{name} address {addr} is invalid: {e}"));
+        }
+    }
+    if let Some(upstreams) = &config.upstream_proxies {
+        for addr in upstreams.addrs() {
+            if let Err(e) = config.resolver.resolve_authority(addr) {
+                errors.push(format!("Upstream proxy {addr} could not be resolved: {e}"));
+            }
+        }
+    }
+    errors
+}
+
+/// Returns the help text listing every option
+pub fn usage() -> String {
+    let mut usage = format!(
+        "{} {}\nA simple HTTP and HTTPS proxy server\n\nUsage: {} [OPTIONS]\n\nOptions:\n",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        env!("CARGO_PKG_NAME"),
+    );
+
+    let flags: Vec<String> = OPTIONS
+        .iter()
+        .map(|opt| match opt.value {
+            Some(value) => format!("{} <{value}>", opt.name),
+            None => opt.name.to_owned(),
+        })
+        .collect();
+    let width = flags.iter().map(String::len).max().unwrap_or_default();
+
+    for (opt, flag) in OPTIONS.iter().zip(flags) {
+        usage.push_str(&format!("  {flag:width$}  {}\n", opt.help));
+    }
+    usage
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Command, String> {
+        parse_args(args.iter().map(|&arg| arg.to_owned()))
+    }
+
+    fn config(args: &[&str]) -> Config {
+        match parse(args) {
+            Ok(Command::Run(config)) => *config,
+            Ok(_) => panic!("{args:?} didn't ask to run the proxy"),
+            Err(e) => panic!("{args:?} were refused: {e}"),
+        }
+    }
+
+    fn error(args: &[&str]) -> String {
+        match parse(args) {
+            Err(e) => e,
+            Ok(_) => panic!("{args:?} were accepted"),
+        }
+    }
+
+    #[test]
+    fn deadline_only_cuts_the_timeouts_that_are_set() {
+        let timeouts = Timeouts {
+            connect: Some(Duration::from_secs(30)),
+            idle: Some(Duration::from_secs(60)),
+            ..Timeouts::default()
+        };
+        let within = timeouts.within(Some(Instant::now() + Duration::from_secs(2)));
+        assert!(within.connect.unwrap() <= Duration::from_secs(2));
+        assert!(within.connect.unwrap() > Duration::from_secs(1));
+        assert!(within.response.is_none());
+        assert_eq!(within.idle, timeouts.idle);
+
+        let passed = timeouts.within(Some(Instant::now()));
+        assert_eq!(passed.connect, Some(MIN_REMAINING));
+        assert_eq!(timeouts.within(None).connect, timeouts.connect);
+    }
+
+    #[test]
+    fn no_arguments_give_the_defaults() {
+        let config = config(&[]);
+        assert_eq!(config.listen_addr, DEFAULT_LISTEN_ADDR);
+        assert_eq!(config.backlog, DEFAULT_BACKLOG);
+        assert!(config.stats_listen_addr.is_none());
+        assert!(config.max_connections.is_none());
+        assert!(config.default_mode == Mode::Forward);
+    }
+
+    #[test]
+    fn values_may_follow_the_option_or_an_equals_sign() {
+        let config = config(&[
+            "--listen",
+            "127.0.0.1:0",
+            "--max-connections=5",
+            "--idle-timeout",
+            "30",
+        ]);
+        assert_eq!(config.listen_addr, "127.0.0.1:0");
+        assert_eq!(config.max_connections, Some(5));
+        assert_eq!(config.timeouts.idle, Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn help_and_version_stop_parsing() {
+        assert!(matches!(parse(&["-h"]), Ok(Command::Help)));
+        assert!(matches!(
+            parse(&["--version", "--no-such-option"]),
+            Ok(Command::Version)
+        ));
+        assert!(usage().contains("--max-connections <N>"));
+    }
+
+    #[test]
+    fn malformed_options_are_refused() {
+        assert_eq!(
+            error(&["--no-such-option"]),
+            "Unknown argument: --no-such-option"
+        );
+        assert_eq!(error(&["--listen"]), "Missing value for --listen");
+        assert_eq!(
+            error(&["--serve-pac=yes"]),
+            "--serve-pac does not take a value"
+        );
+        assert_eq!(
+            error(&["--listen", "127.0.0.1:0", "--listen", "127.0.0.1:0"]),
+            "--listen may only be given once"
+        );
+        assert_eq!(
+            error(&["--max-connections", "0"]),
+            "--max-connections must be a positive integer"
+        );
+    }
+
+    #[test]
+    fn options_missing_the_option_they_refine_are_refused() {
+        assert_eq!(
+            error(&["--overflow", "reject"]),
+            "--overflow requires --max-connections"
+        );
+        assert_eq!(
+            error(&["--stats-allow-cidr", "10.0.0.0/8"]),
+            "--stats-allow-cidr requires --stats-listen"
+        );
+        assert!(matches!(
+            parse(&["--overflow", "reject", "--max-connections", "5"]),
+            Ok(Command::Run(_))
+        ));
+        assert_eq!(
+            error(&["--no-proxy", "example.com"]),
+            "--no-proxy requires --upstream-proxy or --serve-pac"
+        );
+    }
+
+    #[test]
+    fn bypass_list_shapes_the_pac_file_without_an_upstream_proxy() {
+        let config = config(&["--serve-pac", "--no-proxy", "example.com,10.0.0.0/8"]);
+        assert!(config.upstream_proxies.is_none());
+        let pac = config.no_proxy.pac_file("192.0.2.1:8080".parse().unwrap());
+        assert!(pac.contains("dnsDomainIs(host, \".example.com\")) return \"DIRECT\""));
+        assert!(pac.contains("isInNet(host, \"10.0.0.0\", \"255.0.0.0\")) return \"DIRECT\""));
+        assert!(pac.ends_with("return \"PROXY 192.0.2.1:8080\";\n}\n"));
+    }
+
+    #[test]
+    fn summary_shows_the_options_in_effect() {
+        let defaults = config(&[]).summary();
+        assert!(defaults.contains(&"Upstream: none, connecting directly".to_owned()));
+        assert!(defaults.contains(&"Logging: default format to stdout".to_owned()));
+
+        let summary = config(&[
+            "--listen",
+            "127.0.0.1:3128",
+            "--connect-timeout",
+            "7",
+            "--tunnel-idle-timeout",
+            "90",
+            "--max-connections",
+            "20",
+            "--overflow",
+            "reject",
+            "--max-tunnels",
+            "4",
+            "--upstream-proxy",
+            "192.0.2.1:3128",
+            "--fallback-direct",
+            "--log-format",
+            "combined",
+            "--log-file",
+            "/tmp/access.log",
+        ])
+        .summary();
+        assert!(
+            summary[0].starts_with("Listening: 127.0.0.1:3128 "),
+            "{summary:?}"
+        );
+        assert!(summary[1].contains(" connect=7s "), "{summary:?}");
+        assert!(summary[1].contains(" tunnel-idle=90s "), "{summary:?}");
+        assert!(
+            summary[2].contains("connections=20 (reject)"),
+            "{summary:?}"
+        );
+        assert!(summary[2].contains(" tunnels=4 "), "{summary:?}");
+        assert!(summary
+            .contains(&"Upstream: 192.0.2.1:3128, falling back to direct connections".to_owned()));
+        assert!(summary.contains(&"Logging: combined format to /tmp/access.log".to_owned()));
+    }
+
+    #[test]
+    fn every_error_is_reported_at_once() {
+        let errors = error(&[
+            "--no-such-option",
+            "--max-connections",
+            "zero",
+            "--fallback-direct",
+            "--listen",
+            "not an address",
+        ]);
+        let errors: Vec<_> = errors.lines().collect();
+        assert_eq!(errors.len(), 4, "{errors:?}");
+        assert_eq!(errors[0], "Unknown argument: --no-such-option");
+        assert_eq!(errors[1], "--max-connections must be a positive integer");
+        assert_eq!(errors[2], "--fallback-direct requires --upstream-proxy");
+        assert!(errors[3].starts_with("--listen address not an address is invalid"));
+    }
+}
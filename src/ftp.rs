@@ -0,0 +1,331 @@
+use crate::config::Timeouts;
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpStream},
+};
+
+/// Port FTP servers listen on when a URL doesn't give one
+pub const DEFAULT_PORT: u16 = 21;
+
+/// Password sent when a URL gives no credentials, by convention an email address
+const ANONYMOUS_PASSWORD: &str = "anonymous@";
+
+/// A failed retrieval, with the status of the http response explaining it
+pub struct Error {
+    pub status: &'static str,
+    pub message: String,
+}
+
+impl Error {
+    fn failed(message: impl Into<String>) -> Error {
+        Error {
+            status: "502 Bad Gateway",
+            message: message.into(),
+        }
+    }
+}
+
+/// A reply from the server on the control connection
+struct Reply {
+    code: u16,
+    text: String,
+}
+
+/// The control connection to an FTP server, logged in and ready to transfer a file
+pub struct Session {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    /// path of the file to retrieve, relative to the directory the login starts in
+    path: String,
+    /// size of the file, if the server reports it
+    pub size: Option<u64>,
+}
+
+impl Session {
+    /// Connects to the server of an `ftp://` URL at the first of `addrs` that can be reached and
+    /// logs in, with the URL's credentials or anonymously
+    pub fn open(
+        url: &url::Url,
+        addrs: io::Result<Vec<SocketAddr>>,
+        timeouts: Timeouts,
+    ) -> Result<Session, Error> {
+        let path = decode(url.path().trim_start_matches('/'))?;
+        if path.is_empty() || path.ends_with('/') {
+            return Err(Error {
+                status: "404 Not Found",
+                message: "Only files can be retrieved over ftp, not directories".to_owned(),
+            });
+        }
+        let user = match decode(url.username())? {
+            user if user.is_empty() => "anonymous".to_owned(),
+            user => user,
+        };
+        let password = match url.password() {
+            Some(password) => decode(password)?,
+            None => ANONYMOUS_PASSWORD.to_owned(),
+        };
+
+        let stream = addrs
+            .and_then(|addrs| crate::connect(&*addrs, timeouts.connect))
+            .and_then(|stream| {
+                stream.set_read_timeout(timeouts.idle)?;
+                Ok(stream)
+            })
+            .map_err(|e| Error::failed(format!("Could not connect to the ftp server: {e}")))?;
+        let mut session = Session {
+            reader: BufReader::new(
+                stream
+                    .try_clone()
+                    .map_err(|e| Error::failed(e.to_string()))?,
+            ),
+            writer: stream,
+            path,
+            size: None,
+        };
+
+        session.expect(None, 220)?;
+        let reply = session.command(&format!("USER {user}"))?;
+        match reply.code {
+            230 => {}
+            331 => {
+                let reply = session.command(&format!("PASS {password}"))?;
+                if reply.code != 230 {
+                    return Err(Error {
+                        status: "403 Forbidden",
+                        message: format!("The ftp server refused the login: {}", reply.text),
+                    });
+                }
+            }
+            _ => return Err(unexpected(&reply)),
+        }
+        session.expect(Some("TYPE I"), 200)?;
+
+        let reply = session.command(&format!("SIZE {}", session.path))?;
+        match reply.code {
+            213 => session.size = reply.text.trim().parse().ok(),
+            550 => return Err(not_found(&reply)),
+            // not every server supports SIZE
+            _ => {}
+        }
+        Ok(session)
+    }
+
+    /// The address of the server
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.writer.peer_addr()
+    }
+
+    /// Starts retrieving the file, returning the connection its contents arrive on
+    pub fn retrieve(&mut self) -> Result<TcpStream, Error> {
+        let data = self.open_data_connection()?;
+        let reply = self.command(&format!("RETR {}", self.path))?;
+        match reply.code {
+            125 | 150 => Ok(data),
+            550 => Err(not_found(&reply)),
+            _ => Err(unexpected(&reply)),
+        }
+    }
+
+    /// Waits for the server to confirm that a retrieval was complete once its data connection
+    /// has been read to the end, then logs out
+    pub fn finish(mut self) -> Result<(), Error> {
+        let reply = self.read_reply()?;
+        if reply.code != 226 && reply.code != 250 {
+            return Err(unexpected(&reply));
+        }
+        let _ = self.command("QUIT");
+        Ok(())
+    }
+
+    /// Asks the server for a passive data connection and opens it.
+    ///
+    /// The data connection always goes to the address of the control connection, whatever
+    /// address the server gives, so that a server can't point the proxy at another host.
+    fn open_data_connection(&mut self) -> Result<TcpStream, Error> {
+        let server = self.peer_addr().map_err(|e| Error::failed(e.to_string()))?;
+        let reply = self.command("EPSV")?;
+        let port = if reply.code == 229 {
+            // e.g. `Entering Extended Passive Mode (|||6446|)`
+            reply
+                .text
+                .split('|')
+                .nth(3)
+                .and_then(|port| port.parse().ok())
+        } else {
+            let reply = self.command("PASV")?;
+            if reply.code != 227 {
+                return Err(unexpected(&reply));
+            }
+            // e.g. `Entering Passive Mode (192,168,1,2,25,46)`, with the port in the last two
+            let numbers: Vec<u16> = reply
+                .text
+                .split(|c: char| !c.is_ascii_digit())
+                .filter_map(|number| number.parse().ok())
+                .collect();
+            match numbers[..] {
+                [.., high, low] if numbers.len() >= 6 && high < 256 && low < 256 => {
+                    Some(high << 8 | low)
+                }
+                _ => None,
+            }
+        };
+        let port = port.ok_or_else(|| unexpected(&reply))?;
+
+        let data = crate::connect(SocketAddr::new(server.ip(), port), None)
+            .map_err(|e| Error::failed(format!("Could not open the ftp data connection: {e}")))?;
+        let _ = data.set_read_timeout(self.writer.read_timeout().unwrap_or_default());
+        Ok(data)
+    }
+
+    /// Sends `command`, if there is one, and fails unless the reply has status `code`
+    fn expect(&mut self, command: Option<&str>, code: u16) -> Result<Reply, Error> {
+        let reply = match command {
+            Some(command) => self.command(command)?,
+            None => self.read_reply()?,
+        };
+        if reply.code != code {
+            return Err(unexpected(&reply));
+        }
+        Ok(reply)
+    }
+
+    fn command(&mut self, command: &str) -> Result<Reply, Error> {
+        self.writer
+            .write_all(format!("{command}\r\n").as_bytes())
+            .map_err(|e| Error::failed(e.to_string()))?;
+        self.read_reply()
+    }
+
+    /// Reads a reply, which spans several lines when its code is followed by `-`, up to a line
+    /// starting with the same code and a space
+    fn read_reply(&mut self) -> Result<Reply, Error> {
+        let mut read_line = || {
+            let mut line = String::new();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => Err(Error::failed("The ftp server closed the connection")),
+                Ok(_) => Ok(line),
+                Err(e) => Err(Error::failed(e.to_string())),
+            }
+        };
+
+        let first = read_line()?;
+        let code = first
+            .get(..3)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| Error::failed(format!("Malformed ftp reply: {}", first.trim_end())))?;
+        let mut last = first.clone();
+        if first.as_bytes().get(3) == Some(&b'-') {
+            let end = format!("{code} ");
+            while !last.starts_with(&end) {
+                last = read_line()?;
+            }
+        }
+        Ok(Reply {
+            code,
+            text: last.get(4..).unwrap_or_default().trim_end().to_owned(),
+        })
+    }
+}
+
+fn unexpected(reply: &Reply) -> Error {
+    Error::failed(format!(
+        "Unexpected reply from the ftp server: {} {}",
+        reply.code, reply.text
+    ))
+}
+
+fn not_found(reply: &Reply) -> Error {
+    Error {
+        status: "404 Not Found",
+        message: format!("The ftp server has no such file: {}", reply.text),
+    }
+}
+
+/// Decodes a percent-encoded part of a URL, refusing line breaks that would end an ftp command
+/// early
+fn decode(encoded: &str) -> Result<String, Error> {
+    let mut bytes = Vec::new();
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = match tail {
+            [high, low, ..] if byte == b'%' => std::str::from_utf8(&[*high, *low])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match escaped {
+            Some(escaped) => {
+                bytes.push(escaped);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    if bytes.contains(&b'\r') || bytes.contains(&b'\n') {
+        return Err(Error {
+            status: "400 Bad Request",
+            message: "The ftp URL contains line breaks".to_owned(),
+        });
+    }
+    String::from_utf8(bytes).map_err(|_| Error {
+        status: "400 Bad Request",
+        message: "The ftp URL is not valid UTF-8".to_owned(),
+    })
+}
+
+/// Guesses the media type of a file from the extension of its `path`
+pub fn content_type(path: &str) -> &'static str {
+    let extension = path.rsplit_once('.').map_or("", |(_, extension)| extension);
+    match extension.to_ascii_lowercase().as_str() {
+        "txt" | "md" | "asc" => "text/plain",
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "csv" => "text/csv",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" | "tgz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_undoes_percent_encoding() {
+        assert_eq!(decode("dir/my%20file.txt").ok().unwrap(), "dir/my file.txt");
+        // a `%` not followed by two hex digits is kept as it is
+        assert_eq!(decode("100%").ok().unwrap(), "100%");
+        assert_eq!(decode("%zz").ok().unwrap(), "%zz");
+    }
+
+    #[test]
+    fn decode_refuses_line_breaks_that_would_end_a_command() {
+        for encoded in ["file%0d%0aDELE%20other", "file%0A", "file%0d"] {
+            let error = decode(encoded).err().unwrap();
+            assert_eq!(error.status, "400 Bad Request");
+        }
+        assert!(decode("%ff").is_err());
+    }
+
+    #[test]
+    fn content_type_follows_the_extension() {
+        assert_eq!(content_type("/pub/README.TXT"), "text/plain");
+        assert_eq!(content_type("/pub/archive.tar.gz"), "application/gzip");
+        assert_eq!(
+            content_type("/pub/no-extension"),
+            "application/octet-stream"
+        );
+    }
+}
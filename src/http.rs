@@ -0,0 +1,692 @@
+use std::{
+    io::{self, Read, Write},
+    net::IpAddr,
+};
+
+/// Largest request or response head the proxy will read
+const MAX_HEAD: usize = 8192;
+
+/// Maximum number of header fields in a request or response head
+const MAX_HEADERS: usize = 64;
+
+pub struct Header {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+/// A parsed request head
+pub struct Request {
+    pub method: String,
+    pub target: String,
+    /// minor version of HTTP/1.x
+    pub version: u8,
+    pub headers: Vec<Header>,
+    /// the head exactly as it was received
+    pub raw: Vec<u8>,
+}
+
+/// A parsed response head
+pub struct Response {
+    pub status: u16,
+    /// minor version of HTTP/1.x
+    pub version: u8,
+    pub headers: Vec<Header>,
+    /// the head exactly as it was received
+    pub raw: Vec<u8>,
+}
+
+fn owned_headers(headers: &[httparse::Header]) -> Vec<Header> {
+    headers
+        .iter()
+        .map(|header| Header {
+            name: header.name.to_owned(),
+            value: header.value.to_owned(),
+        })
+        .collect()
+}
+
+impl Request {
+    pub fn parse(raw: Vec<u8>) -> Result<Request, String> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+        if err_to_str!(request.parse(&raw))?.is_partial() {
+            return Err("Incomplete request head".to_owned());
+        }
+
+        let target = request.path.ok_or("Unable to get request path")?;
+        // the head is rebuilt from these parts before being forwarded, so anything that could end
+        // a line early must not get through
+        if target.bytes().any(|byte| byte <= b' ' || byte == 0x7f) {
+            return Err("Request target contains control characters".to_owned());
+        }
+        if let Some(header) = request.headers.iter().find(|header| {
+            header
+                .value
+                .iter()
+                .any(|&byte| (byte < b' ' && byte != b'\t') || byte == 0x7f)
+        }) {
+            return Err(format!(
+                "Header {} contains control characters",
+                header.name
+            ));
+        }
+
+        Ok(Request {
+            method: request
+                .method
+                .ok_or("Unable to get request method")?
+                .to_owned(),
+            target: target.to_owned(),
+            version: request.version.unwrap_or(1),
+            headers: owned_headers(request.headers),
+            raw,
+        })
+    }
+
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        header_value(&self.headers, name)
+    }
+
+    /// The request line, e.g. `GET http://example.com/ HTTP/1.1`
+    pub fn line(&self) -> String {
+        format!("{} {} HTTP/1.{}", self.method, self.target, self.version)
+    }
+
+    /// Whether the connection should be closed once this request has been answered
+    pub fn wants_close(&self) -> bool {
+        wants_close(&self.headers, self.version)
+    }
+
+    /// The format for error responses to this request: JSON if the client accepts it, otherwise
+    /// `default`
+    pub fn error_format(&self, default: ErrorFormat) -> ErrorFormat {
+        let accepts_json = self.header("Accept").is_some_and(|accept| {
+            accept
+                .split(|&byte| byte == b',')
+                .map(|range| range.split(|&byte| byte == b';').next().unwrap_or_default())
+                .any(|range| range.trim_ascii().eq_ignore_ascii_case(b"application/json"))
+        });
+        if accepts_json {
+            ErrorFormat::Json
+        } else {
+            default
+        }
+    }
+
+    /// Whether the client will wait for a `100 Continue` before sending the request body
+    pub fn expects_continue(&self) -> bool {
+        self.header("Expect")
+            .is_some_and(|value| value.eq_ignore_ascii_case(b"100-continue"))
+    }
+
+    /// The head as it was received, for a `TRACE` response, without the `Cookie`, `Authorization`
+    /// and `Proxy-Authorization` headers a script on another site could otherwise read from it
+    pub fn traced(&self) -> Vec<u8> {
+        const CREDENTIALS: [&[u8]; 3] = [b"cookie", b"authorization", b"proxy-authorization"];
+        let mut traced = Vec::with_capacity(self.raw.len());
+        let mut dropping = false;
+        for line in self.raw.split_inclusive(|&byte| byte == b'\n') {
+            // a line starting with whitespace continues the header before it
+            if !line.starts_with(b" ") && !line.starts_with(b"\t") {
+                let name = line.split(|&byte| byte == b':').next().unwrap_or_default();
+                dropping = line.contains(&b':')
+                    && CREDENTIALS
+                        .iter()
+                        .any(|credential| name.trim_ascii().eq_ignore_ascii_case(credential));
+            }
+            if !dropping {
+                traced.extend_from_slice(line);
+            }
+        }
+        traced
+    }
+
+    /// Rebuilds the head to be sent to the server with `target` in place of the client's request
+    /// target, dropping the hop-by-hop `Proxy-Connection` header and passing on a client's
+    /// request to close the connection.
+    ///
+    /// The client's `Host` header is kept exactly as it was sent, and only set to `host` if the
+    /// client left it out. A `100-continue` expectation is dropped, since the proxy answers it
+    /// itself before forwarding the body. `Content-Length` is dropped from a request that also
+    /// has `Transfer-Encoding`, which is what the proxy frames the body by, so a server can't be
+    /// made to read the body differently. The `extra` headers follow the client's.
+    pub fn forwarded_head(
+        &self,
+        target: &str,
+        host: &str,
+        close: bool,
+        extra: &[Header],
+    ) -> Vec<u8> {
+        let mut head = format!("{} {target} HTTP/1.{}\r\n", self.method, self.version).into_bytes();
+        if self.header("Host").is_none() {
+            head.extend_from_slice(format!("Host: {host}\r\n").as_bytes());
+        }
+
+        let transfer_coded = self.header("Transfer-Encoding").is_some();
+        let kept = self.headers.iter().filter(|header| {
+            !(header.name.eq_ignore_ascii_case("Proxy-Connection")
+                || (close && header.name.eq_ignore_ascii_case("Connection"))
+                || (transfer_coded && header.name.eq_ignore_ascii_case("Content-Length"))
+                || (header.name.eq_ignore_ascii_case("Expect")
+                    && header.value.eq_ignore_ascii_case(b"100-continue")))
+        });
+        for header in kept.chain(extra) {
+            head.extend_from_slice(header.name.as_bytes());
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(&header.value);
+            head.extend_from_slice(b"\r\n");
+        }
+
+        if close {
+            head.extend_from_slice(b"Connection: close\r\n");
+        }
+        head.extend_from_slice(b"\r\n");
+        head
+    }
+}
+
+impl Response {
+    pub fn parse(raw: Vec<u8>) -> Result<Response, String> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut response = httparse::Response::new(&mut headers);
+        if err_to_str!(response.parse(&raw))?.is_partial() {
+            return Err("Incomplete response head".to_owned());
+        }
+
+        Ok(Response {
+            status: response.code.ok_or("Unable to get response status")?,
+            version: response.version.unwrap_or(1),
+            headers: owned_headers(response.headers),
+            raw,
+        })
+    }
+
+    /// Whether the server will close the connection once this response has been sent
+    pub fn wants_close(&self) -> bool {
+        wants_close(&self.headers, self.version)
+    }
+}
+
+/// Returns the value of the first header named `name` (case-insensitive)
+pub fn header_value<'a>(headers: &'a [Header], name: &str) -> Option<&'a [u8]> {
+    headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case(name))
+        .map(|header| header.value.as_slice())
+}
+
+/// Whether a message with `headers` asks for its connection to be closed afterwards, either with
+/// a `close` directive in `Connection` or `Proxy-Connection`, or by being HTTP/1.0 without asking
+/// to be kept alive
+fn wants_close(headers: &[Header], version: u8) -> bool {
+    let has_directive = |directive: &[u8]| {
+        headers
+            .iter()
+            .filter(|header| {
+                header.name.eq_ignore_ascii_case("Connection")
+                    || header.name.eq_ignore_ascii_case("Proxy-Connection")
+            })
+            .flat_map(|header| header.value.split(|&byte| byte == b','))
+            .any(|token| token.trim_ascii().eq_ignore_ascii_case(directive))
+    };
+    has_directive(b"close") || (version == 0 && !has_directive(b"keep-alive"))
+}
+
+/// Which headers telling servers where a request came from are added to forwarded requests
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ForwardedHeader {
+    /// `Forwarded`, as standardized in RFC 7239
+    Standard,
+    /// `X-Forwarded-For` and `X-Forwarded-Proto`
+    XForwarded,
+    /// all of the above
+    Both,
+}
+
+impl ForwardedHeader {
+    pub fn parse(style: &str) -> Result<ForwardedHeader, String> {
+        match style {
+            "standard" => Ok(ForwardedHeader::Standard),
+            "xforwarded" => Ok(ForwardedHeader::XForwarded),
+            "both" => Ok(ForwardedHeader::Both),
+            _ => Err(format!("Unknown forwarded header style: {style}")),
+        }
+    }
+
+    /// The headers for a request with scheme `proto` received from `client` by the proxy at
+    /// `proxy`.
+    ///
+    /// They are sent as new fields after any the client sent, which servers read as later
+    /// entries of the same list.
+    pub fn headers(self, client: IpAddr, proxy: IpAddr, proto: &str) -> Vec<Header> {
+        // ipv6 addresses must be bracketed and quoted in `Forwarded` values
+        let node = |ip: IpAddr| match ip {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("\"[{ip}]\""),
+        };
+        let header = |name: &str, value: String| Header {
+            name: name.to_owned(),
+            value: value.into_bytes(),
+        };
+
+        let mut headers = Vec::new();
+        if self != ForwardedHeader::XForwarded {
+            headers.push(header(
+                "Forwarded",
+                format!("for={};proto={proto};by={}", node(client), node(proxy)),
+            ));
+        }
+        if self != ForwardedHeader::Standard {
+            headers.push(header("X-Forwarded-For", client.to_string()));
+            headers.push(header("X-Forwarded-Proto", proto.to_owned()));
+        }
+        headers
+    }
+}
+
+/// What a client appears to be speaking, judging by the first bytes it sends
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// something that starts like an http request line
+    Http,
+    /// a TLS handshake, from a client that took the proxy port for an https one
+    Tls,
+    Unknown,
+}
+
+/// Reads the first bytes a client sends into `buffered` and works out what protocol it is
+/// speaking, or returns `None` if it closes without sending anything
+pub fn sniff(stream: &mut impl Read, buffered: &mut Vec<u8>) -> io::Result<Option<Protocol>> {
+    let mut chunk = [0; 4096];
+    let bytes = stream.read(&mut chunk)?;
+    buffered.extend_from_slice(&chunk[..bytes]);
+
+    let is_token = |byte: &u8| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(byte);
+    let method = buffered
+        .split(|&byte| byte == b' ')
+        .next()
+        .unwrap_or_default();
+    Ok(match buffered.as_slice() {
+        [] => None,
+        // a handshake record, then the major version of TLS and SSL 3
+        [0x16] | [0x16, 0x03, ..] => Some(Protocol::Tls),
+        _ if !method.is_empty() && method.iter().all(is_token) => Some(Protocol::Http),
+        _ => Some(Protocol::Unknown),
+    })
+}
+
+/// Reads from `stream` until `buffered` holds a complete head, then removes and returns it,
+/// leaving any bytes that followed in `buffered`.
+///
+/// Returns `None` if the stream ends before anything is received.
+pub fn read_head(stream: &mut impl Read, buffered: &mut Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+    // where to resume searching for the end of the head, a little before the end of the last
+    // search in case it stopped partway through the blank line
+    let mut search_from: usize = 0;
+    loop {
+        if let Some(end) = buffered[search_from..]
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+        {
+            let length = search_from + end + 4;
+            return Ok(Some(buffered.drain(..length).collect()));
+        }
+        search_from = buffered.len().saturating_sub(3);
+
+        if buffered.len() >= MAX_HEAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Message head too large",
+            ));
+        }
+
+        let mut chunk = [0; 4096];
+        match stream.read(&mut chunk)? {
+            0 if buffered.is_empty() => return Ok(None),
+            0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Connection closed mid message head",
+                ))
+            }
+            bytes => buffered.extend_from_slice(&chunk[..bytes]),
+        }
+    }
+}
+
+/// How the bodies of error responses generated by the proxy are written
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// a line of plain text
+    Text,
+    /// a JSON object giving the status code, its reason, and a message
+    Json,
+}
+
+impl ErrorFormat {
+    pub fn parse(format: &str) -> Result<ErrorFormat, String> {
+        match format {
+            "text" => Ok(ErrorFormat::Text),
+            "json" => Ok(ErrorFormat::Json),
+            _ => Err(format!("Unknown error format: {format}")),
+        }
+    }
+}
+
+/// Writes an error response with `status`, e.g. `504 Gateway Timeout`, explained by `message`
+pub fn send_error(
+    stream: &mut impl Write,
+    status: &str,
+    message: &str,
+    format: ErrorFormat,
+) -> io::Result<()> {
+    match format {
+        ErrorFormat::Text => send_response(
+            stream,
+            status,
+            "text/plain",
+            format!("{message}\n").as_bytes(),
+        ),
+        ErrorFormat::Json => {
+            let (code, reason) = status.split_once(' ').unwrap_or((status, ""));
+            let body = format!(
+                "{{\"status\":{code},\"error\":\"{}\",\"message\":\"{}\"}}\n",
+                json_escape(reason),
+                json_escape(message)
+            );
+            send_response(stream, status, "application/json", body.as_bytes())
+        }
+    }
+}
+
+/// Makes `value` safe to place between quotes in a JSON string
+fn json_escape(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Writes a complete response with the given status line and body to `stream`
+pub fn send_response(
+    stream: &mut impl Write,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(head: &str) -> Request {
+        Request::parse(head.as_bytes().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn forwarded_head_drops_content_length_alongside_transfer_encoding() {
+        let request = request(
+            "POST http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\
+             Transfer-Encoding: chunked\r\nContent-Length: 4\r\n\r\n",
+        );
+        let head =
+            String::from_utf8(request.forwarded_head("/", "example.com", false, &[])).unwrap();
+        assert_eq!(
+            head,
+            "POST / HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn traced_drops_credentials() {
+        let request = request(
+            "TRACE / HTTP/1.1\r\nHost: example.com\r\nCookie: session=1\r\n\
+             authorization: Basic dXNlcg==\r\nProxy-Authorization: Basic cHJveHk=\r\n\
+             Accept: */*\r\n\r\n",
+        );
+        assert_eq!(
+            request.traced(),
+            b"TRACE / HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn forwarded_head_keeps_content_length_alone() {
+        let request = request("POST / HTTP/1.1\r\nHost: example.com\r\nContent-Length: 4\r\n\r\n");
+        let head =
+            String::from_utf8(request.forwarded_head("/", "example.com", false, &[])).unwrap();
+        assert!(head.contains("Content-Length: 4\r\n"));
+    }
+
+    #[test]
+    fn parse_reads_the_request_line_and_headers() {
+        let request = request("GET http://example.com/a?b HTTP/1.0\r\nHost: example.com\r\n\r\n");
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.target, "http://example.com/a?b");
+        assert_eq!(request.version, 0);
+        assert_eq!(request.header("host"), Some(&b"example.com"[..]));
+        assert_eq!(request.line(), "GET http://example.com/a?b HTTP/1.0");
+    }
+
+    #[test]
+    fn parse_rejects_control_characters_that_could_split_the_rebuilt_head() {
+        for head in [
+            "GET http://example.com/\x7f HTTP/1.1\r\n\r\n",
+            "GET / HTTP/1.1\r\nX-Test: a\x0bb\r\n\r\n",
+            "GET / HTTP/1.1\r\nX-Test: a\x7fb\r\n\r\n",
+        ] {
+            assert!(
+                Request::parse(head.as_bytes().to_vec()).is_err(),
+                "{head:?}"
+            );
+        }
+        assert!(Request::parse(b"GET / HTTP/1.1\r\nX-Test: a\tb\r\n\r\n".to_vec()).is_ok());
+    }
+
+    #[test]
+    fn parse_rejects_incomplete_heads() {
+        assert!(Request::parse(b"GET / HTTP/1.1\r\nHost: a\r\n".to_vec()).is_err());
+        assert!(Response::parse(b"HTTP/1.1 200 OK\r\n".to_vec()).is_err());
+    }
+
+    #[test]
+    fn forwarded_head_keeps_the_clients_host_header_exactly() {
+        let request =
+            request("GET http://Example.COM:80/ HTTP/1.1\r\nhost: Example.COM:80\r\n\r\n");
+        let head = request.forwarded_head("/", "example.com", false, &[]);
+        assert_eq!(head, b"GET / HTTP/1.1\r\nhost: Example.COM:80\r\n\r\n");
+    }
+
+    #[test]
+    fn forwarded_head_adds_a_missing_host_header() {
+        let request = request("GET http://example.com/ HTTP/1.0\r\n\r\n");
+        let head = request.forwarded_head("/", "example.com:8080", false, &[]);
+        assert_eq!(head, b"GET / HTTP/1.0\r\nHost: example.com:8080\r\n\r\n");
+    }
+
+    #[test]
+    fn forwarded_head_drops_hop_by_hop_headers_and_passes_on_close() {
+        let request = request(
+            "PUT http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\
+             Proxy-Connection: keep-alive\r\nConnection: keep-alive\r\n\
+             Expect: 100-continue\r\nContent-Length: 0\r\n\r\n",
+        );
+        let extra = [Header {
+            name: "X-Extra".to_owned(),
+            value: b"1".to_vec(),
+        }];
+
+        let kept = request.forwarded_head("/", "example.com", false, &extra);
+        assert_eq!(
+            kept,
+            b"PUT / HTTP/1.1\r\nHost: example.com\r\nConnection: keep-alive\r\n\
+              Content-Length: 0\r\nX-Extra: 1\r\n\r\n"
+        );
+        let closed = request.forwarded_head("/", "example.com", true, &[]);
+        assert_eq!(
+            closed,
+            b"PUT / HTTP/1.1\r\nHost: example.com\r\nContent-Length: 0\r\n\
+              Connection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn forwarded_header_styles() {
+        let client = IpAddr::from([192, 0, 2, 1]);
+        let proxy: IpAddr = "2001:db8::1".parse().unwrap();
+        let headers = |style: &str| -> Vec<String> {
+            ForwardedHeader::parse(style)
+                .unwrap()
+                .headers(client, proxy, "http")
+                .into_iter()
+                .map(|header| {
+                    format!(
+                        "{}: {}",
+                        header.name,
+                        String::from_utf8_lossy(&header.value)
+                    )
+                })
+                .collect()
+        };
+        let forwarded = "Forwarded: for=192.0.2.1;proto=http;by=\"[2001:db8::1]\"";
+
+        assert_eq!(headers("standard"), [forwarded]);
+        assert_eq!(
+            headers("xforwarded"),
+            ["X-Forwarded-For: 192.0.2.1", "X-Forwarded-Proto: http"]
+        );
+        assert_eq!(
+            headers("both"),
+            [
+                forwarded,
+                "X-Forwarded-For: 192.0.2.1",
+                "X-Forwarded-Proto: http"
+            ]
+        );
+        assert!(ForwardedHeader::parse("x-forwarded").is_err());
+    }
+
+    #[test]
+    fn error_format_follows_the_accept_header() {
+        let json = request("GET / HTTP/1.1\r\nAccept: text/html, Application/JSON;q=0.9\r\n\r\n");
+        let html = request("GET / HTTP/1.1\r\nAccept: text/html\r\n\r\n");
+        assert!(json.error_format(ErrorFormat::Text) == ErrorFormat::Json);
+        assert!(html.error_format(ErrorFormat::Text) == ErrorFormat::Text);
+        assert!(html.error_format(ErrorFormat::Json) == ErrorFormat::Json);
+    }
+
+    #[test]
+    fn send_error_writes_text_or_escaped_json() {
+        let mut text = Vec::new();
+        send_error(&mut text, "502 Bad Gateway", "No route", ErrorFormat::Text).unwrap();
+        assert_eq!(
+            String::from_utf8(text).unwrap(),
+            "HTTP/1.1 502 Bad Gateway\r\nContent-Type: text/plain\r\nContent-Length: 9\r\n\
+             Connection: close\r\n\r\nNo route\n"
+        );
+
+        let mut json = Vec::new();
+        send_error(
+            &mut json,
+            "400 Bad Request",
+            "Bad \"target\"\n",
+            ErrorFormat::Json,
+        )
+        .unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.contains("Content-Type: application/json\r\n"));
+        assert!(json.ends_with(
+            "\r\n\r\n{\"status\":400,\"error\":\"Bad Request\",\"message\":\"Bad \\\"target\\\"\\u000a\"}\n"
+        ));
+    }
+
+    #[test]
+    fn read_head_leaves_pipelined_bytes_buffered() {
+        let mut stream: &[u8] = b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\nGET /c";
+        let mut buffered = Vec::new();
+        assert_eq!(
+            read_head(&mut stream, &mut buffered).unwrap().unwrap(),
+            b"GET /a HTTP/1.1\r\n\r\n"
+        );
+        assert_eq!(
+            read_head(&mut stream, &mut buffered).unwrap().unwrap(),
+            b"GET /b HTTP/1.1\r\n\r\n"
+        );
+        assert_eq!(buffered, b"GET /c");
+        let error = read_head(&mut stream, &mut buffered).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn read_head_finds_a_blank_line_split_across_reads() {
+        struct Trickle<'a>(&'a [u8]);
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let (first, rest) = self.0.split_at(self.0.len().min(1));
+                buf[..first.len()].copy_from_slice(first);
+                self.0 = rest;
+                Ok(first.len())
+            }
+        }
+        let mut buffered = Vec::new();
+        let head = read_head(&mut Trickle(b"GET / HTTP/1.1\r\n\r\n"), &mut buffered);
+        assert_eq!(head.unwrap().unwrap(), b"GET / HTTP/1.1\r\n\r\n");
+        assert!(read_head(&mut Trickle(b""), &mut buffered)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn read_head_gives_up_on_oversized_heads() {
+        let mut stream: &[u8] = &[b'a'; MAX_HEAD + 1];
+        let error = read_head(&mut stream, &mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn wants_close_reads_connection_directives_and_the_version() {
+        let wants_close = |head: &str| request(head).wants_close();
+        assert!(!wants_close("GET / HTTP/1.1\r\n\r\n"));
+        assert!(wants_close(
+            "GET / HTTP/1.1\r\nConnection: Upgrade, Close\r\n\r\n"
+        ));
+        assert!(wants_close(
+            "GET / HTTP/1.1\r\nProxy-Connection: close\r\n\r\n"
+        ));
+        assert!(wants_close("GET / HTTP/1.0\r\n\r\n"));
+        assert!(!wants_close(
+            "GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n"
+        ));
+
+        let response = Response::parse(b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n".to_vec());
+        assert!(response.unwrap().wants_close());
+    }
+
+    #[test]
+    fn sniff_tells_http_from_tls_and_other_protocols() {
+        let sniffed = |bytes: &[u8]| {
+            let mut buffered = Vec::new();
+            let protocol = sniff(&mut &bytes[..], &mut buffered).unwrap();
+            assert_eq!(buffered, bytes);
+            protocol
+        };
+        assert!(sniffed(b"GET / HTTP/1.1\r\n") == Some(Protocol::Http));
+        assert!(sniffed(b"\x16\x03\x01\x02\x00") == Some(Protocol::Tls));
+        assert!(sniffed(b"SSH-2.0-OpenSSH\r\n") == Some(Protocol::Unknown));
+        assert!(sniffed(b"\x00\x01") == Some(Protocol::Unknown));
+        assert!(sniffed(b"").is_none());
+    }
+}
@@ -0,0 +1,281 @@
+use crate::clock::{Clock, SystemClock};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Limits how many threads may hold a permit at once, making the rest wait their turn
+pub struct Semaphore {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+/// A permit held from a `Semaphore`, given back when dropped
+pub struct Permit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            available: Mutex::new(permits),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Waits until a permit is available and takes it
+    pub fn acquire(&self) -> Permit<'_> {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= 1;
+        Permit { semaphore: self }
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.semaphore.available.lock().unwrap() += 1;
+        self.semaphore.released.notify_one();
+    }
+}
+
+/// Limits how many connections to each host may be open at once
+pub struct HostLimits {
+    /// most connections to any one host
+    pub max_per_host: usize,
+    /// number of connections open to each lowercase host name
+    open: Mutex<HashMap<String, usize>>,
+    released: Condvar,
+}
+
+/// A connection to a host counted by `HostLimits`, uncounted when dropped
+pub struct HostPermit<'a> {
+    limits: &'a HostLimits,
+    host: String,
+}
+
+impl HostLimits {
+    pub fn new(max_per_host: usize) -> HostLimits {
+        HostLimits {
+            max_per_host,
+            open: Mutex::new(HashMap::new()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Counts a connection to `host`, waiting up to `wait` for one to close if the host is at its
+    /// limit, or returns `None` if it still is
+    pub fn acquire(&self, host: &str, wait: Duration) -> Option<HostPermit<'_>> {
+        let host = host.to_ascii_lowercase();
+        let deadline = Instant::now() + wait;
+        let mut open = self.open.lock().unwrap();
+        while open
+            .get(&host)
+            .is_some_and(|&count| count >= self.max_per_host)
+        {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return None;
+            }
+            open = self.released.wait_timeout(open, remaining).unwrap().0;
+        }
+        *open.entry(host.clone()).or_default() += 1;
+        Some(HostPermit { limits: self, host })
+    }
+}
+
+impl Drop for HostPermit<'_> {
+    fn drop(&mut self) {
+        let mut open = self.limits.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.host) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.host);
+            }
+        }
+        self.limits.released.notify_all();
+    }
+}
+
+/// Caps how many requests of one kind may be handled at once, turning away the rest
+pub struct Budget {
+    pub max: usize,
+    in_use: AtomicUsize,
+}
+
+/// A request counted by a `Budget`, uncounted when dropped
+pub struct BudgetPermit<'a> {
+    budget: &'a Budget,
+}
+
+impl Budget {
+    pub fn new(max: usize) -> Budget {
+        Budget {
+            max,
+            in_use: AtomicUsize::new(0),
+        }
+    }
+
+    /// Counts a request, or returns `None` if the budget is used up
+    pub fn try_acquire(&self) -> Option<BudgetPermit<'_>> {
+        self.in_use
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |in_use| {
+                (in_use < self.max).then_some(in_use + 1)
+            })
+            .ok()
+            .map(|_| BudgetPermit { budget: self })
+    }
+}
+
+impl Drop for BudgetPermit<'_> {
+    fn drop(&mut self) {
+        self.budget.in_use.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Allows events at a steady rate, with bursts of up to a second's worth after a quiet spell
+pub struct TokenBucket<C: Clock = SystemClock> {
+    clock: C,
+    /// events allowed per second
+    rate: f64,
+    /// most tokens the bucket holds
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64) -> TokenBucket {
+        TokenBucket::with_clock(rate, SystemClock)
+    }
+}
+
+impl<C: Clock> TokenBucket<C> {
+    /// A bucket refilled by the time read from `clock`
+    pub fn with_clock(rate: f64, clock: C) -> TokenBucket<C> {
+        let burst = rate.max(1.0);
+        TokenBucket {
+            rate,
+            burst,
+            tokens: burst,
+            refilled: clock.now(),
+            clock,
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = self.clock.now();
+        let earned = now.duration_since(self.refilled).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + earned).min(self.burst);
+        self.refilled = now;
+    }
+
+    /// Takes a token for an event, or returns `false` without taking one if the rate has been
+    /// reached
+    pub fn try_take(&mut self) -> bool {
+        self.refill();
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// How long until a token can be taken
+    pub fn wait(&mut self) -> Duration {
+        self.refill();
+        Duration::from_secs_f64((1.0 - self.tokens).max(0.0) / self.rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::{sync::Arc, thread};
+
+    #[test]
+    fn semaphore_makes_acquirers_wait_for_a_release() {
+        let semaphore = Arc::new(Semaphore::new(1));
+        let permit = semaphore.acquire();
+        let waiter = {
+            let semaphore = Arc::clone(&semaphore);
+            thread::spawn(move || drop(semaphore.acquire()))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+        drop(permit);
+        waiter.join().unwrap();
+        assert_eq!(*semaphore.available.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn host_limits_count_each_host_apart_and_ignore_case() {
+        let limits = HostLimits::new(1);
+        let first = limits.acquire("Example.com", Duration::ZERO).unwrap();
+        assert!(limits.acquire("example.COM", Duration::ZERO).is_none());
+        assert!(limits
+            .acquire("example.com", Duration::from_millis(20))
+            .is_none());
+        let other = limits.acquire("example.org", Duration::ZERO);
+        assert!(other.is_some());
+
+        drop(first);
+        assert!(limits.acquire("example.com", Duration::ZERO).is_some());
+        drop(other);
+        assert!(limits.open.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn host_limits_wait_for_a_connection_to_close() {
+        let limits = Arc::new(HostLimits::new(1));
+        let permit = limits.acquire("example.com", Duration::ZERO).unwrap();
+        let waiter = {
+            let limits = Arc::clone(&limits);
+            thread::spawn(move || {
+                limits
+                    .acquire("example.com", Duration::from_secs(5))
+                    .is_some()
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        drop(permit);
+        assert!(waiter.join().unwrap());
+    }
+
+    #[test]
+    fn budget_turns_away_requests_beyond_its_max() {
+        let budget = Budget::new(2);
+        let first = budget.try_acquire().unwrap();
+        let _second = budget.try_acquire().unwrap();
+        assert!(budget.try_acquire().is_none());
+        drop(first);
+        assert!(budget.try_acquire().is_some());
+    }
+
+    #[test]
+    fn token_bucket_allows_a_burst_then_refills_at_its_rate() {
+        let clock = ManualClock::new();
+        let mut bucket = TokenBucket::with_clock(2.0, &clock);
+        assert!(bucket.try_take());
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+        assert_eq!(bucket.wait(), Duration::from_millis(500));
+
+        clock.advance(Duration::from_millis(500));
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+
+        // a long quiet spell only refills up to the burst
+        clock.advance(Duration::from_secs(60));
+        assert!(bucket.try_take());
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+    }
+}
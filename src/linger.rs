@@ -0,0 +1,94 @@
+use std::{io, net::TcpStream, sync::OnceLock, time::Duration};
+
+/// How long closing a connection waits for its unsent data to be delivered, once set at startup
+static LINGER: OnceLock<Duration> = OnceLock::new();
+
+/// Makes closing every later client and server connection wait up to `linger` for unsent data,
+/// or reset the connection if `linger` is zero
+pub fn set(linger: Duration) {
+    let _ = LINGER.set(linger);
+}
+
+/// Applies the linger time set with `set` to `stream`, leaving the system's default of closing at
+/// once and delivering the data in the background if none was set
+#[cfg(unix)]
+pub fn apply(stream: &TcpStream) -> io::Result<()> {
+    use crate::sys;
+    use std::os::unix::io::AsRawFd;
+
+    let Some(linger) = LINGER.get() else {
+        return Ok(());
+    };
+    let linger = sys::Linger {
+        onoff: 1,
+        linger: linger
+            .as_secs()
+            .try_into()
+            .unwrap_or(std::os::raw::c_int::MAX),
+    };
+    // SAFETY: the value points to a `struct linger` of the given length that outlives the call
+    let result = unsafe {
+        sys::setsockopt(
+            stream.as_raw_fd(),
+            sys::SOL_SOCKET,
+            sys::SO_LINGER,
+            (&linger as *const sys::Linger).cast(),
+            size_of::<sys::Linger>() as u32,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn apply(_stream: &TcpStream) -> io::Result<()> {
+    match LINGER.get() {
+        Some(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Linger times are only supported on unix",
+        )),
+        None => Ok(()),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::sys;
+    use std::{net::TcpListener, os::unix::io::AsRawFd};
+
+    fn linger_of(stream: &TcpStream) -> sys::Linger {
+        let mut linger = sys::Linger {
+            onoff: 0,
+            linger: 0,
+        };
+        let mut len = size_of::<sys::Linger>() as u32;
+        // SAFETY: the value points to a `struct linger` of the given length that outlives the call
+        let result = unsafe {
+            sys::getsockopt(
+                stream.as_raw_fd(),
+                sys::SOL_SOCKET,
+                sys::SO_LINGER,
+                (&mut linger as *mut sys::Linger).cast(),
+                &mut len,
+            )
+        };
+        assert_eq!(result, 0, "{}", io::Error::last_os_error());
+        linger
+    }
+
+    #[test]
+    fn linger_time_is_set_on_the_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        assert_eq!(linger_of(&stream).onoff, 0);
+
+        set(Duration::from_secs(3));
+        apply(&stream).unwrap();
+        let linger = linger_of(&stream);
+        assert_eq!(linger.onoff, 1);
+        assert_eq!(linger.linger, 3);
+    }
+}
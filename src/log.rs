@@ -0,0 +1,394 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The file log lines are appended to, and the path it was opened from, if not stdout
+static LOG_FILE: Mutex<Option<(String, File)>> = Mutex::new(None);
+
+/// Set to have the log file reopened before the next line is written, so a file moved away by log
+/// rotation is replaced by a fresh one at the configured path
+pub static REOPEN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+fn open_append(path: &str) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Sends log lines to the file at `path` instead of stdout
+pub fn log_to_file(path: &str) -> io::Result<()> {
+    let file = open_append(path)?;
+    *LOG_FILE.lock().unwrap() = Some((path.to_owned(), file));
+    Ok(())
+}
+
+/// Writes a line to the log, reopening the log file first if that has been requested
+pub fn write_line(line: &str) {
+    let mut log_file = LOG_FILE.lock().unwrap();
+    let Some((path, file)) = log_file.as_mut() else {
+        println!("{line}");
+        return;
+    };
+
+    if REOPEN_REQUESTED.swap(false, Ordering::SeqCst) {
+        match open_append(path) {
+            Ok(reopened) => *file = reopened,
+            // logging carries on to the file already open, so the failure is noted there
+            Err(e) => {
+                let _ = writeln!(file, "Could not reopen log file {path}: {e}");
+            }
+        }
+    }
+    if let Err(e) = writeln!(file, "{line}") {
+        // the log itself can't be written, so stderr is all that's left to say so on
+        eprintln!("Could not write to log file {path}: {e}");
+    }
+}
+
+/// What the proxy logs as it handles connections
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// a line describing each step of handling a connection
+    Default,
+    /// one Apache Combined Log Format line per request
+    Combined,
+}
+
+impl LogFormat {
+    pub fn parse(format: &str) -> Result<LogFormat, String> {
+        match format {
+            "default" => Ok(LogFormat::Default),
+            "combined" => Ok(LogFormat::Combined),
+            _ => Err(format!("Unknown log format: {format}")),
+        }
+    }
+}
+
+/// How a client connection ended, as logged when connections are
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// its requests were answered, whatever the servers' responses were
+    Served,
+    /// closed unread because its source address is not allowed
+    RejectedSource,
+    /// turned away at a limit on connections, requests, or resources
+    RejectedLimit,
+    /// a request was refused by the proxy's access controls
+    DeniedAcl,
+    /// the client sent something the proxy can't handle as an http request
+    ProtocolError,
+    /// the client closed or went idle before sending a request
+    ClientGone,
+    /// handling a request failed partway, such as when a server couldn't be reached
+    Error,
+}
+
+impl Outcome {
+    /// The outcome of a connection whose last request was answered by the proxy with `status`
+    /// before it was closed
+    pub fn of_status(status: Option<u16>) -> Outcome {
+        match status {
+            Some(403) => Outcome::DeniedAcl,
+            Some(400 | 405 | 413 | 414 | 431) => Outcome::ProtocolError,
+            Some(503) => Outcome::RejectedLimit,
+            _ => Outcome::Error,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Outcome::Served => "served",
+            Outcome::RejectedSource => "rejected-source",
+            Outcome::RejectedLimit => "rejected-limit",
+            Outcome::DeniedAcl => "denied-acl",
+            Outcome::ProtocolError => "protocol-error",
+            Outcome::ClientGone => "client-gone",
+            Outcome::Error => "error",
+        }
+    }
+}
+
+/// What closed a client connection, more precisely than its outcome, for aggregating failures
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// the client closed its side, or its connection was reset
+    ClientEof,
+    /// the server closed its side, or the response ended the connection
+    ServerEof,
+    /// no data moved for longer than the idle timeout
+    IdleTimeout,
+    /// the request or tunnel ran past its maximum duration
+    TotalTimeout,
+    /// the server took longer than the response timeout to start responding
+    ResponseTimeout,
+    /// turned away at a limit on connections, requests, or their rate
+    RateLimit,
+    /// refused by the allowed clients, egress networks, or another access control
+    AclDeny,
+    /// the server or upstream proxy couldn't be connected to
+    UpstreamConnectFail,
+    /// the server's host name couldn't be resolved
+    DnsFail,
+    /// the server's host name resolved, but to no address the proxy may connect to
+    NoUsableAddress,
+    /// the client sent something the proxy can't handle as an http request
+    ProtocolError,
+    /// closed from the stats server
+    AdminClosed,
+    /// the proxy was shutting down and took no more requests
+    Shutdown,
+    /// anything else that failed partway
+    Error,
+}
+
+impl Reason {
+    /// The reason a connection was closed after the proxy answered its last request with
+    /// `status`, when nothing more precise was noted
+    pub fn of_status(status: Option<u16>) -> Reason {
+        match status {
+            Some(403) => Reason::AclDeny,
+            Some(400 | 405 | 413 | 414 | 431) => Reason::ProtocolError,
+            Some(502) => Reason::UpstreamConnectFail,
+            Some(503) => Reason::RateLimit,
+            Some(504) => Reason::ResponseTimeout,
+            _ => Reason::Error,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Reason::ClientEof => "client-eof",
+            Reason::ServerEof => "server-eof",
+            Reason::IdleTimeout => "idle-timeout",
+            Reason::TotalTimeout => "total-timeout",
+            Reason::ResponseTimeout => "response-timeout",
+            Reason::RateLimit => "rate-limit",
+            Reason::AclDeny => "acl-deny",
+            Reason::UpstreamConnectFail => "upstream-connect-fail",
+            Reason::DnsFail => "dns-fail",
+            Reason::NoUsableAddress => "no-usable-address",
+            Reason::ProtocolError => "protocol-error",
+            Reason::AdminClosed => "admin-closed",
+            Reason::Shutdown => "shutdown",
+            Reason::Error => "error",
+        }
+    }
+}
+
+/// Formats the line logged once a client connection has ended, e.g.
+/// `connection client=127.0.0.1:50000 outcome=served reason=client-eof age=0.3`, with its age in
+/// seconds
+pub fn connection_line(
+    client: SocketAddr,
+    outcome: Outcome,
+    reason: Reason,
+    age: Duration,
+) -> String {
+    format!(
+        "connection client={client} outcome={} reason={} age={:.1}",
+        outcome.name(),
+        reason.name(),
+        age.as_secs_f64()
+    )
+}
+
+/// The details of a proxied request recorded in the access log
+pub struct AccessEntry<'a> {
+    pub client: IpAddr,
+    pub time: SystemTime,
+    pub request_line: &'a str,
+    /// status of the response, if there was one to a request that is not a tunnel
+    pub status: Option<u16>,
+    /// bytes of response body sent to the client
+    pub bytes: u64,
+    pub referer: Option<&'a [u8]>,
+    pub user_agent: Option<&'a [u8]>,
+    /// address of the server or upstream proxy connected to, if one was
+    pub server_addr: Option<SocketAddr>,
+    /// name of the route the request matched, if any
+    pub route: Option<&'a str>,
+}
+
+impl AccessEntry<'_> {
+    /// Formats the entry as a Combined Log Format line:
+    /// `client - - [time] "request line" status bytes "referer" "user agent"`, followed by
+    /// `"server address"` if `with_server_addr` is set and `"route"` if `with_route` is
+    pub fn combined(&self, with_server_addr: bool, with_route: bool) -> String {
+        let status = self
+            .status
+            .map_or("-".to_owned(), |status| status.to_string());
+        let bytes = match self.bytes {
+            0 => "-".to_owned(),
+            bytes => bytes.to_string(),
+        };
+        let mut line = format!(
+            "{} - - [{}] \"{}\" {status} {bytes} \"{}\" \"{}\"",
+            self.client,
+            clf_time(self.time),
+            escape(self.request_line.as_bytes()),
+            self.referer.map_or("-".to_owned(), escape),
+            self.user_agent.map_or("-".to_owned(), escape),
+        );
+        if with_server_addr {
+            let server_addr = self
+                .server_addr
+                .map_or("-".to_owned(), |addr| addr.to_string());
+            line.push_str(&format!(" \"{server_addr}\""));
+        }
+        if with_route {
+            let route = self
+                .route
+                .map_or("-".to_owned(), |route| escape(route.as_bytes()));
+            line.push_str(&format!(" \"{route}\""));
+        }
+        line
+    }
+}
+
+/// Makes a value safe to place between quotes in a log line
+pub fn escape(value: &[u8]) -> String {
+    let mut escaped = String::new();
+    for &byte in value {
+        match byte {
+            b'"' => escaped.push_str("\\\""),
+            b'\\' => escaped.push_str("\\\\"),
+            b' '..=b'~' => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("\\x{byte:02x}")),
+        }
+    }
+    escaped
+}
+
+/// Formats `time` in UTC as the Common Log Format timestamp, e.g. `10/Oct/2000:13:55:36 +0000`
+fn clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+    let seconds_of_day = seconds % 86400;
+    format!(
+        "{day:02}/{}/{year}:{:02}:{:02}:{:02} +0000",
+        MONTHS[month as usize - 1],
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60
+    )
+}
+
+/// Converts a number of days since 1970-01-01 into a (year, month, day) date, using Howard
+/// Hinnant's `civil_from_days` algorithm
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn combined_line_has_the_combined_log_format_layout() {
+        let entry = AccessEntry {
+            client: "192.0.2.7".parse().unwrap(),
+            time: at(1709251199),
+            request_line: "GET http://example.com/a?b=\"c\" HTTP/1.1",
+            status: Some(200),
+            bytes: 1234,
+            referer: Some(b"http://example.com/\"quoted\""),
+            user_agent: Some(b"curl/8.0\r\nforged\x7f\\"),
+            server_addr: Some("198.51.100.1:80".parse().unwrap()),
+            route: Some("api"),
+        };
+        assert_eq!(
+            entry.combined(false, false),
+            "192.0.2.7 - - [29/Feb/2024:23:59:59 +0000] \"GET http://example.com/a?b=\\\"c\\\" \
+             HTTP/1.1\" 200 1234 \"http://example.com/\\\"quoted\\\"\" \
+             \"curl/8.0\\x0d\\x0aforged\\x7f\\\\\""
+        );
+        assert!(entry
+            .combined(true, true)
+            .ends_with("\" \"198.51.100.1:80\" \"api\""));
+    }
+
+    #[test]
+    fn combined_line_for_a_tunnel_has_no_status() {
+        let entry = AccessEntry {
+            client: "2001:db8::1".parse().unwrap(),
+            time: at(0),
+            request_line: "CONNECT example.com:443 HTTP/1.1",
+            status: None,
+            bytes: 0,
+            referer: None,
+            user_agent: None,
+            server_addr: None,
+            route: None,
+        };
+        assert_eq!(
+            entry.combined(true, true),
+            "2001:db8::1 - - [01/Jan/1970:00:00:00 +0000] \"CONNECT example.com:443 HTTP/1.1\" - - \
+             \"-\" \"-\" \"-\" \"-\""
+        );
+    }
+
+    #[test]
+    fn timestamps_fall_on_the_right_day_around_leap_days() {
+        assert_eq!(clf_time(at(1709251200)), "01/Mar/2024:00:00:00 +0000");
+        assert_eq!(clf_time(at(951825600)), "29/Feb/2000:12:00:00 +0000");
+        // 2100 is not a leap year, so February ends on the 28th
+        assert_eq!(clf_time(at(4107542400)), "01/Mar/2100:00:00:00 +0000");
+        assert_eq!(civil_from_days(4107542400 / 86400 - 1), (2100, 2, 28));
+        assert_eq!(clf_time(at(1704067199)), "31/Dec/2023:23:59:59 +0000");
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+    }
+
+    #[test]
+    fn log_file_is_reopened_at_its_path_after_rotation() {
+        let dir = std::env::temp_dir().join(format!("proxy_server-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("proxy.log");
+        let rotated = dir.join("proxy.log.1");
+
+        log_to_file(path.to_str().unwrap()).unwrap();
+        write_line("before");
+        fs::rename(&path, &rotated).unwrap();
+        write_line("still to the rotated file");
+        REOPEN_REQUESTED.store(true, Ordering::SeqCst);
+        write_line("after");
+        *LOG_FILE.lock().unwrap() = None;
+
+        assert_eq!(
+            fs::read_to_string(&rotated).unwrap(),
+            "before\nstill to the rotated file\n"
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap(), "after\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
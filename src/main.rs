@@ -1,15 +1,194 @@
-use httparse;
+macro_rules! err_to_str {
+    ($fallible:expr) => {
+        $fallible.map_err(|err| err.to_string())
+    };
+}
+
+mod admin;
+mod balance;
+mod body;
+mod buffer;
+mod bypass;
+mod cidr;
+mod clock;
+mod config;
+mod ftp;
+mod http;
+mod limit;
+mod linger;
+mod log;
+mod pool;
+mod resolve;
+mod rlimit;
+mod route;
+mod source;
+mod stats;
+#[cfg(unix)]
+mod sys;
+mod tee;
+mod tls;
+mod upstream;
+
+use body::BodyLength;
+use config::{Command, Config, OverflowPolicy, Timeouts};
+use http::{send_error, send_response, ErrorFormat, Protocol, Request, Response};
+use limit::Semaphore;
+use log::{AccessEntry, LogFormat, Outcome, Reason};
+use route::Mode;
 use std::{
+    collections::HashSet,
     io::{self, Read, Write},
-    net::{TcpListener, TcpStream},
+    net::{Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     thread,
+    time::{Duration, Instant, SystemTime},
 };
-use url;
+use tee::{Direction, Teed};
 
-macro_rules! err_to_str {
-    ($fallible:expr) => {
-        $fallible.map_err(|err| err.to_string())
+/// How long the accept loop waits before polling the listener again when no connection is pending
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How often idle pooled server connections are checked for having outstayed the pool's timeout
+const POOL_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the accept loop checks the proxy's memory use when it is bounded
+const MEMORY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a tunnel waits on the client for the rest of its first TLS record when ClientHellos
+/// are logged, which also holds up protocols where the server speaks first
+const CLIENT_HELLO_WAIT: Duration = Duration::from_secs(2);
+
+/// Longest a request waits for a connection to its host to finish when the host is at its limit
+const HOST_LIMIT_WAIT: Duration = Duration::from_secs(5);
+
+/// How long the accept loop may spend telling a connection over the limit that it was rejected
+const OVERFLOW_WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Delay after the first failed accept, doubled on each consecutive failure up to the maximum
+const ACCEPT_BACKOFF_INITIAL: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+/// Backoff bounds used when accepts fail because the process or system is out of resources, which
+/// only clears once existing connections close
+const ACCEPT_EXHAUSTED_BACKOFF_INITIAL: Duration = Duration::from_millis(100);
+const ACCEPT_EXHAUSTED_BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+/// Delay after the first pass of the relay loop that moves no data, doubled on each consecutive
+/// idle pass up to the maximum, which bounds the latency added once data arrives
+const RELAY_BACKOFF_INITIAL: Duration = Duration::from_micros(50);
+const RELAY_BACKOFF_MAX: Duration = Duration::from_millis(10);
+
+/// How long a relayed connection may move no data before its buffers go back to the pool
+const BUFFER_RELEASE_IDLE: Duration = Duration::from_secs(5);
+
+/// Set once the proxy has been asked to stop accepting connections
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+/// Connects to the first address `addr` resolves to that accepts within `timeout`, from a port in
+/// the source port range if one is set
+fn connect(addr: impl ToSocketAddrs, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for addr in addr.to_socket_addrs()? {
+        let connected = source::connect(addr, timeout).unwrap_or_else(|| match timeout {
+            Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+            None => TcpStream::connect(addr),
+        });
+        match connected.and_then(|stream| linger::apply(&stream).map(|()| stream)) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "Address resolved to nothing")
+    }))
+}
+
+#[cfg(unix)]
+extern "C" fn request_shutdown(_signum: std::os::raw::c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+#[cfg(unix)]
+extern "C" fn request_log_reopen(_signum: std::os::raw::c_int) {
+    log::REOPEN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Makes `SIGINT` and `SIGTERM` stop the accept loop instead of killing the process outright, and
+/// `SIGHUP` reopen the log file
+fn install_signal_handlers() {
+    #[cfg(unix)]
+    // SAFETY: the handlers only store to atomics, which is async-signal-safe
+    unsafe {
+        sys::signal(sys::SIGINT, request_shutdown);
+        sys::signal(sys::SIGTERM, request_shutdown);
+        sys::signal(sys::SIGHUP, request_log_reopen);
+    }
+}
+
+/// Binds a listener to `addr` that queues up to `backlog` pending connections, trying each
+/// address it resolves to in turn.
+///
+/// The standard library binds and listens in one call with a backlog of its own, so on Linux this
+/// makes the calls itself: `SO_REUSEADDR` is set before binding, so quick restarts can rebind while
+/// old connections sit in `TIME_WAIT`, and the socket listens with `backlog` from the start.
+/// Elsewhere the standard library's backlog is used, and `--backlog` is refused at startup.
+fn bind_listener(addr: &str, backlog: i32) -> io::Result<TcpListener> {
+    #[cfg(not(target_os = "linux"))]
+    let _ = backlog;
+    let mut last_error = None;
+    for addr in addr.to_socket_addrs()? {
+        #[cfg(target_os = "linux")]
+        let bound = listen_on(addr, backlog);
+        #[cfg(not(target_os = "linux"))]
+        let bound = TcpListener::bind(addr);
+        match bound {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "Address resolved to nothing")
+    }))
+}
+
+/// Opens a socket listening on `addr` with `backlog`, reusing the address
+#[cfg(target_os = "linux")]
+fn listen_on(addr: SocketAddr, backlog: i32) -> io::Result<TcpListener> {
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    let domain = match addr {
+        SocketAddr::V4(_) => sys::AF_INET,
+        SocketAddr::V6(_) => sys::AF_INET6,
     };
+    // SAFETY: `socket` takes no pointers
+    let fd = unsafe { sys::socket(domain, sys::SOCK_STREAM | sys::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` is a newly opened socket owned by nothing else, which the listener now closes
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    let fd = listener.as_raw_fd();
+
+    sys::set_int_option(fd, sys::SOL_SOCKET, sys::SO_REUSEADDR, 1)?;
+    // SAFETY: the address points to a sockaddr of the given length that outlives the call
+    sys::with_sockaddr(addr, |sockaddr, len| unsafe {
+        sys::bind(fd, sockaddr, len)
+    })?;
+    // SAFETY: `listen` takes no pointers
+    if unsafe { sys::listen(fd, backlog) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(listener)
+}
+
+/// Progress made by a single call to `pipe`
+struct Piped {
+    /// number of bytes read from `in_stream` into the buffer during the call
+    read: usize,
+    /// number of bytes in the buffer that must still be written to `out_stream`
+    pending: usize,
 }
 
 enum PipeError {
@@ -22,16 +201,18 @@ fn pipe(
     out_stream: &mut TcpStream,
     buffer: &mut [u8],
     mut bytes_to_pass: usize,
-) -> Result<usize, PipeError> {
+) -> Result<Piped, PipeError> {
+    let mut read = 0;
     if bytes_to_pass == 0 {
-        bytes_to_pass = match in_stream.read(buffer) {
+        read = match in_stream.read(buffer) {
             Ok(0) => return Err(PipeError::SocketClosed), // socket has been closed
             Ok(bytes) => bytes, // number of bytes successfully read into the buffer
             Err(e) => match e.kind() {
                 io::ErrorKind::WouldBlock => 0, // no data ready to be read
                 _ => return Err(PipeError::Unknown(format!("{e}"))),
             },
-        }
+        };
+        bytes_to_pass = read;
     }
 
     if bytes_to_pass > 0 {
@@ -49,99 +230,1925 @@ fn pipe(
             },
         }
     }
-    // returns number of bytes in `buffer` that must still be written to `out_stream`
-    Ok(bytes_to_pass)
+    Ok(Piped {
+        read,
+        pending: bytes_to_pass,
+    })
+}
+
+/// Whether a request for `path` received on `local_addr` is addressed to the proxy itself rather
+/// than to be forwarded, either by using a non-absolute target or by naming the proxy's address
+fn targets_proxy(path: &str, headers: &[http::Header], local_addr: SocketAddr) -> bool {
+    // a request that may not be forwarded any further must be answered here
+    if http::header_value(headers, "Max-Forwards").is_some_and(|value| value.trim_ascii() == b"0") {
+        return true;
+    }
+
+    let Ok(url) = url::Url::parse(path) else {
+        return path.starts_with('/') || path == "*";
+    };
+    let host_matches = match url.host() {
+        Some(url::Host::Ipv4(ip)) => local_addr.ip() == ip,
+        Some(url::Host::Ipv6(ip)) => local_addr.ip() == ip,
+        Some(url::Host::Domain(domain)) => domain.eq_ignore_ascii_case("localhost"),
+        None => false,
+    };
+    host_matches && url.port_or_known_default() == Some(local_addr.port())
+}
+
+/// The page shown to someone visiting the proxy at `local_addr` as if it were a website, explaining
+/// how to use it as a proxy instead
+fn info_page(local_addr: SocketAddr, serve_pac: bool) -> String {
+    let pac = if serve_pac {
+        format!(
+            "<p>Browsers can also be configured automatically with the PAC file at \
+             <a href=\"/proxy.pac\">http://{local_addr}/proxy.pac</a>.</p>\n"
+        )
+    } else {
+        String::new()
+    };
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>{name}</title></head>\n<body>\n\
+         <h1>This is an HTTP proxy</h1>\n\
+         <p>It is not a website. To browse through it, set <code>{local_addr}</code> as the HTTP \
+         and HTTPS proxy in your browser or system network settings, or pass it to tools with \
+         e.g. <code>curl -x {local_addr} http://example.com/</code>.</p>\n\
+         {pac}</body>\n</html>\n",
+        name = env!("CARGO_PKG_NAME"),
+    )
+}
+
+/// The path, without any query, of a request target addressed to the proxy
+fn proxy_path(target: &str) -> &str {
+    let path = match target.find("://") {
+        // the path of an absolute-form target starts after its authority
+        Some(scheme_end) => target[scheme_end + 3..]
+            .find('/')
+            .map_or("/", |start| &target[scheme_end + 3 + start..]),
+        None => target,
+    };
+    path.split(['?', '#']).next().unwrap_or_default()
+}
+
+/// Whether `target` is in the `host:port` authority form required of a `CONNECT` request target,
+/// without a scheme, path, or user info
+fn is_authority_form(target: &str) -> bool {
+    let Some((host, port)) = target.rsplit_once(':') else {
+        return false;
+    };
+
+    let host_valid = match host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+    {
+        Some(ip) => ip.parse::<Ipv6Addr>().is_ok(),
+        None => {
+            !host.is_empty()
+                && host
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_'))
+        }
+    };
+    host_valid && port.parse::<u16>().is_ok()
+}
+
+/// Whether an io error is a read or write timing out
+fn is_timeout(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// A reader that fails with a timeout once a deadline has passed, however steadily data arrives
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    deadline: Option<Instant>,
+    /// longest a single read may wait for data
+    idle: Option<Duration>,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Request took longer than its maximum duration",
+                ));
+            }
+            self.stream.set_read_timeout(Some(
+                self.idle.map_or(remaining, |idle| idle.min(remaining)),
+            ))?;
+        }
+        (&mut &*self.stream).read(buf)
+    }
+}
+
+/// A client connection and what has been read from it but not yet handled
+struct Client {
+    stream: TcpStream,
+    /// bytes received past the end of the request being handled, which are the start of the next
+    /// request when the client pipelines them
+    buffered: Vec<u8>,
+    /// the `host:port` the client's last request was sent to and the addresses it resolved to,
+    /// reused while the client keeps sending requests to the same server
+    resolved: Option<(String, Vec<SocketAddr>)>,
+    /// the different hosts the client has sent requests for, tracked when their number is limited
+    hosts: HashSet<String>,
+}
+
+/// What the proxy sent back to the client in answer to a request
+#[derive(Default)]
+struct Answer {
+    /// status of the response, if there was one to a request that is not a tunnel
+    status: Option<u16>,
+    /// bytes of response body sent to the client
+    bytes: u64,
+    /// address of the server or upstream proxy the request went to
+    server_addr: Option<SocketAddr>,
+    /// name of the route the request matched, if any
+    route: Option<String>,
+    /// how the client connection ends if handling the request fails, when the response status
+    /// doesn't tell
+    outcome: Option<Outcome>,
+    /// what closed the client connection, when the response status doesn't tell
+    reason: Option<Reason>,
+}
+
+/// Handles the requests on a client connection until it closes, setting `outcome` to how it ended
+/// and `reason` to what closed it
+fn handle_connection(
+    client_stream: TcpStream,
+    config: &Config,
+    outcome: &mut Outcome,
+    reason: &mut Reason,
+) -> Result<(), String> {
+    let client_addr = err_to_str!(client_stream.peer_addr())?;
+    let mut client = Client {
+        stream: client_stream,
+        buffered: Vec::new(),
+        resolved: None,
+        hosts: HashSet::new(),
+    };
+
+    err_to_str!(client.stream.set_read_timeout(config.timeouts.idle))?;
+    *outcome = Outcome::ProtocolError;
+    *reason = Reason::ProtocolError;
+    match http::sniff(&mut client.stream, &mut client.buffered) {
+        Ok(Some(Protocol::Http)) => {}
+        Ok(Some(Protocol::Tls)) => {
+            return Err(format!(
+                "Closed connection from {client_addr} that started a TLS handshake; https must be \
+                 proxied with CONNECT"
+            ))
+        }
+        Ok(Some(Protocol::Unknown)) => {
+            return Err(format!(
+                "Dropped connection from {client_addr} speaking an unknown protocol"
+            ))
+        }
+        Ok(None) => {
+            *outcome = Outcome::ClientGone;
+            *reason = Reason::ClientEof;
+            return Ok(());
+        }
+        Err(e) if is_timeout(&e) => {
+            *outcome = Outcome::ClientGone;
+            *reason = Reason::IdleTimeout;
+            return Err("Connection closed after being idle".to_owned());
+        }
+        Err(e) => {
+            *outcome = Outcome::ClientGone;
+            *reason = Reason::ClientEof;
+            return Err(e.to_string());
+        }
+    }
+
+    let mut first_request = true;
+    loop {
+        // until a request has been answered, a client that stops now left without one
+        *outcome = if first_request {
+            Outcome::ClientGone
+        } else {
+            Outcome::Served
+        };
+        // connections are left to finish while the proxy drains, but take no more requests
+        if !first_request && SHUTDOWN.load(Ordering::SeqCst) {
+            *reason = Reason::Shutdown;
+            return Ok(());
+        }
+        err_to_str!(client.stream.set_read_timeout(config.timeouts.idle))?;
+        *reason = Reason::ClientEof;
+        let head = match http::read_head(&mut client.stream, &mut client.buffered) {
+            Ok(Some(head)) => head,
+            // the client is done sending requests
+            Ok(None) => return Ok(()),
+            Err(e) if is_timeout(&e) => {
+                *reason = Reason::IdleTimeout;
+                if !first_request {
+                    return Ok(());
+                }
+                return Err("Connection closed after being idle".to_owned());
+            }
+            Err(e) => {
+                if e.kind() == io::ErrorKind::InvalidData {
+                    *reason = Reason::ProtocolError;
+                }
+                return Err(e.to_string());
+            }
+        };
+        *outcome = Outcome::ProtocolError;
+        *reason = Reason::ProtocolError;
+        let request = match Request::parse(head) {
+            Ok(request) => request,
+            Err(e) => {
+                err_to_str!(send_error(
+                    &mut client.stream,
+                    "400 Bad Request",
+                    &e,
+                    config.error_format,
+                ))?;
+                return Err(e);
+            }
+        };
+
+        let oversized = config.max_header_value_size.and_then(|max| {
+            request
+                .headers
+                .iter()
+                .find(|header| header.value.len() > max)
+        });
+        if let Some(header) = oversized {
+            let message = format!("Header {} is too large", header.name);
+            err_to_str!(send_error(
+                &mut client.stream,
+                "431 Request Header Fields Too Large",
+                &message,
+                request.error_format(config.error_format),
+            ))?;
+            return Err(message);
+        }
+
+        let mut answer = Answer::default();
+        let result = handle_request(&mut client, &request, config, &mut answer);
+
+        if config.log_format == LogFormat::Combined {
+            let entry = AccessEntry {
+                client: client_addr.ip(),
+                time: SystemTime::now(),
+                request_line: &request.line(),
+                status: answer.status,
+                bytes: answer.bytes,
+                referer: request.header("Referer"),
+                user_agent: request.header("User-Agent"),
+                server_addr: answer.server_addr,
+                route: answer.route.as_deref(),
+            };
+            log::write_line(&entry.combined(config.log_server_addr, config.log_route));
+        }
+
+        *outcome = match &result {
+            Ok(_) => Outcome::Served,
+            Err(_) => answer.outcome.unwrap_or(Outcome::of_status(answer.status)),
+        };
+        *reason = match &result {
+            // a connection not kept for another request was ended by its response
+            Ok(_) => answer.reason.unwrap_or(Reason::ServerEof),
+            Err(_) => answer.reason.unwrap_or(Reason::of_status(answer.status)),
+        };
+        if !result? {
+            return Ok(());
+        }
+        first_request = false;
+    }
 }
 
-fn handle_connection(mut client_stream: TcpStream) -> Result<(), String> {
-    let mut initial_request_buffer = [0; 1024];
-    err_to_str!(client_stream.read(&mut initial_request_buffer))?;
+/// Handles one request read from the client, returning whether the client connection may carry
+/// another request afterwards
+fn handle_request(
+    client: &mut Client,
+    request: &Request,
+    config: &Config,
+    answer: &mut Answer,
+) -> Result<bool, String> {
+    let path = request.target.as_str();
+    let local_addr = err_to_str!(client.stream.local_addr())?;
+    let tunneling = request.method == "CONNECT";
+
+    // checked before the requests the proxy answers itself, so strict mode covers those too
+    let url = url::Url::parse(path);
+    let names_host = tunneling || url.as_ref().is_ok_and(|url| url.host_str().is_some());
+    if !names_host && request.header("Host").is_none() && config.reject_on_missing_host {
+        reject(
+            client,
+            request,
+            config,
+            answer,
+            "400 Bad Request",
+            "Request does not say which host it is for",
+        )?;
+        return Err(format!("Rejected request without a target host: {path}"));
+    }
+
+    if config.serve_pac
+        && request.method == "GET"
+        && targets_proxy(path, &request.headers, local_addr)
+        && proxy_path(path) == "/proxy.pac"
+    {
+        answer.status = Some(200);
+        // the address the client reached the proxy on is one it can reach the proxy at
+        let pac = config.no_proxy.pac_file(local_addr);
+        answer.bytes = pac.len() as u64;
+        err_to_str!(send_response(
+            &mut client.stream,
+            "200 OK",
+            "application/x-ns-proxy-autoconfig",
+            pac.as_bytes(),
+        ))?;
+        return Ok(false);
+    }
+
+    // a browser pointed straight at the proxy sends an origin-form request, which would otherwise
+    // fail as a request with no server to forward to
+    if request.method == "GET" && path == "/" {
+        answer.status = Some(200);
+        let page = info_page(local_addr, config.serve_pac);
+        answer.bytes = page.len() as u64;
+        err_to_str!(send_response(
+            &mut client.stream,
+            "200 OK",
+            "text/html; charset=utf-8",
+            page.as_bytes(),
+        ))?;
+        return Ok(false);
+    }
+
+    if request.method == "TRACE" && targets_proxy(path, &request.headers, local_addr) {
+        return if config.enable_trace {
+            answer.status = Some(200);
+            // echo the request as it was received so the client can see what arrived
+            err_to_str!(send_response(
+                &mut client.stream,
+                "200 OK",
+                "message/http",
+                &request.traced(),
+            ))?;
+            Ok(false)
+        } else {
+            reject(
+                client,
+                request,
+                config,
+                answer,
+                "405 Method Not Allowed",
+                "TRACE is disabled on this proxy",
+            )?;
+            Err("Rejected TRACE request to the proxy".to_owned())
+        };
+    }
+
+    if tunneling && !is_authority_form(path) {
+        reject(
+            client,
+            request,
+            config,
+            answer,
+            "400 Bad Request",
+            "CONNECT target must be of the form host:port",
+        )?;
+        return Err(format!("Rejected malformed CONNECT target: {path}"));
+    }
+
+    let url = if tunneling {
+        None
+    } else {
+        let user_agent = request.header("User-Agent").unwrap_or_default();
+        if config.require_user_agent && user_agent.trim_ascii().is_empty() {
+            reject(
+                client,
+                request,
+                config,
+                answer,
+                "403 Forbidden",
+                "Requests through this proxy must have a User-Agent header",
+            )?;
+            return Err(format!("Rejected request without a User-Agent: {path}"));
+        }
+        // an origin-form path isn't forwarded to the Host it names, which may be the proxy itself
+        let Ok(url) = url else {
+            reject(
+                client,
+                request,
+                config,
+                answer,
+                "400 Bad Request",
+                "Requests through this proxy must name an absolute URL",
+            )?;
+            return Err(format!("Rejected request without an absolute URL: {path}"));
+        };
+        Some(url)
+    };
+    let host = match &url {
+        Some(url) => url.host_str().unwrap_or_default(),
+        None => path.rsplit_once(':').map_or(path, |(host, _)| host),
+    };
+    // the proxy can't speak TLS to the server, and relaying cleartext to an https port fails
+    // confusingly, so without an upstream proxy to take them these must be tunneled instead
+    if url.as_ref().is_some_and(|url| url.scheme() == "https")
+        && config.upstream_for(host).is_none()
+    {
+        reject(
+            client,
+            request,
+            config,
+            answer,
+            "400 Bad Request",
+            "https URLs must be requested through a CONNECT tunnel",
+        )?;
+        return Err(format!("Rejected plain request for an https URL: {path}"));
+    }
+    let route = route::find(&config.routes, host);
+    answer.route = route.map(|route| route.name.clone());
+    if let (Some(route), true, LogFormat::Default) = (route, config.log_route, config.log_format) {
+        log::write_line(&format!("Matched route {}", route.name));
+    }
+    stats::record_request(&request.method, host, route.map(|route| &*route.name));
+
+    if let Some(max) = config.max_hosts_per_connection {
+        client.hosts.insert(host.to_ascii_lowercase());
+        if client.hosts.len() > max {
+            answer.outcome = Some(Outcome::RejectedLimit);
+            answer.reason = Some(Reason::RateLimit);
+            return Err(format!(
+                "Closed connection from {} after requests for more than {max} hosts",
+                err_to_str!(client.stream.peer_addr())?
+            ));
+        }
+    }
+    let port = match &url {
+        Some(url) => url.port_or_known_default().unwrap_or(80),
+        // the target was checked to end in a valid port
+        None => path
+            .rsplit_once(':')
+            .map_or(0, |(_, port)| port.parse().unwrap_or(0)),
+    };
+    let timeouts = route.map_or(config.timeouts, |route| route.timeouts.or(config.timeouts));
+
+    // the addresses checked are kept for connecting, so a second lookup can't return others
+    if !config.egress_networks.is_empty() && config.upstream_for(host).is_none() {
+        let setup = config.setup_limit.as_ref().map(Semaphore::acquire);
+        let addrs = server_addrs(host, port, config, &client.resolved);
+        drop(setup);
+        if let Ok(addrs) = addrs {
+            let allowed: Vec<_> = addrs
+                .into_iter()
+                .filter(|addr| {
+                    config
+                        .egress_networks
+                        .iter()
+                        .any(|network| network.contains(addr.ip()))
+                })
+                .collect();
+            if allowed.is_empty() {
+                reject(
+                    client,
+                    request,
+                    config,
+                    answer,
+                    "403 Forbidden",
+                    "The proxy may not connect to this server",
+                )?;
+                return Err(format!(
+                    "Rejected request for {host}, which is outside the egress networks"
+                ));
+            }
+            client.resolved = Some((format!("{host}:{port}"), allowed));
+        }
+    }
+
+    let (budget, kind) = if tunneling {
+        (&config.tunnel_budget, "tunnels")
+    } else {
+        (&config.forward_budget, "forwards")
+    };
+    let _budget_permit = match budget {
+        Some(budget) => {
+            let permit = budget.try_acquire();
+            if permit.is_none() {
+                reject(
+                    client,
+                    request,
+                    config,
+                    answer,
+                    "503 Service Unavailable",
+                    "The proxy is handling too many requests of this kind",
+                )?;
+                return Err(format!(
+                    "Rejected request for {host} at the limit of {} {kind}",
+                    budget.max
+                ));
+            }
+            permit
+        }
+        None => None,
+    };
+
+    let _host_permit = match &config.host_limits {
+        Some(limits) => {
+            let wait = match config.per_host_overflow {
+                OverflowPolicy::Block => HOST_LIMIT_WAIT,
+                OverflowPolicy::Reject => Duration::ZERO,
+            };
+            let permit = limits.acquire(host, wait);
+            if permit.is_none() {
+                reject(
+                    client,
+                    request,
+                    config,
+                    answer,
+                    "503 Service Unavailable",
+                    "The proxy is handling too many connections to this host",
+                )?;
+                return Err(format!(
+                    "Rejected request at the connection limit for {host}"
+                ));
+            }
+            permit
+        }
+        None => None,
+    };
 
-    let mut headers = [httparse::EMPTY_HEADER; 16];
-    let mut request = httparse::Request::new(&mut headers);
-    let _result = err_to_str!(request.parse(&initial_request_buffer))?;
+    // with upstream proxies, ftp URLs are left to them like any other absolute-form request
+    let fetches_ftp = |url: &&url::Url| {
+        url.scheme() == "ftp" && config.enable_ftp && config.upstream_for(host).is_none()
+    };
+    if let Some(url) = url.as_ref().filter(fetches_ftp) {
+        return fetch_ftp(client, request, url, timeouts, config, answer);
+    }
 
-    let method = request.method.ok_or("Unable to get request method")?;
-    let path = request.path.ok_or("Unable to get request path")?;
+    let mut server_stream = if let Some(url) = &url {
+        let mode = route.map_or(config.default_mode, |route| route.mode);
 
-    let mut server_stream = if method == "CONNECT" {
-        println!("Connecting securely...");
-        let stream = err_to_str!(TcpStream::connect(path))?;
+        let body_length = match BodyLength::of_request(&request.headers) {
+            Ok(length) => length,
+            Err(e) => {
+                reject(client, request, config, answer, "400 Bad Request", &e)?;
+                return Err(e);
+            }
+        };
 
-        err_to_str!(client_stream.write(b"HTTP/1.1 200 OK\r\n\r\n"))?;
+        if mode == Mode::Forward {
+            return forward_request(client, request, body_length, url, timeouts, config, answer);
+        }
 
+        let stream = connected(
+            connect_for_request(url, timeouts, config, &mut client.resolved, answer)
+                .map(|(stream, _)| stream),
+            client,
+            request,
+            config,
+            answer,
+        )?;
+        let mut server_out = Teed::new(&stream, config.tee.as_ref(), Direction::Request);
+        // everything the client sends from here on goes to the server untouched
+        let sent = server_out
+            .write_all(&request.raw)
+            .and_then(|()| server_out.write_all(&client.buffered));
+        if let Err(e) = sent {
+            return not_sent(client, request, config, answer, e);
+        }
+        client.buffered.clear();
         stream
     } else {
-        println!("Connecting via http...");
-        let path = err_to_str!(url::Url::parse(path))?;
-        let addr = err_to_str!(path.socket_addrs(|| Some(80)))?;
-        let addr = addr
-            .get(0)
-            .ok_or("Unable to parse url into socket address")?;
-
-        let mut stream = err_to_str!(TcpStream::connect(addr))?;
-        err_to_str!(stream.write(&initial_request_buffer))?;
+        if config.log_format == LogFormat::Default {
+            log::write_line("Connecting securely...");
+        }
+        let setup = config.setup_limit.as_ref().map(Semaphore::acquire);
+        let stream = match config.upstream_for(host) {
+            Some(upstreams) => match upstreams.connect(&config.resolver, timeouts.connect) {
+                Ok(mut stream) => upstream::open_tunnel(&mut stream, path).map(|()| stream),
+                Err(e) => fall_back_direct(
+                    host,
+                    port,
+                    timeouts.connect,
+                    config,
+                    &client.resolved,
+                    answer,
+                    e,
+                ),
+            },
+            None => server_addrs(host, port, config, &client.resolved)
+                .map_err(|e| lookup_failed(e, answer))
+                .and_then(|addrs| err_to_str!(connect_server(&addrs, timeouts.connect, config))),
+        };
+        drop(setup);
+        let stream = connected(stream, client, request, config, answer)?;
+
+        // answered in the client's own version, since some HTTP/1.0 clients reject a newer one;
+        // either way the connection carries only the tunnel from here on
+        let established = format!("HTTP/1.{} 200 OK\r\n\r\n", request.version);
+        err_to_str!(client.stream.write_all(established.as_bytes()))?;
+
+        if config.log_tls_client_hello {
+            err_to_str!(read_first_record(client, timeouts.idle))?;
+            if let Some(hello) = tls::ClientHello::parse(&client.buffered) {
+                log::write_line(&format!("TLS ClientHello for {path}: {}", hello.summary()));
+            }
+        }
+
+        // a client may send the start of the tunneled data, such as a TLS ClientHello, along with
+        // the CONNECT request instead of waiting for the tunnel to open
+        err_to_str!(
+            Teed::new(&stream, config.tee.as_ref(), Direction::Request).write_all(&client.buffered)
+        )?;
+        client.buffered.clear();
+
         stream
     };
 
-    // client_stream.flush().unwrap(); not sure if this is needed
+    let mut relayed = Relayed::default();
+    let result = relay(
+        &mut client.stream,
+        &mut server_stream,
+        tunneling,
+        timeouts,
+        config,
+        request.error_format(config.error_format),
+        &mut relayed,
+    );
+    answer.status = relayed.status();
+    answer.bytes = relayed.body_bytes();
+    answer.reason = relayed.reason;
+
+    // the client connection has been handed over to the relay, which runs until it closes
+    result.map(|()| false)
+}
+
+/// Reads from the client into `client.buffered` until it holds the whole of the first TLS record
+/// sent through a tunnel, so it can be looked at before being passed on untouched. Stops at
+/// whatever has arrived if the client sends something other than TLS, closes, or goes quiet for
+/// `CLIENT_HELLO_WAIT`, then restores the `idle` timeout.
+fn read_first_record(client: &mut Client, idle: Option<Duration>) -> io::Result<()> {
+    client.stream.set_read_timeout(Some(CLIENT_HELLO_WAIT))?;
+    let mut chunk = [0; 4096];
+    while let Some(len) = tls::first_record_len(&client.buffered) {
+        if client.buffered.len() >= len {
+            break;
+        }
+        match client.stream.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(bytes) => client.buffered.extend_from_slice(&chunk[..bytes]),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                break
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    client.stream.set_read_timeout(idle)
+}
+
+/// Retrieves the file at an `ftp://` URL and sends it to the client as an http response, which
+/// ends the client connection since the file's length may not be known in advance
+fn fetch_ftp(
+    client: &mut Client,
+    request: &Request,
+    url: &url::Url,
+    timeouts: Timeouts,
+    config: &Config,
+    answer: &mut Answer,
+) -> Result<bool, String> {
+    // credentials in the URL are kept out of the log
+    let mut shown = url.clone();
+    let _ = shown.set_username("");
+    let _ = shown.set_password(None);
+
+    if request.method != "GET" && request.method != "HEAD" {
+        reject(
+            client,
+            request,
+            config,
+            answer,
+            "405 Method Not Allowed",
+            "Only GET and HEAD are supported for ftp URLs",
+        )?;
+        return Err(format!("Rejected {} request for {shown}", request.method));
+    }
+
+    let mut data = None;
+    let setup = config.setup_limit.as_ref().map(Semaphore::acquire);
+    let host = url.host_str().unwrap_or_default();
+    let addrs = server_addrs(
+        host,
+        url.port().unwrap_or(ftp::DEFAULT_PORT),
+        config,
+        &client.resolved,
+    );
+    let session = ftp::Session::open(url, addrs, timeouts).and_then(|mut session| {
+        if request.method == "GET" {
+            data = Some(session.retrieve()?);
+        }
+        Ok(session)
+    });
+    drop(setup);
+    let session = match session {
+        Ok(session) => session,
+        Err(e) => {
+            reject(client, request, config, answer, e.status, &e.message)?;
+            return Err(format!("Could not retrieve {shown}: {}", e.message));
+        }
+    };
+    answer.server_addr = session.peer_addr().ok();
+
+    let length = session
+        .size
+        .map(|size| format!("Content-Length: {size}\r\n"))
+        .unwrap_or_default();
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\n{length}Connection: close\r\n\r\n",
+        ftp::content_type(url.path())
+    );
+    answer.status = Some(200);
+    err_to_str!(client.stream.write_all(head.as_bytes()))?;
+
+    if let Some(mut data) = data {
+        answer.bytes = err_to_str!(io::copy(
+            &mut data,
+            &mut Teed::new(&client.stream, config.tee.as_ref(), Direction::Response),
+        ))?;
+        drop(data);
+        session
+            .finish()
+            .map_err(|e| format!("Could not retrieve {shown}: {}", e.message))?;
+    }
+    Ok(false)
+}
+
+/// Sends the client an error response in the format `request` asks for, recording its status
+fn reject(
+    client: &mut Client,
+    request: &Request,
+    config: &Config,
+    answer: &mut Answer,
+    status: &str,
+    message: &str,
+) -> Result<(), String> {
+    answer.status = status.split(' ').next().and_then(|code| code.parse().ok());
+    err_to_str!(send_error(
+        &mut client.stream,
+        status,
+        message,
+        request.error_format(config.error_format),
+    ))
+}
+
+/// Passes on a successfully opened server connection, or tells the client the server couldn't be
+/// reached
+fn connected(
+    stream: Result<TcpStream, String>,
+    client: &mut Client,
+    request: &Request,
+    config: &Config,
+    answer: &mut Answer,
+) -> Result<TcpStream, String> {
+    if let Ok(stream) = &stream {
+        record_server_addr(stream, config, answer);
+    }
+    stream.or_else(|e| {
+        let message = match answer.reason {
+            Some(Reason::DnsFail) => "Could not look up the server's address",
+            Some(Reason::NoUsableAddress) => {
+                "The server's host name resolved, but to no address the proxy may connect to"
+            }
+            _ => "Could not connect to the server",
+        };
+        reject(client, request, config, answer, "502 Bad Gateway", message)?;
+        Err(e)
+    })
+}
+
+/// Notes in `answer` why looking up the server's addresses failed with `error`, telling a host
+/// that doesn't exist from one with only addresses of a family the proxy doesn't connect to
+fn lookup_failed(error: io::Error, answer: &mut Answer) -> String {
+    answer.reason = Some(match error.kind() {
+        io::ErrorKind::AddrNotAvailable => Reason::NoUsableAddress,
+        _ => Reason::DnsFail,
+    });
+    error.to_string()
+}
+
+/// Tells the client that its request could not be sent to the server, which failed with `error`
+fn not_sent(
+    client: &mut Client,
+    request: &Request,
+    config: &Config,
+    answer: &mut Answer,
+    error: io::Error,
+) -> Result<bool, String> {
+    reject(
+        client,
+        request,
+        config,
+        answer,
+        "502 Bad Gateway",
+        "Could not send the request to the server",
+    )?;
+    Err(format!("Could not send the request to the server: {error}"))
+}
+
+/// Notes the address `stream` is connected to for the access log, logging it on its own line in
+/// the default format
+fn record_server_addr(stream: &TcpStream, config: &Config, answer: &mut Answer) {
+    stats::record_server(stream);
+    let addr = stream.peer_addr().ok();
+    if answer.server_addr != addr && config.log_server_addr {
+        if let (Some(addr), LogFormat::Default) = (addr, config.log_format) {
+            log::write_line(&format!("Connected to {addr}"));
+        }
+    }
+    answer.server_addr = addr;
+}
+
+/// Opens a connection to send a plain http request for `url` over, looking up the server's
+/// addresses unless they are the ones in `resolved` from the client's last request, and noting in
+/// `answer` if the lookup fails. Returns the connection along with whether it goes through an
+/// upstream proxy.
+fn connect_for_request(
+    url: &url::Url,
+    timeouts: Timeouts,
+    config: &Config,
+    resolved: &mut Option<(String, Vec<SocketAddr>)>,
+    answer: &mut Answer,
+) -> Result<(TcpStream, bool), String> {
+    let _setup = config.setup_limit.as_ref().map(Semaphore::acquire);
+    // an upstream proxy takes the same absolute-form request the client sent
+    let host = url.host_str().unwrap_or_default();
+    let port = url.port_or_known_default().unwrap_or(80);
+    match config.upstream_for(host) {
+        Some(upstreams) => match upstreams.connect(&config.resolver, timeouts.connect) {
+            Ok(stream) => Ok((stream, true)),
+            // without TLS of its own, the proxy can only fetch http URLs itself
+            Err(e) if url.scheme() == "http" => {
+                fall_back_direct(host, port, timeouts.connect, config, resolved, answer, e)
+                    .map(|stream| (stream, false))
+            }
+            Err(e) => Err(e),
+        },
+        None => {
+            let addrs =
+                server_addrs(host, port, config, resolved).map_err(|e| lookup_failed(e, answer))?;
+            let stream = err_to_str!(connect_server(&addrs, timeouts.connect, config))?;
+            *resolved = Some((format!("{host}:{port}"), addrs));
+            Ok((stream, false))
+        }
+    }
+}
+
+/// Connects straight to the server at `host` and `port` if the configuration allows it when no
+/// upstream proxy could be reached, failing with `error` otherwise. Only addresses within the
+/// egress networks are connected to, since they weren't checked for a request bound for an
+/// upstream proxy.
+fn fall_back_direct(
+    host: &str,
+    port: u16,
+    timeout: Option<Duration>,
+    config: &Config,
+    resolved: &Option<(String, Vec<SocketAddr>)>,
+    answer: &mut Answer,
+    error: String,
+) -> Result<TcpStream, String> {
+    if !config.fallback_direct {
+        return Err(error);
+    }
+    log::write_line(&format!("{error}; connecting directly to {host}:{port}"));
+    let mut addrs =
+        server_addrs(host, port, config, resolved).map_err(|e| lookup_failed(e, answer))?;
+    if !config.egress_networks.is_empty() {
+        addrs.retain(|addr| {
+            config
+                .egress_networks
+                .iter()
+                .any(|network| network.contains(addr.ip()))
+        });
+        if addrs.is_empty() {
+            answer.reason = Some(Reason::NoUsableAddress);
+            return Err(format!(
+                "Could not fall back to {host}, which is outside the egress networks"
+            ));
+        }
+    }
+    err_to_str!(connect_server(&addrs, timeout, config))
+}
+
+/// Connects to a server at one of `addrs`, spread across them if the configuration asks for it
+fn connect_server(
+    addrs: &[SocketAddr],
+    timeout: Option<Duration>,
+    config: &Config,
+) -> io::Result<TcpStream> {
+    match &config.balancer {
+        Some(balancer) => balancer.connect(addrs, timeout),
+        None => connect(addrs, timeout),
+    }
+}
+
+/// The addresses of the server for requests to `host` and `port`, taken from `resolved` if the
+/// client's last request went to the same server and otherwise looked up for the host they are
+/// remapped to, if any
+fn server_addrs(
+    host: &str,
+    port: u16,
+    config: &Config,
+    resolved: &Option<(String, Vec<SocketAddr>)>,
+) -> io::Result<Vec<SocketAddr>> {
+    match resolved {
+        Some((authority, addrs)) if *authority == format!("{host}:{port}") => Ok(addrs.clone()),
+        _ => {
+            let (host, port) = config.resolver.destination(host, port);
+            config.resolver.resolve(host, port)
+        }
+    }
+}
+
+/// Sends a plain http request to its server and passes the response back to the client, returning
+/// whether both sides allow the client connection to carry another request.
+///
+/// The request goes over an idle connection to the same server from the pool if there is one, and
+/// the connection is returned to the pool afterwards if the server allows it.
+fn forward_request(
+    client: &mut Client,
+    request: &Request,
+    body_length: BodyLength,
+    url: &url::Url,
+    timeouts: Timeouts,
+    config: &Config,
+    answer: &mut Answer,
+) -> Result<bool, String> {
+    let close = request.wants_close();
+    let host = url.host_str().unwrap_or_default();
+    let via_upstream = config.upstream_for(host).is_some();
+
+    // the route for the host may send the request to another path on the server
+    let rewritten = route::find(&config.routes, host)
+        .and_then(|route| route.rewrite(url.path()))
+        .map(|path| {
+            let mut rewritten = url.clone();
+            rewritten.set_path(&path);
+            rewritten
+        });
+    if let (Some(rewritten), LogFormat::Default) = (&rewritten, config.log_format) {
+        log::write_line(&format!(
+            "Rewrote path {} to {}",
+            url.path(),
+            rewritten.path()
+        ));
+    }
+    // the port is left out of a synthesized `Host` when it is the scheme's default
+    let host_header = match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_owned(),
+    };
+    let extra = match config.forwarded_header {
+        Some(style) => style.headers(
+            err_to_str!(client.stream.peer_addr())?.ip(),
+            err_to_str!(client.stream.local_addr())?.ip(),
+            url.scheme(),
+        ),
+        None => Vec::new(),
+    };
+    // the head depends on whether the connection it goes over, which may fall back to a direct
+    // one, is to an upstream proxy
+    let head_for = |through_upstream| {
+        // an upstream proxy takes the absolute-form target the client sent, while servers expect
+        // just the path and query
+        let target = match &rewritten {
+            Some(rewritten) if through_upstream => rewritten.as_str(),
+            None if through_upstream => request.target.as_str(),
+            Some(rewritten) => &rewritten[url::Position::BeforePath..url::Position::AfterQuery],
+            None => &url[url::Position::BeforePath..url::Position::AfterQuery],
+        };
+        request.forwarded_head(target, &host_header, close, &extra)
+    };
+
+    // every request goes to the same upstream proxy connection, whatever its server
+    let authority_for = |through_upstream| {
+        if through_upstream {
+            None
+        } else {
+            Some(format!(
+                "{host}:{}",
+                url.port_or_known_default().unwrap_or(80)
+            ))
+        }
+    };
+    let mut reusable = config.pool.take(&authority_for(via_upstream));
+
+    if config.log_format == LogFormat::Default {
+        if reusable.is_none() {
+            log::write_line("Connecting via http...");
+        }
+        if close {
+            log::write_line("Client requested connection close");
+        }
+    }
+
+    let deadline = timeouts.duration.map(|duration| Instant::now() + duration);
+    // bytes received from the server past the end of the response head
+    let mut server_buffered = Vec::new();
+    let (mut server_stream, response, response_length, through_upstream) = loop {
+        // a server may close a kept-alive connection before seeing the request sent over it, in
+        // which case the request is sent again over a new connection, as long as its body hasn't
+        // already been read from the client
+        let reused = reusable.is_some();
+        let (mut stream, through_upstream) = match reusable.take() {
+            Some(stream) => (stream, via_upstream),
+            None => {
+                let connection = connect_for_request(
+                    url,
+                    timeouts.within(deadline),
+                    config,
+                    &mut client.resolved,
+                    answer,
+                );
+                let through_upstream = connection.as_ref().is_ok_and(|&(_, through)| through);
+                let stream = connected(
+                    connection.map(|(stream, _)| stream),
+                    client,
+                    request,
+                    config,
+                    answer,
+                )?;
+                (stream, through_upstream)
+            }
+        };
+        let head = head_for(through_upstream);
+
+        if let Err(e) = Teed::new(&stream, config.tee.as_ref(), Direction::Request).write_all(&head)
+        {
+            if reused {
+                continue;
+            }
+            return not_sent(client, request, config, answer, e);
+        }
+
+        if body_length != BodyLength::Empty {
+            if request.expects_continue() {
+                err_to_str!(client.stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n"))?;
+            }
+            err_to_str!(client.stream.set_read_timeout(timeouts.idle))?;
+            let forwarded = body::forward(
+                &mut client.stream,
+                &mut Teed::new(&stream, config.tee.as_ref(), Direction::Request),
+                &mut client.buffered,
+                body_length,
+                config.max_chunk_size,
+            );
+            if let Err(e) = forwarded {
+                // the server's answer to a partial body is never read, so the client gets this
+                if e.kind() == io::ErrorKind::InvalidData {
+                    reject(
+                        client,
+                        request,
+                        config,
+                        answer,
+                        "400 Bad Request",
+                        &e.to_string(),
+                    )?;
+                }
+                return Err(e.to_string());
+            }
+        }
+
+        // the wait is measured once the request is sent, so it is cut to what remains after that
+        let waiting = timeouts.within(deadline);
+        let wait = [
+            waiting.response.or(waiting.idle),
+            config::remaining(deadline),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        err_to_str!(stream.set_read_timeout(wait))?;
+        let response = loop {
+            let head = match http::read_head(&mut stream, &mut server_buffered) {
+                Ok(Some(head)) => head,
+                Ok(None) => break None,
+                Err(e) if is_timeout(&e) && deadline.is_some_and(|d| Instant::now() >= d) => {
+                    reject(
+                        client,
+                        request,
+                        config,
+                        answer,
+                        "504 Gateway Timeout",
+                        "The request took longer than the proxy allows",
+                    )?;
+                    answer.reason = Some(Reason::TotalTimeout);
+                    return Err("Request took longer than the maximum duration".to_owned());
+                }
+                Err(e) if is_timeout(&e) && waiting.response.is_some() => {
+                    reject(
+                        client,
+                        request,
+                        config,
+                        answer,
+                        "504 Gateway Timeout",
+                        "The server did not respond in time",
+                    )?;
+                    return Err("Timed out waiting for a response from the server".to_owned());
+                }
+                Err(e) if is_timeout(&e) => {
+                    answer.reason = Some(Reason::IdleTimeout);
+                    return Err("Connection closed after being idle".to_owned());
+                }
+                Err(e) => return Err(e.to_string()),
+            };
+            // the framing is checked before any of the response is passed on, so a server that
+            // sends a bad one is answered for rather than leaving the client a truncated response
+            let parsed = Response::parse(head).and_then(|response| {
+                let length = BodyLength::of_response(&request.method, &response)?;
+                Ok((response, length))
+            });
+            let (response, length) = match parsed {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    reject(
+                        client,
+                        request,
+                        config,
+                        answer,
+                        "502 Bad Gateway",
+                        "The server sent a malformed response",
+                    )?;
+                    return Err(format!("Malformed response from the server: {e}"));
+                }
+            };
+            err_to_str!(
+                Teed::new(&client.stream, config.tee.as_ref(), Direction::Response)
+                    .write_all(&response.raw)
+            )?;
+            // interim responses are passed on while waiting for the final one
+            if !(100..200).contains(&response.status) || response.status == 101 {
+                break Some((response, length));
+            }
+        };
+
+        match response {
+            Some((response, length)) => break (stream, response, length, through_upstream),
+            None if reused && body_length == BodyLength::Empty => continue,
+            None => {
+                answer.reason = Some(Reason::ServerEof);
+                return Err("Server closed the connection without responding".to_owned());
+            }
+        }
+    };
+    answer.status = Some(response.status);
+    record_server_addr(&server_stream, config, answer);
+
+    if response.status == 101 {
+        // the connection has switched to another protocol, whose bytes pass through untouched
+        err_to_str!(
+            Teed::new(&client.stream, config.tee.as_ref(), Direction::Response)
+                .write_all(&server_buffered)
+        )?;
+        err_to_str!(
+            Teed::new(&server_stream, config.tee.as_ref(), Direction::Request)
+                .write_all(&client.buffered)
+        )?;
+        client.buffered.clear();
+
+        let mut relayed = Relayed::default();
+        let result = relay(
+            &mut client.stream,
+            &mut server_stream,
+            true,
+            timeouts,
+            config,
+            request.error_format(config.error_format),
+            &mut relayed,
+        );
+        answer.bytes = server_buffered.len() as u64 + relayed.bytes;
+        answer.reason = relayed.reason;
+        return result.map(|()| false);
+    }
+
+    err_to_str!(server_stream.set_read_timeout(timeouts.idle))?;
+    answer.bytes = body::forward(
+        &mut Deadline {
+            stream: &server_stream,
+            deadline,
+            idle: timeouts.idle,
+        },
+        &mut Teed::new(&client.stream, config.tee.as_ref(), Direction::Response),
+        &mut server_buffered,
+        response_length,
+        config.max_chunk_size,
+    )
+    .map_err(|e| {
+        if is_timeout(&e) {
+            answer.reason = Some(match deadline {
+                Some(deadline) if Instant::now() >= deadline => Reason::TotalTimeout,
+                _ => Reason::IdleTimeout,
+            });
+        }
+        e.to_string()
+    })?;
+
+    let server_reusable = !response.wants_close() && response_length != BodyLength::UntilClose;
+    if server_reusable {
+        answer.reason = Some(Reason::ClientEof);
+    }
+    // a server that sent more than its response can't be trusted with another request
+    if server_reusable && server_buffered.is_empty() {
+        config
+            .pool
+            .put(authority_for(through_upstream), server_stream);
+    }
+    Ok(server_reusable && !close)
+}
+
+/// Maximum number of bytes from the start of a response kept to read its status from
+const MAX_RESPONSE_HEAD: usize = 8192;
+
+/// What was sent back to the client while relaying a connection
+#[derive(Default)]
+struct Relayed {
+    /// whether the connection is a `CONNECT` tunnel, whose data has no http framing
+    tunnel: bool,
+    /// the start of the data sent to the client, up to `MAX_RESPONSE_HEAD` bytes
+    response_start: Vec<u8>,
+    /// total number of bytes sent to the client
+    bytes: u64,
+    /// status of a response the proxy sent in place of the server's
+    proxy_status: Option<u16>,
+    /// what ended the relay, once something has
+    reason: Option<Reason>,
+}
+
+impl Relayed {
+    fn record(&mut self, data: &[u8]) {
+        let keep = data
+            .len()
+            .min(MAX_RESPONSE_HEAD - self.response_start.len());
+        self.response_start.extend_from_slice(&data[..keep]);
+        self.bytes += data.len() as u64;
+    }
+
+    /// Parses the head of the response sent to the client, returning its status and length
+    fn response_head(&self) -> Option<(u16, usize)> {
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut response = httparse::Response::new(&mut headers);
+        match response.parse(&self.response_start) {
+            Ok(httparse::Status::Complete(length)) => Some((response.code?, length)),
+            _ => None,
+        }
+    }
+
+    fn status(&self) -> Option<u16> {
+        if self.tunnel {
+            return None;
+        }
+        self.proxy_status
+            .or_else(|| self.response_head().map(|(status, _)| status))
+    }
+
+    /// Number of bytes sent to the client after the response head
+    fn body_bytes(&self) -> u64 {
+        match self.response_head() {
+            Some((_, head_length)) if !self.tunnel => self.bytes - head_length as u64,
+            _ => self.bytes,
+        }
+    }
+}
+
+/// Pipes data between the client and server until both are done sending, or a timeout expires
+fn relay(
+    client_stream: &mut TcpStream,
+    server_stream: &mut TcpStream,
+    tunneling: bool,
+    timeouts: Timeouts,
+    config: &Config,
+    error_format: ErrorFormat,
+    relayed: &mut Relayed,
+) -> Result<(), String> {
+    relayed.tunnel = tunneling;
 
     err_to_str!(client_stream.set_nonblocking(true))?;
     err_to_str!(server_stream.set_nonblocking(true))?;
 
-    // bytes read from client to be written to server
-    let mut client_buffer = [0; 4096];
-    // bytes read from server to be written to client
-    let mut server_buffer = [0; 4096];
+    let tee = config.tee.as_ref();
+
+    // bytes read from client to be written to server, while the buffer is held
+    let mut client_buffer = Some(config.buffers.take());
+    // bytes read from server to be written to client, while the buffer is held
+    let mut server_buffer = Some(config.buffers.take());
 
     // num bytes in `client_buffer` to write to `server_stream`
     let mut to_write_to_server = 0;
     // num bytes in `server_buffer` to write to `client_stream`
     let mut to_write_to_client = 0;
 
-    loop {
-        to_write_to_server = match pipe(
-            &mut client_stream,
-            &mut server_stream,
-            &mut client_buffer,
-            to_write_to_server,
-        ) {
-            Ok(bytes) => bytes,
-            Err(PipeError::SocketClosed) => return Err("Client socket closed".to_owned()),
-            Err(PipeError::Unknown(e)) => return Err(e),
-        };
+    // whether each side may still send data
+    let mut client_open = true;
+    let mut server_open = true;
 
-        to_write_to_client = match pipe(
-            &mut server_stream,
-            &mut client_stream,
-            &mut server_buffer,
-            to_write_to_client,
-        ) {
-            Ok(bytes) => bytes,
-            Err(PipeError::SocketClosed) => return Err("Server socket closed".to_owned()),
-            Err(PipeError::Unknown(e)) => return Err(e),
-        };
+    // when the server was last handed a request it has not started responding to; a tunnel's
+    // request is whatever the client sends first
+    let mut awaiting_response_since = if tunneling {
+        None
+    } else {
+        Some(Instant::now())
+    };
+    let mut response_started = false;
+
+    // when data last moved in either direction
+    let mut last_activity = Instant::now();
+    let (max_duration, idle_timeout) = if tunneling {
+        (
+            timeouts.tunnel_duration,
+            timeouts.tunnel_idle.or(timeouts.idle),
+        )
+    } else {
+        (timeouts.duration, timeouts.idle)
+    };
+    let deadline = max_duration.map(|duration| last_activity + duration);
+    // how long the loop last slept for want of data, reset once any moves
+    let mut idle_delay = Duration::ZERO;
+
+    while client_open || server_open {
+        let mut moved = false;
+
+        if client_open && client_buffer.is_none() && readable(client_stream) {
+            client_buffer = Some(config.buffers.take());
+        }
+        if let (true, Some(buffer)) = (client_open, client_buffer.as_mut()) {
+            match pipe(client_stream, server_stream, buffer, to_write_to_server) {
+                Ok(piped) => {
+                    moved |= piped.read > 0 || piped.pending < to_write_to_server;
+                    stats::record_transfer(piped.read);
+                    to_write_to_server = piped.pending;
+                    if let Some(tee) = tee {
+                        tee.copy(Direction::Request, &buffer[..piped.read]);
+                    }
+                    if piped.read > 0 {
+                        last_activity = Instant::now();
+                        if !response_started && awaiting_response_since.is_none() {
+                            awaiting_response_since = Some(last_activity);
+                        }
+                    }
+                }
+                Err(PipeError::SocketClosed) => {
+                    // signal the end of the client's data to the server, which may still respond
+                    client_open = false;
+                    relayed.reason.get_or_insert(Reason::ClientEof);
+                    let _ = server_stream.shutdown(Shutdown::Write);
+                }
+                Err(PipeError::Unknown(e)) => return Err(e),
+            };
+        }
+
+        if server_open && server_buffer.is_none() && readable(server_stream) {
+            server_buffer = Some(config.buffers.take());
+        }
+        if let (true, Some(buffer)) = (server_open, server_buffer.as_mut()) {
+            match pipe(server_stream, client_stream, buffer, to_write_to_client) {
+                Ok(piped) => {
+                    moved |= piped.read > 0 || piped.pending < to_write_to_client;
+                    stats::record_transfer(piped.read);
+                    to_write_to_client = piped.pending;
+                    relayed.record(&buffer[..piped.read]);
+                    if let Some(tee) = tee {
+                        tee.copy(Direction::Response, &buffer[..piped.read]);
+                    }
+                    if piped.read > 0 {
+                        last_activity = Instant::now();
+                        response_started = true;
+                    }
+                }
+                // the response is complete once a plain http server closes, so close the client too
+                Err(PipeError::SocketClosed) if !tunneling => {
+                    relayed.reason.get_or_insert(Reason::ServerEof);
+                    break;
+                }
+                Err(PipeError::SocketClosed) => {
+                    server_open = false;
+                    relayed.reason.get_or_insert(Reason::ServerEof);
+                    let _ = client_stream.shutdown(Shutdown::Write);
+                }
+                Err(PipeError::Unknown(e)) => return Err(e),
+            };
+        }
+
+        if idle_timeout.is_some_and(|timeout| last_activity.elapsed() >= timeout) {
+            relayed.reason = Some(Reason::IdleTimeout);
+            return Err("Connection closed after being idle".to_owned());
+        }
+
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            relayed.reason = Some(Reason::TotalTimeout);
+            return Err("Connection closed after reaching its maximum duration".to_owned());
+        }
+
+        if let (Some(timeout), Some(since)) = (timeouts.response, awaiting_response_since) {
+            if !response_started && since.elapsed() >= timeout {
+                relayed.reason = Some(Reason::ResponseTimeout);
+                if !tunneling {
+                    err_to_str!(client_stream.set_nonblocking(false))?;
+                    relayed.proxy_status = Some(504);
+                    err_to_str!(send_error(
+                        client_stream,
+                        "504 Gateway Timeout",
+                        "The server did not respond in time",
+                        error_format,
+                    ))?;
+                }
+                return Err("Timed out waiting for a response from the server".to_owned());
+            }
+        }
+
+        // a quiet connection gives its buffers back for others to use, taking them again once
+        // there is data to read; only empty ones are given back, so no bytes are lost
+        if last_activity.elapsed() >= BUFFER_RELEASE_IDLE {
+            if to_write_to_server == 0 {
+                client_buffer = None;
+            }
+            if to_write_to_client == 0 {
+                server_buffer = None;
+            }
+        }
+
+        // both sockets are non-blocking, so without a pause an idle connection spins a core
+        idle_delay = relay_backoff(idle_delay, moved);
+        if !idle_delay.is_zero() {
+            thread::sleep(idle_delay);
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether reading the non-blocking `stream` would return something rather than block, either
+/// data or the end of the stream
+fn readable(stream: &TcpStream) -> bool {
+    !matches!(
+        stream.peek(&mut [0]),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock
+    )
+}
+
+/// Whether a failed accept was caused by running out of file descriptors or memory
+fn is_resource_exhaustion(error: &io::Error) -> bool {
+    // there's no error kind for running out of file descriptors
+    #[cfg(target_os = "linux")]
+    if matches!(error.raw_os_error(), Some(sys::EMFILE | sys::ENFILE)) {
+        return true;
     }
+    error.kind() == io::ErrorKind::OutOfMemory
+}
+
+/// Returns how long the relay loop sleeps after a pass that did or didn't move data, given the
+/// previous sleep
+fn relay_backoff(previous: Duration, moved: bool) -> Duration {
+    if moved {
+        Duration::ZERO
+    } else {
+        (previous * 2).clamp(RELAY_BACKOFF_INITIAL, RELAY_BACKOFF_MAX)
+    }
+}
+
+/// Returns how long to wait after an accept fails with `error`, given the previous wait
+fn accept_backoff(previous: Duration, error: &io::Error) -> Duration {
+    let (initial, max) = if is_resource_exhaustion(error) {
+        (
+            ACCEPT_EXHAUSTED_BACKOFF_INITIAL,
+            ACCEPT_EXHAUSTED_BACKOFF_MAX,
+        )
+    } else {
+        (ACCEPT_BACKOFF_INITIAL, ACCEPT_BACKOFF_MAX)
+    };
+    (previous * 2).clamp(initial, max)
+}
+
+/// Picks a random duration between half of `delay` and `delay`
+fn jittered(delay: Duration) -> Duration {
+    use std::hash::{BuildHasher, Hasher};
+    // each `RandomState` is freshly seeded, which is random enough to spread out retries
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    delay / 2 + delay.mul_f64((random % 1000) as f64 / 2000.0)
 }
 
 fn main() -> Result<(), String> {
-    let listener = TcpListener::bind("127.0.0.1:8080")
+    let config = match config::parse_args(std::env::args().skip(1)) {
+        Ok(Command::Run(config)) => Arc::<Config>::from(config),
+        Ok(Command::Help) => {
+            print!("{}", config::usage());
+            return Ok(());
+        }
+        Ok(Command::Version) => {
+            println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("{e}\nRun with --help to see the available options");
+            std::process::exit(2);
+        }
+    };
+
+    if let Some(path) = &config.log_file {
+        log::log_to_file(path).map_err(|err| format!("Could not open log file {path}: {err}"))?;
+    }
+    if let Some((start, end)) = config.source_ports {
+        source::restrict_ports(start, end);
+    }
+    if let Some(linger) = config.linger {
+        linger::set(linger);
+    }
+
+    let listener = bind_listener(&config.listen_addr, config.backlog)
         .map_err(|err| format!("Could not start TCP listener: {err}"))?;
 
-    println!("Server started...");
+    // polling the listener lets the loop notice a shutdown request between connections
+    err_to_str!(listener.set_nonblocking(true))?;
+    install_signal_handlers();
+
+    if let Some(addr) = &config.stats_listen_addr {
+        let stats_listener = TcpListener::bind(addr)
+            .map_err(|err| format!("Could not start stats listener: {err}"))?;
+        admin::spawn(
+            stats_listener,
+            config.server_name.clone(),
+            config.stats_allowed_clients.clone(),
+            &SHUTDOWN,
+        );
+    }
+
+    let stats_logger = config
+        .stats_interval
+        .map(|interval| thread::spawn(move || log_stats(interval)));
+
+    let sweeper_config = Arc::clone(&config);
+    thread::spawn(move || loop {
+        thread::sleep(POOL_SWEEP_INTERVAL);
+        sweeper_config.pool.sweep();
+    });
+
+    log::write_line("Server started...");
+    for line in config.summary() {
+        log::write_line(&line);
+    }
+
+    let open_files_limit = rlimit::open_files_limit();
+    if let Some(limit) = open_files_limit {
+        log::write_line(&format!("Open file limit: {limit}"));
+    }
+    let mut open_files = rlimit::OpenFiles::new(
+        open_files_limit,
+        config.fd_warn_ratio,
+        config.fd_reject_ratio,
+    );
+    let mut memory_bound = config
+        .max_memory
+        .map(|max| rlimit::MemoryBound::new(max, MEMORY_CHECK_INTERVAL, rlimit::resident_memory));
+    let mut accept_bucket = config.accept_rate.map(limit::TokenBucket::new);
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
+    // how long the accept loop last waited after a failed accept, reset once one succeeds
+    let mut accept_error_delay = Duration::ZERO;
+
+    while !SHUTDOWN.load(Ordering::SeqCst) {
+        let saturated = config
+            .max_connections
+            .is_some_and(|max| stats::ACTIVE_CONNECTIONS.load(Ordering::SeqCst) >= max);
+        if saturated && config.overflow_policy == OverflowPolicy::Block {
+            // leave new connections queued in the backlog until one finishes
+            thread::sleep(ACCEPT_POLL_INTERVAL);
+            continue;
+        }
+        if config.accept_rate_policy == OverflowPolicy::Block {
+            if let Some(wait) = accept_bucket.as_mut().map(limit::TokenBucket::wait) {
+                if !wait.is_zero() {
+                    // leave new connections queued in the backlog until the rate allows another
+                    thread::sleep(wait.min(ACCEPT_POLL_INTERVAL));
+                    continue;
+                }
+            }
+        }
+
+        if memory_bound
+            .as_mut()
+            .is_some_and(rlimit::MemoryBound::exceeded)
+        {
+            // leave new connections queued in the backlog until memory is freed
+            thread::sleep(ACCEPT_POLL_INTERVAL);
+            continue;
+        }
+
+        let fds_exhausted = open_files.exhausted(rlimit::estimated_open_files());
+
+        match listener.accept() {
+            Ok((mut stream, client_addr)) => {
+                accept_error_delay = Duration::ZERO;
+                if !config.allowed_clients.is_empty()
+                    && !config
+                        .allowed_clients
+                        .iter()
+                        .any(|network| network.contains(client_addr.ip()))
+                {
+                    let rejections = stats::SOURCE_REJECTIONS.fetch_add(1, Ordering::SeqCst) + 1;
+                    log::write_line(&format!(
+                        "Refused connection from {client_addr}, which is not an allowed client \
+                         ({rejections} so far)"
+                    ));
+                    if config.log_connections {
+                        log::write_line(&log::connection_line(
+                            client_addr,
+                            Outcome::RejectedSource,
+                            Reason::AclDeny,
+                            Duration::ZERO,
+                        ));
+                    }
+                    continue;
+                }
+
+                // some platforms hand out accepted sockets with the listener's non-blocking mode
+                if let Err(e) = stream
+                    .set_nonblocking(false)
+                    .and_then(|()| linger::apply(&stream))
+                {
+                    log::write_line(&format!("Could not configure stream: {e}"));
+                    continue;
+                }
+
+                let rate_exceeded = accept_bucket
+                    .as_mut()
+                    .is_some_and(|bucket| !bucket.try_take());
+                if saturated || fds_exhausted || rate_exceeded {
+                    let rejections = stats::OVERFLOW_REJECTIONS.fetch_add(1, Ordering::SeqCst) + 1;
+                    let limit = if saturated {
+                        "connection limit"
+                    } else if fds_exhausted {
+                        "open file limit"
+                    } else {
+                        "accept rate limit"
+                    };
+                    log::write_line(&format!(
+                        "Rejected connection at the {limit} ({rejections} so far)"
+                    ));
+                    if config.log_connections {
+                        log::write_line(&log::connection_line(
+                            client_addr,
+                            Outcome::RejectedLimit,
+                            Reason::RateLimit,
+                            Duration::ZERO,
+                        ));
+                    }
+                    // the request isn't read, so this is sent whether or not it's plain http
+                    let _ = stream.set_write_timeout(Some(OVERFLOW_WRITE_TIMEOUT));
+                    let _ = send_error(
+                        &mut stream,
+                        "503 Service Unavailable",
+                        "The proxy is handling too many connections",
+                        config.error_format,
+                    );
+                    continue;
+                }
+
+                let active = stats::ActiveConnection::start(&stream, client_addr);
+                let config = Arc::clone(&config);
                 thread::spawn(move || {
-                    if let Err(e) = handle_connection(stream) {
-                        eprintln!("{e}")
+                    active.enter();
+                    let started = Instant::now();
+                    let mut outcome = Outcome::Error;
+                    let mut reason = Reason::Error;
+                    if let Err(e) = handle_connection(stream, &config, &mut outcome, &mut reason) {
+                        log::write_line(&e)
+                    }
+                    // the sockets were shut down under it, so it saw them close like any other
+                    if active.closing() {
+                        reason = Reason::AdminClosed;
+                    }
+                    if config.log_connections {
+                        log::write_line(&log::connection_line(
+                            client_addr,
+                            outcome,
+                            reason,
+                            started.elapsed(),
+                        ));
                     }
                 });
             }
-            Err(e) => eprintln!("Could not connect to stream: {e}"),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL_INTERVAL),
+            Err(e) => {
+                log::write_line(&format!("Could not connect to stream: {e}"));
+                accept_error_delay = accept_backoff(accept_error_delay, &e);
+                thread::sleep(jittered(accept_error_delay));
+            }
+        }
+    }
+
+    log::write_line("Shutting down...");
+    // new connections are refused from here on rather than left waiting in the backlog
+    drop(listener);
+    // idle server connections serve no client, so they are closed now rather than timing out
+    config.pool.close();
+
+    let drain_started = Instant::now();
+    while stats::ACTIVE_CONNECTIONS.load(Ordering::SeqCst) > 0 {
+        if drain_started.elapsed() >= config.drain_timeout {
+            log::write_line(&format!(
+                "Closing {} connections still open after the drain timeout",
+                stats::ACTIVE_CONNECTIONS.load(Ordering::SeqCst)
+            ));
+            break;
         }
+        thread::sleep(ACCEPT_POLL_INTERVAL);
+    }
+
+    if let Some(stats_logger) = stats_logger {
+        let _ = stats_logger.join();
     }
 
     Ok(())
 }
+
+/// Logs the statistics every `interval` until the proxy shuts down, with how much each
+/// cumulative one has grown since the last time
+fn log_stats(interval: Duration) {
+    let mut previous = stats::counters().map(|counter| counter.value);
+    let mut next = Instant::now() + interval;
+    while !SHUTDOWN.load(Ordering::SeqCst) {
+        // waiting in steps lets a shutdown end the thread promptly
+        let now = Instant::now();
+        if now < next {
+            thread::sleep(ACCEPT_POLL_INTERVAL.min(next - now));
+            continue;
+        }
+        next += interval;
+
+        let counters = stats::counters();
+        let line = counters
+            .iter()
+            .zip(previous)
+            .map(|(counter, previous)| {
+                if counter.cumulative {
+                    format!(
+                        "{}={} (+{})",
+                        counter.name,
+                        counter.value,
+                        counter.value - previous
+                    )
+                } else {
+                    format!("{}={}", counter.name, counter.value)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        log::write_line(&format!("Stats: {line}"));
+        previous = counters.map(|counter| counter.value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bound_listener_accepts_connections() {
+        let listener = bind_listener("127.0.0.1:0", 16).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = TcpStream::connect(addr).unwrap();
+        let (accepted, peer) = listener.accept().unwrap();
+        assert_eq!(peer, client.local_addr().unwrap());
+        assert_eq!(accepted.local_addr().unwrap(), addr);
+    }
+
+    #[test]
+    fn listener_can_be_rebound_while_its_connections_linger() {
+        let listener = bind_listener("127.0.0.1:0", 16).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(addr).unwrap();
+        // the accepted side closes first, so it stays bound until the close completes
+        drop(listener.accept().unwrap());
+        drop(listener);
+        bind_listener(&addr.to_string(), 16).unwrap();
+    }
+
+    #[test]
+    fn accept_backoff_waits_longer_after_running_out_of_resources() {
+        let exhausted = io::Error::from(io::ErrorKind::OutOfMemory);
+        let aborted = io::Error::from(io::ErrorKind::ConnectionAborted);
+        assert!(is_resource_exhaustion(&exhausted));
+        assert!(!is_resource_exhaustion(&aborted));
+
+        assert_eq!(
+            accept_backoff(Duration::ZERO, &exhausted),
+            ACCEPT_EXHAUSTED_BACKOFF_INITIAL
+        );
+        assert_eq!(
+            accept_backoff(Duration::ZERO, &aborted),
+            ACCEPT_BACKOFF_INITIAL
+        );
+        assert_eq!(
+            accept_backoff(ACCEPT_BACKOFF_MAX, &aborted),
+            ACCEPT_BACKOFF_MAX
+        );
+    }
+
+    #[test]
+    fn server_is_looked_up_once_for_the_egress_check_and_the_connection() {
+        let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = origin.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = origin.accept().unwrap();
+            let mut buffered = Vec::new();
+            while let Ok(Some(_)) = http::read_head(&mut stream, &mut buffered) {
+                let response = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+                if stream.write_all(response).is_err() {
+                    break;
+                }
+            }
+        });
+        let args = [
+            "--egress-cidr",
+            "127.0.0.0/8",
+            "--host-override",
+            "resolved-once.test=127.0.0.1",
+        ];
+        let Ok(config::Command::Run(config)) =
+            config::parse_args(args.iter().map(|&arg| arg.to_owned()))
+        else {
+            panic!("{args:?} were refused");
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        let handler = thread::spawn(move || {
+            let (mut outcome, mut reason) = (Outcome::Error, Reason::Error);
+            handle_connection(accepted, &config, &mut outcome, &mut reason)
+        });
+
+        let lookups = || {
+            resolve::LOOKUPS
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|host| *host == "resolved-once.test")
+                .count()
+        };
+        let mut buffered = Vec::new();
+        for path in ["/first", "/second"] {
+            write!(
+                client,
+                "GET http://resolved-once.test:{port}{path} HTTP/1.1\r\n\
+                 Host: resolved-once.test:{port}\r\n\r\n"
+            )
+            .unwrap();
+            let head = http::read_head(&mut client, &mut buffered)
+                .unwrap()
+                .unwrap();
+            assert!(head.starts_with(b"HTTP/1.1 200"));
+            // the keep-alive request to the same server reuses the addresses already checked
+            assert_eq!(lookups(), 1);
+        }
+        drop(client);
+        handler.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn quiet_relay_passes_are_bounded() {
+        // count the passes the loop makes over a second with nothing to move
+        let (mut passes, mut slept, mut delay) = (0, Duration::ZERO, Duration::ZERO);
+        while slept < Duration::from_secs(1) {
+            delay = relay_backoff(delay, false);
+            slept += delay;
+            passes += 1;
+        }
+        assert!(passes <= 110, "{passes} passes");
+        assert_eq!(relay_backoff(Duration::ZERO, false), RELAY_BACKOFF_INITIAL);
+        assert_eq!(delay, RELAY_BACKOFF_MAX);
+
+        // data moving again ends the pause at once
+        assert_eq!(relay_backoff(delay, true), Duration::ZERO);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn running_out_of_file_descriptors_is_resource_exhaustion() {
+        assert!(is_resource_exhaustion(&io::Error::from_raw_os_error(
+            sys::EMFILE
+        )));
+        assert!(is_resource_exhaustion(&io::Error::from_raw_os_error(
+            sys::ENFILE
+        )));
+    }
+}
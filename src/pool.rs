@@ -0,0 +1,261 @@
+use crate::{
+    clock::{Clock, SystemClock},
+    stats,
+};
+use std::{
+    collections::VecDeque,
+    io,
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Default number of idle connections kept to any one server
+pub const DEFAULT_MAX_IDLE_PER_HOST: usize = 8;
+/// Default number of idle connections kept across all servers
+pub const DEFAULT_MAX_IDLE_TOTAL: usize = 256;
+/// Default time an idle connection is kept before it is closed
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A server connection waiting in the pool
+struct Idle {
+    /// the `host:port` the connection is to, or `None` if it goes through an upstream proxy
+    authority: Option<String>,
+    stream: TcpStream,
+    since: Instant,
+}
+
+/// Server connections left open after a response, shared between client connections so later
+/// requests to the same server skip connecting
+pub struct Pool<C: Clock = SystemClock> {
+    clock: C,
+    /// most idle connections kept to any one server
+    pub max_idle_per_host: usize,
+    /// most idle connections kept across all servers
+    pub max_idle_total: usize,
+    /// how long a connection may sit in the pool before it is closed
+    pub idle_timeout: Duration,
+    /// idle connections, least recently returned first
+    idle: Mutex<VecDeque<Idle>>,
+    /// set once the pool has been closed, after which returned connections are closed too
+    closed: AtomicBool,
+}
+
+impl Pool {
+    pub fn new() -> Pool {
+        Pool::with_clock(SystemClock)
+    }
+}
+
+impl<C: Clock> Pool<C> {
+    /// A pool timing how long connections have been idle by `clock`
+    pub fn with_clock(clock: C) -> Pool<C> {
+        Pool {
+            clock,
+            max_idle_per_host: DEFAULT_MAX_IDLE_PER_HOST,
+            max_idle_total: DEFAULT_MAX_IDLE_TOTAL,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            idle: Mutex::new(VecDeque::new()),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// Takes the most recently returned connection to `authority` that is still open
+    pub fn take(&self, authority: &Option<String>) -> Option<TcpStream> {
+        let now = self.clock.now();
+        let mut idle = self.idle.lock().unwrap();
+        while let Some(index) = idle.iter().rposition(|entry| entry.authority == *authority) {
+            let entry = idle.remove(index).unwrap();
+            stats::POOLED_CONNECTIONS.store(idle.len(), Ordering::SeqCst);
+            if now.duration_since(entry.since) < self.idle_timeout && is_reusable(&entry.stream) {
+                return Some(entry.stream);
+            }
+        }
+        None
+    }
+
+    /// Keeps `stream` for a later request to `authority`, closing the least recently used
+    /// connections as needed to stay within the limits
+    pub fn put(&self, authority: Option<String>, stream: TcpStream) {
+        let mut idle = self.idle.lock().unwrap();
+        if self.closed.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let for_host = idle
+            .iter()
+            .filter(|entry| entry.authority == authority)
+            .count();
+        if for_host >= self.max_idle_per_host {
+            if let Some(oldest) = idle.iter().position(|entry| entry.authority == authority) {
+                idle.remove(oldest);
+            }
+        }
+        if idle.len() >= self.max_idle_total {
+            idle.pop_front();
+        }
+
+        if self.max_idle_per_host > 0 && self.max_idle_total > 0 {
+            idle.push_back(Idle {
+                authority,
+                stream,
+                since: self.clock.now(),
+            });
+        }
+        stats::POOLED_CONNECTIONS.store(idle.len(), Ordering::SeqCst);
+    }
+
+    /// Closes every idle connection, and each one returned from now on, so that none are held
+    /// open while the proxy shuts down
+    pub fn close(&self) {
+        let mut idle = self.idle.lock().unwrap();
+        self.closed.store(true, Ordering::SeqCst);
+        idle.clear();
+        stats::POOLED_CONNECTIONS.store(0, Ordering::SeqCst);
+    }
+
+    /// Closes the connections that have been idle for longer than the idle timeout
+    pub fn sweep(&self) {
+        let now = self.clock.now();
+        let mut idle = self.idle.lock().unwrap();
+        idle.retain(|entry| now.duration_since(entry.since) < self.idle_timeout);
+        stats::POOLED_CONNECTIONS.store(idle.len(), Ordering::SeqCst);
+    }
+}
+
+/// Whether an idle connection can still take a request: the server must not have closed it or
+/// sent anything unprompted while it waited
+fn is_reusable(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let waiting = matches!(
+        stream.peek(&mut [0]),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock
+    );
+    stream.set_nonblocking(false).is_ok() && waiting
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::net::{SocketAddr, TcpListener};
+
+    /// A server accepting connections it never writes to, and a way to open connections to it
+    struct Server {
+        listener: TcpListener,
+        addr: SocketAddr,
+        accepted: Vec<TcpStream>,
+    }
+
+    impl Server {
+        fn new() -> Server {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            Server {
+                listener,
+                addr,
+                accepted: Vec::new(),
+            }
+        }
+
+        fn connect(&mut self) -> TcpStream {
+            let stream = TcpStream::connect(self.addr).unwrap();
+            self.accepted.push(self.listener.accept().unwrap().0);
+            stream
+        }
+    }
+
+    fn key(name: &str) -> Option<String> {
+        Some(name.to_owned())
+    }
+
+    fn local_port(stream: &TcpStream) -> u16 {
+        stream.local_addr().unwrap().port()
+    }
+
+    #[test]
+    fn take_returns_the_latest_connection_to_the_same_server() {
+        let mut server = Server::new();
+        let pool = Pool::new();
+        let (older, newer, other) = (server.connect(), server.connect(), server.connect());
+        let (older_port, newer_port) = (local_port(&older), local_port(&newer));
+        pool.put(key("a:80"), older);
+        pool.put(key("a:80"), newer);
+        pool.put(key("b:80"), other);
+
+        assert_eq!(
+            pool.take(&key("a:80")).map(|s| local_port(&s)),
+            Some(newer_port)
+        );
+        assert_eq!(
+            pool.take(&key("a:80")).map(|s| local_port(&s)),
+            Some(older_port)
+        );
+        assert!(pool.take(&key("a:80")).is_none());
+        assert!(pool.take(&None).is_none());
+        assert!(pool.take(&key("b:80")).is_some());
+    }
+
+    #[test]
+    fn put_keeps_within_the_per_host_and_total_limits() {
+        let mut server = Server::new();
+        let pool = Pool {
+            max_idle_per_host: 2,
+            max_idle_total: 3,
+            ..Pool::new()
+        };
+        for _ in 0..3 {
+            pool.put(key("a:80"), server.connect());
+        }
+        assert_eq!(pool.idle.lock().unwrap().len(), 2);
+        pool.put(key("b:80"), server.connect());
+        pool.put(key("c:80"), server.connect());
+        let idle = pool.idle.lock().unwrap();
+        let kept: Vec<_> = idle.iter().map(|entry| entry.authority.clone()).collect();
+        assert_eq!(kept, [key("a:80"), key("b:80"), key("c:80")]);
+    }
+
+    #[test]
+    fn connections_closed_by_the_server_are_not_reused() {
+        let mut server = Server::new();
+        let pool = Pool::new();
+        pool.put(key("a:80"), server.connect());
+        server.accepted.clear();
+        // give the close time to arrive
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(pool.take(&key("a:80")).is_none());
+    }
+
+    #[test]
+    fn idle_connections_expire_after_the_idle_timeout() {
+        let mut server = Server::new();
+        let clock = ManualClock::new();
+        let pool = Pool {
+            idle_timeout: Duration::from_secs(10),
+            ..Pool::with_clock(&clock)
+        };
+        pool.put(key("a:80"), server.connect());
+        pool.put(key("a:80"), server.connect());
+        clock.advance(Duration::from_secs(9));
+        assert!(pool.take(&key("a:80")).is_some());
+
+        clock.advance(Duration::from_secs(1));
+        pool.sweep();
+        assert!(pool.idle.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn closed_pool_keeps_nothing() {
+        let mut server = Server::new();
+        let pool = Pool::new();
+        pool.put(key("a:80"), server.connect());
+        pool.close();
+        pool.put(key("a:80"), server.connect());
+        assert!(pool.take(&key("a:80")).is_none());
+    }
+}
@@ -0,0 +1,317 @@
+use crate::limit::Semaphore;
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+};
+
+/// The kinds of addresses connections may be made to
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Family {
+    Any,
+    V4,
+    V6,
+}
+
+impl Family {
+    pub fn parse(family: &str) -> Result<Family, String> {
+        match family {
+            "any" => Ok(Family::Any),
+            "v4" => Ok(Family::V4),
+            "v6" => Ok(Family::V6),
+            _ => Err(format!("Unknown address family: {family}")),
+        }
+    }
+
+    fn allows(self, ip: IpAddr) -> bool {
+        match self {
+            Family::Any => true,
+            Family::V4 => ip.is_ipv4(),
+            Family::V6 => ip.is_ipv6(),
+        }
+    }
+}
+
+/// Every host `Resolver::resolve` has been asked for, so tests can count the lookups made
+#[cfg(test)]
+pub static LOOKUPS: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+
+/// Looks up the addresses of the hosts the proxy connects to
+pub struct Resolver {
+    /// bounds the number of lookups in progress at once, if set
+    pub limit: Option<Semaphore>,
+    /// addresses used for these lowercase host names instead of looking them up
+    pub overrides: HashMap<String, Vec<IpAddr>>,
+    /// hosts connected to in place of these lowercase host names, with the port to use instead
+    /// of the requested one, if any
+    pub remaps: HashMap<String, (String, Option<u16>)>,
+    /// the only kind of address hosts resolve to, if limited
+    pub family: Family,
+}
+
+impl Resolver {
+    /// Resolves `host` to the addresses of the allowed family it can be reached at on `port`,
+    /// failing with `AddrNotAvailable` if it only has addresses of the other family
+    pub fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        #[cfg(test)]
+        LOOKUPS.lock().unwrap().push(host.to_owned());
+        let addrs: Vec<_> = self
+            .resolve_any(host, port)?
+            .into_iter()
+            .filter(|addr| self.family.allows(addr.ip()))
+            .collect();
+        if addrs.is_empty() && self.family != Family::Any {
+            let family = if self.family == Family::V4 {
+                "ipv4"
+            } else {
+                "ipv6"
+            };
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("{host} resolved, but has no {family} addresses"),
+            ));
+        }
+        Ok(addrs)
+    }
+
+    /// Resolves `host` to every address it can be reached at on `port`
+    fn resolve_any(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        // literal addresses need no lookup, so they don't wait behind ones that do
+        let literal = host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(host);
+        if let Ok(ip) = literal.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        if let Some(ips) = self
+            .overrides
+            .get(&host.trim_end_matches('.').to_ascii_lowercase())
+        {
+            return Ok(ips.iter().map(|&ip| SocketAddr::new(ip, port)).collect());
+        }
+
+        let _permit = self.limit.as_ref().map(Semaphore::acquire);
+        Ok((host, port).to_socket_addrs()?.collect())
+    }
+
+    /// Adds an override given as `host=ip[,ip...]`, whose addresses are tried in order
+    pub fn add_override(&mut self, spec: &str) -> Result<(), String> {
+        let (host, ips) = spec
+            .split_once('=')
+            .ok_or(format!("Host override must be host=ip: {spec:?}"))?;
+        let ips = ips
+            .split(',')
+            .map(|ip| {
+                ip.trim()
+                    .parse()
+                    .map_err(|_| format!("Invalid address in host override: {ip}"))
+            })
+            .collect::<Result<Vec<IpAddr>, String>>()?;
+        self.overrides
+            .entry(host.trim().to_ascii_lowercase())
+            .or_default()
+            .extend(ips);
+        Ok(())
+    }
+
+    /// Adds a remapping given as `from-host=to-host[:port]`
+    pub fn add_remap(&mut self, spec: &str) -> Result<(), String> {
+        let (from, to) = spec.split_once('=').ok_or(format!(
+            "Host remapping must be from-host=to-host: {spec:?}"
+        ))?;
+        let (from, to) = (from.trim(), to.trim());
+        // a bracketed ipv6 address is only followed by a port after its closing bracket
+        let (host, port) = match to.rsplit_once(':') {
+            Some((host, port))
+                if !host.is_empty() && (!to.starts_with('[') || host.ends_with(']')) =>
+            {
+                let port = port
+                    .parse()
+                    .map_err(|_| format!("Invalid port in host remapping: {spec:?}"))?;
+                (host, Some(port))
+            }
+            _ => (to, None),
+        };
+        if from.is_empty() || host.is_empty() {
+            return Err(format!(
+                "Host remapping must be from-host=to-host: {spec:?}"
+            ));
+        }
+        self.remaps
+            .insert(from.to_ascii_lowercase(), (host.to_owned(), port));
+        Ok(())
+    }
+
+    /// The host and port to connect to for a request to `host` on `port`, which are the same
+    /// unless `host` is remapped
+    pub fn destination<'a>(&'a self, host: &'a str, port: u16) -> (&'a str, u16) {
+        match self
+            .remaps
+            .get(&host.trim_end_matches('.').to_ascii_lowercase())
+        {
+            Some((to, to_port)) => (to, to_port.unwrap_or(port)),
+            None => (host, port),
+        }
+    }
+
+    /// Adds the entries of a file in the `/etc/hosts` format, with an address followed by the
+    /// names it is for on each line
+    pub fn load_hosts_file(&mut self, path: &str) -> Result<(), String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read hosts file {path}: {e}"))?;
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(ip) = fields.next() else {
+                continue;
+            };
+            let ip: IpAddr = ip
+                .parse()
+                .map_err(|_| format!("Invalid address on line {} of {path}", number + 1))?;
+            for host in fields {
+                self.overrides
+                    .entry(host.to_ascii_lowercase())
+                    .or_default()
+                    .push(ip);
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolves an address given as `host:port`
+    pub fn resolve_authority(&self, authority: &str) -> io::Result<Vec<SocketAddr>> {
+        let (host, port) = authority
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "Address must be host:port")
+            })?;
+        self.resolve(host, port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolver() -> Resolver {
+        Resolver {
+            limit: None,
+            overrides: HashMap::new(),
+            remaps: HashMap::new(),
+            family: Family::Any,
+        }
+    }
+
+    fn ips(addrs: Vec<SocketAddr>) -> Vec<String> {
+        addrs.iter().map(SocketAddr::to_string).collect()
+    }
+
+    #[test]
+    fn literal_addresses_resolve_to_themselves() {
+        let resolver = resolver();
+        assert_eq!(
+            ips(resolver.resolve("10.0.0.1", 80).unwrap()),
+            ["10.0.0.1:80"]
+        );
+        assert_eq!(ips(resolver.resolve("[::1]", 443).unwrap()), ["[::1]:443"]);
+        assert_eq!(
+            ips(resolver.resolve_authority("127.0.0.1:8080").unwrap()),
+            ["127.0.0.1:8080"]
+        );
+        assert!(resolver.resolve_authority("127.0.0.1").is_err());
+    }
+
+    #[test]
+    fn overrides_are_used_in_order_regardless_of_case() {
+        let mut resolver = resolver();
+        resolver
+            .add_override("Example.COM=10.0.0.1, 10.0.0.2")
+            .unwrap();
+        resolver.add_override("example.com=::1").unwrap();
+        assert_eq!(
+            ips(resolver.resolve("EXAMPLE.com.", 80).unwrap()),
+            ["10.0.0.1:80", "10.0.0.2:80", "[::1]:80"]
+        );
+    }
+
+    #[test]
+    fn malformed_overrides_are_refused() {
+        let mut resolver = resolver();
+        assert!(resolver.add_override("example.com").is_err());
+        assert!(resolver.add_override("example.com=not-an-ip").is_err());
+        assert!(resolver.overrides.is_empty());
+    }
+
+    #[test]
+    fn hosts_file_entries_become_overrides() {
+        let path = std::env::temp_dir().join(format!("proxy_server-hosts-{}", std::process::id()));
+        fs::write(
+            &path,
+            "# comment\n10.0.0.1  one.test One-Alias.test\n\n::1 two.test # trailing comment\n",
+        )
+        .unwrap();
+        let mut resolver = resolver();
+        resolver.load_hosts_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            ips(resolver.resolve("one-alias.test", 80).unwrap()),
+            ["10.0.0.1:80"]
+        );
+        assert_eq!(ips(resolver.resolve("two.test", 80).unwrap()), ["[::1]:80"]);
+
+        fs::write(&path, "10.0.0.1 fine.test\nnonsense bad.test\n").unwrap();
+        let error = resolver
+            .load_hosts_file(path.to_str().unwrap())
+            .unwrap_err();
+        assert!(error.contains("line 2"), "{error}");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn remaps_replace_the_host_and_optionally_the_port() {
+        let mut resolver = resolver();
+        resolver.add_remap("Old.test=new.test").unwrap();
+        resolver.add_remap("api.test=backend.test:8080").unwrap();
+        resolver.add_remap("v6.test=[::1]:8443").unwrap();
+        resolver.add_remap("bare6.test=[::1]").unwrap();
+        assert_eq!(resolver.destination("old.TEST.", 80), ("new.test", 80));
+        assert_eq!(resolver.destination("api.test", 80), ("backend.test", 8080));
+        assert_eq!(resolver.destination("v6.test", 80), ("[::1]", 8443));
+        assert_eq!(resolver.destination("bare6.test", 80), ("[::1]", 80));
+        assert_eq!(resolver.destination("other.test", 80), ("other.test", 80));
+    }
+
+    #[test]
+    fn malformed_remaps_are_refused() {
+        let mut resolver = resolver();
+        assert!(resolver.add_remap("old.test").is_err());
+        assert!(resolver.add_remap("=new.test").is_err());
+        assert!(resolver.add_remap("old.test=").is_err());
+        assert!(resolver.add_remap("old.test=new.test:port").is_err());
+        assert!(resolver.remaps.is_empty());
+    }
+
+    #[test]
+    fn family_limits_the_addresses_hosts_resolve_to() {
+        let mut resolver = resolver();
+        resolver.add_override("dual.test=10.0.0.1,::1").unwrap();
+        resolver.add_override("v4only.test=10.0.0.2").unwrap();
+        resolver.family = Family::V6;
+        assert_eq!(
+            ips(resolver.resolve("dual.test", 80).unwrap()),
+            ["[::1]:80"]
+        );
+        assert_eq!(
+            resolver.resolve("v4only.test", 80).unwrap_err().kind(),
+            io::ErrorKind::AddrNotAvailable
+        );
+
+        resolver.family = Family::V4;
+        assert_eq!(
+            ips(resolver.resolve("dual.test", 80).unwrap()),
+            ["10.0.0.1:80"]
+        );
+        assert!(Family::parse("v5").is_err());
+    }
+}
@@ -0,0 +1,205 @@
+use crate::{log, stats};
+use std::{
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+/// Returns the soft limit on the number of files the process may have open, if there is one
+pub fn open_files_limit() -> Option<u64> {
+    #[cfg(unix)]
+    {
+        use crate::sys;
+
+        let mut limit = sys::Rlimit { current: 0, max: 0 };
+        // SAFETY: `limit` is a valid `struct rlimit` for the call to fill in
+        if unsafe { sys::getrlimit(sys::RLIMIT_NOFILE, &mut limit) } != 0
+            || limit.current == sys::RLIM_INFINITY
+        {
+            return None;
+        }
+        // a no-op where `rlim_t` is already 64 bits wide
+        #[allow(clippy::useless_conversion)]
+        let current = u64::from(limit.current);
+        Some(current)
+    }
+    #[cfg(not(unix))]
+    None
+}
+
+/// A lower bound on how many file descriptors the process holds: a client and a server socket for
+/// each active connection, plus the idle pooled connections. The listeners, log file, standard
+/// streams and sockets opened for name lookups aren't counted, so the true number is somewhat
+/// higher.
+pub fn estimated_open_files() -> u64 {
+    let active = stats::ACTIVE_CONNECTIONS.load(Ordering::SeqCst) as u64;
+    let pooled = stats::POOLED_CONNECTIONS.load(Ordering::SeqCst) as u64;
+    active * 2 + pooled
+}
+
+/// Returns how much of the process's memory is resident, in bytes, where the platform reports it
+pub fn resident_memory() -> Option<u64> {
+    // e.g. `VmRSS:     4512 kB`
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+/// Compares the open files against the limit on them, warning as usage nears it
+pub struct OpenFiles {
+    limit: Option<u64>,
+    /// share of the limit at which a warning is logged
+    warn_ratio: f64,
+    /// share of the limit at which new connections are refused, if any
+    reject_ratio: Option<f64>,
+    /// whether the warning has been given since usage was last below `warn_ratio`
+    warned: bool,
+}
+
+impl OpenFiles {
+    pub fn new(limit: Option<u64>, warn_ratio: f64, reject_ratio: Option<f64>) -> OpenFiles {
+        OpenFiles {
+            limit,
+            warn_ratio,
+            reject_ratio,
+            warned: false,
+        }
+    }
+
+    /// Notes that about `open` files are open, logging a warning each time usage rises to the
+    /// warning ratio, and returns whether new connections should be refused. There is nothing to
+    /// compare against without a limit.
+    pub fn exhausted(&mut self, open: u64) -> bool {
+        let Some(limit) = self.limit else {
+            return false;
+        };
+        let usage = open as f64 / limit as f64;
+        let near = usage >= self.warn_ratio;
+        if near && !self.warned {
+            log::write_line(&format!(
+                "Warning: connections hold about {open} of the {limit} open files allowed"
+            ));
+        }
+        self.warned = near;
+        // stopping short of the limit keeps accepts from failing with EMFILE over and over
+        self.reject_ratio.is_some_and(|ratio| usage >= ratio)
+    }
+}
+
+/// Holds off new connections while resident memory is over a bound, as read by `read` at most
+/// once per `interval`, so a burst that leaves memory high isn't met with yet more connections
+pub struct MemoryBound<R> {
+    max: u64,
+    interval: Duration,
+    read: R,
+    /// when memory was last read
+    checked: Option<Instant>,
+    /// whether it was over the bound then
+    exceeded: bool,
+}
+
+impl<R: FnMut() -> Option<u64>> MemoryBound<R> {
+    pub fn new(max: u64, interval: Duration, read: R) -> MemoryBound<R> {
+        MemoryBound {
+            max,
+            interval,
+            read,
+            checked: None,
+            exceeded: false,
+        }
+    }
+
+    /// Whether memory was over the bound when last read, reading it again first if `interval`
+    /// has passed since, and logging when it crosses the bound in either direction. Memory that
+    /// can't be read is taken to be under the bound.
+    pub fn exceeded(&mut self) -> bool {
+        if self
+            .checked
+            .is_some_and(|checked| checked.elapsed() < self.interval)
+        {
+            return self.exceeded;
+        }
+        self.checked = Some(Instant::now());
+        let resident = (self.read)();
+        let exceeded = resident.is_some_and(|resident| resident >= self.max);
+        match (self.exceeded, exceeded) {
+            (false, true) => log::write_line(&format!(
+                "Warning: resident memory is {} MB, over the {} MB allowed; not accepting \
+                 connections until it falls",
+                resident.unwrap_or_default() / (1024 * 1024),
+                self.max / (1024 * 1024)
+            )),
+            (true, false) => log::write_line("Resident memory has fallen; accepting connections"),
+            _ => {}
+        }
+        self.exceeded = exceeded;
+        exceeded
+    }
+}
+
+/// Parses the value of the option `name` as a fraction greater than 0 and at most 1
+pub fn parse_ratio(name: &str, value: &str) -> Result<f64, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|ratio| *ratio > 0.0 && *ratio <= 1.0)
+        .ok_or(format!(
+            "{name} must be a number greater than 0 and at most 1"
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    const MB: u64 = 1024 * 1024;
+
+    #[test]
+    fn open_files_warn_once_each_time_usage_nears_the_limit() {
+        let mut open_files = OpenFiles::new(Some(100), 0.8, Some(0.95));
+        assert!(!open_files.exhausted(79));
+        assert!(!open_files.warned);
+        assert!(!open_files.exhausted(80));
+        assert!(open_files.warned);
+        assert!(!open_files.exhausted(90));
+        assert!(open_files.exhausted(95));
+        assert!(!open_files.exhausted(10));
+        assert!(!open_files.warned);
+    }
+
+    #[test]
+    fn open_files_without_a_limit_are_never_exhausted() {
+        let mut open_files = OpenFiles::new(None, 0.1, Some(0.1));
+        assert!(!open_files.exhausted(u64::MAX));
+        assert!(!open_files.warned);
+        assert!(!OpenFiles::new(Some(100), 0.8, None).exhausted(100));
+    }
+
+    #[test]
+    fn memory_bound_follows_memory_across_the_bound_both_ways() {
+        let resident = Cell::new(Some(10 * MB));
+        let mut bound = MemoryBound::new(64 * MB, Duration::ZERO, || resident.get());
+        assert!(!bound.exceeded());
+        resident.set(Some(64 * MB));
+        assert!(bound.exceeded());
+        resident.set(Some(100 * MB));
+        assert!(bound.exceeded());
+        resident.set(Some(63 * MB));
+        assert!(!bound.exceeded());
+        resident.set(None);
+        assert!(!bound.exceeded());
+    }
+
+    #[test]
+    fn memory_bound_reads_memory_once_per_interval() {
+        let reads = Cell::new(0);
+        let mut bound = MemoryBound::new(MB, Duration::from_secs(60), || {
+            reads.set(reads.get() + 1);
+            Some(2 * MB)
+        });
+        assert!(bound.exceeded());
+        assert!(bound.exceeded());
+        assert_eq!(reads.get(), 1);
+    }
+}
@@ -0,0 +1,295 @@
+use crate::config::{parse_seconds, Timeouts};
+
+/// How a plain HTTP request is handled on its way to the server
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// rewrite the request head before forwarding it
+    Forward,
+    /// pass the client's bytes to the server untouched
+    Tunnel,
+}
+
+impl Mode {
+    pub fn parse(mode: &str) -> Result<Mode, String> {
+        match mode {
+            "forward" => Ok(Mode::Forward),
+            "tunnel" => Ok(Mode::Tunnel),
+            _ => Err(format!("Unknown mode: {mode}")),
+        }
+    }
+}
+
+/// A host name to match against: either exact, `*.domain` for any subdomain, or `*` for any host
+pub struct HostPattern(String);
+
+impl HostPattern {
+    pub fn parse(pattern: &str) -> Result<HostPattern, String> {
+        let domain = pattern.strip_prefix("*.").unwrap_or(pattern);
+        if pattern != "*" && (domain.is_empty() || domain.contains('*')) {
+            return Err(format!("Invalid host pattern: {pattern}"));
+        }
+        Ok(HostPattern(pattern.to_ascii_lowercase()))
+    }
+
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        match self.0.strip_prefix('*') {
+            Some("") => true,
+            // keep the leading dot so `*.example.com` doesn't match `badexample.com`
+            Some(suffix) => host.ends_with(suffix),
+            None => host == self.0,
+        }
+    }
+}
+
+/// Maps request paths matching `from`, where each `*` matches any run of characters, to `to`,
+/// where `$1` to `$9` stand for what the wildcards matched, e.g. `/old/*` to `/new/$1`
+pub struct Rewrite {
+    from: String,
+    to: String,
+}
+
+impl Rewrite {
+    /// Parses a rewrite given as `FROM=TO`
+    pub fn parse(spec: &str) -> Result<Rewrite, String> {
+        let (from, to) = spec
+            .split_once('=')
+            .ok_or(format!("Route rewrite must be FROM=TO: {spec:?}"))?;
+        if !from.starts_with('/') || !to.starts_with('/') {
+            return Err(format!("Route rewrite must map a path to a path: {spec:?}"));
+        }
+        let wildcards = from.matches('*').count();
+        let mut refs = to.split('$').skip(1);
+        if refs.any(|rest| {
+            rest.chars()
+                .next()
+                .and_then(|digit| digit.to_digit(10))
+                .is_some_and(|n| n == 0 || n as usize > wildcards)
+        }) {
+            return Err(format!(
+                "Route rewrite refers to a wildcard its pattern doesn't have: {spec:?}"
+            ));
+        }
+        Ok(Rewrite {
+            from: from.to_owned(),
+            to: to.to_owned(),
+        })
+    }
+
+    /// Returns what `path` is rewritten to, or `None` if it doesn't match
+    pub fn apply(&self, path: &str) -> Option<String> {
+        let mut literals = self.from.split('*');
+        let mut rest = path.strip_prefix(literals.next().unwrap_or_default())?;
+        let literals: Vec<_> = literals.collect();
+        if literals.is_empty() && !rest.is_empty() {
+            return None;
+        }
+
+        let mut captured = Vec::new();
+        for (i, literal) in literals.iter().enumerate() {
+            if i == literals.len() - 1 {
+                // the last wildcard takes everything up to the suffix ending the pattern
+                captured.push(rest.strip_suffix(literal)?);
+            } else {
+                let at = rest.find(literal)?;
+                captured.push(&rest[..at]);
+                rest = &rest[at + literal.len()..];
+            }
+        }
+
+        let mut parts = self.to.split('$');
+        let mut rewritten = parts.next().unwrap_or_default().to_owned();
+        for part in parts {
+            match part.chars().next().and_then(|digit| digit.to_digit(10)) {
+                Some(n) => {
+                    rewritten.push_str(captured[n as usize - 1]);
+                    rewritten.push_str(&part[1..]);
+                }
+                None => {
+                    rewritten.push('$');
+                    rewritten.push_str(part);
+                }
+            }
+        }
+        Some(rewritten)
+    }
+}
+
+/// Handling settings for connections to the hosts matching `host`. Everything but `mode` applies
+/// to `CONNECT` tunnels as well as plain http requests.
+pub struct Route {
+    /// identifies the route in the access log and the connection listing, the host pattern
+    /// unless given a name
+    pub name: String,
+    pub host: HostPattern,
+    pub mode: Mode,
+    /// timeouts replacing the global ones for these hosts
+    pub timeouts: Timeouts,
+    /// path rewrites for forwarded requests, the first that matches applying
+    pub rewrites: Vec<Rewrite>,
+}
+
+impl Route {
+    /// Parses a route given as comma-separated `key=value` settings,
+    /// e.g. `host=*.example.com,mode=tunnel`
+    pub fn parse(spec: &str) -> Result<Route, String> {
+        let mut name = None;
+        let mut host = None;
+        let mut mode = Mode::Forward;
+        let mut timeouts = Timeouts::default();
+        let mut rewrites = Vec::new();
+
+        for setting in spec.split(',') {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or(format!("Route setting must be key=value: {setting:?}"))?;
+            let value = value.trim();
+            match key.trim() {
+                "name" if value.is_empty() => return Err("Route name must not be empty".to_owned()),
+                "name" => name = Some(value.to_owned()),
+                "host" => host = Some(HostPattern::parse(value)?),
+                "mode" => mode = Mode::parse(value)?,
+                "connect-timeout" => {
+                    timeouts.connect = Some(parse_seconds("connect-timeout", value)?)
+                }
+                "idle-timeout" => timeouts.idle = Some(parse_seconds("idle-timeout", value)?),
+                "tunnel-idle-timeout" => {
+                    timeouts.tunnel_idle = Some(parse_seconds("tunnel-idle-timeout", value)?)
+                }
+                "response-timeout" => {
+                    timeouts.response = Some(parse_seconds("response-timeout", value)?)
+                }
+                "max-duration" => timeouts.duration = Some(parse_seconds("max-duration", value)?),
+                "max-tunnel-duration" => {
+                    timeouts.tunnel_duration = Some(parse_seconds("max-tunnel-duration", value)?)
+                }
+                "rewrite" => rewrites.push(Rewrite::parse(value)?),
+                key => return Err(format!("Unknown route setting: {key}")),
+            }
+        }
+
+        let host: HostPattern = host.ok_or(format!("Route is missing a host: {spec:?}"))?;
+        // a tunneled request's head goes to the server as the client sent it
+        if mode == Mode::Tunnel && !rewrites.is_empty() {
+            return Err(format!("Route rewrites need mode=forward: {spec:?}"));
+        }
+        Ok(Route {
+            name: name.unwrap_or_else(|| host.0.clone()),
+            host,
+            mode,
+            timeouts,
+            rewrites,
+        })
+    }
+
+    /// Returns what `path` is rewritten to by the first of the route's rewrites that matches it
+    pub fn rewrite(&self, path: &str) -> Option<String> {
+        self.rewrites.iter().find_map(|rewrite| rewrite.apply(path))
+    }
+}
+
+/// Returns the first route in `routes` that matches `host`
+pub fn find<'a>(routes: &'a [Route], host: &str) -> Option<&'a Route> {
+    routes.iter().find(|route| route.host.matches(host))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn host_patterns_match_exactly_by_subdomain_or_anything() {
+        let exact = HostPattern::parse("Example.com").unwrap();
+        assert!(exact.matches("example.COM"));
+        assert!(!exact.matches("www.example.com"));
+
+        let subdomains = HostPattern::parse("*.example.com").unwrap();
+        assert!(subdomains.matches("www.example.com"));
+        assert!(subdomains.matches("a.b.example.com"));
+        assert!(!subdomains.matches("example.com"));
+        assert!(!subdomains.matches("badexample.com"));
+
+        assert!(HostPattern::parse("*").unwrap().matches("anything"));
+        for invalid in ["", "*.", "www.*.com", "**"] {
+            assert!(HostPattern::parse(invalid).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn rewrites_substitute_what_the_wildcards_matched() {
+        let rewrite = |spec, path| Rewrite::parse(spec).unwrap().apply(path);
+        assert_eq!(
+            rewrite("/old/*=/new/$1", "/old/a/b?c"),
+            Some("/new/a/b?c".to_owned())
+        );
+        assert_eq!(rewrite("/old/*=/new/$1", "/other/a"), None);
+        assert_eq!(
+            rewrite("/exact=/other", "/exact"),
+            Some("/other".to_owned())
+        );
+        assert_eq!(rewrite("/exact=/other", "/exact/more"), None);
+        assert_eq!(
+            rewrite("/*/items/*.json=/v2/$2/$1", "/shop/items/42.json"),
+            Some("/v2/42/shop".to_owned())
+        );
+        assert_eq!(
+            rewrite("/a/*=/cost/$$1", "/a/5"),
+            Some("/cost/$5".to_owned())
+        );
+    }
+
+    #[test]
+    fn rewrites_must_map_paths_and_refer_to_existing_wildcards() {
+        for invalid in ["/a", "a=/b", "/a=b", "/a/*=/b/$2", "/a/*=/b/$0"] {
+            assert!(Rewrite::parse(invalid).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn route_settings_are_parsed() {
+        let route = Route::parse(
+            "host=*.example.com,name=shop,mode=forward,connect-timeout=3,response-timeout=10,\
+             rewrite=/old/*=/new/$1,rewrite=/*=/root/$1",
+        )
+        .unwrap();
+        assert_eq!(route.name, "shop");
+        assert!(route.mode == Mode::Forward);
+        assert_eq!(route.timeouts.connect, Some(Duration::from_secs(3)));
+        assert_eq!(route.timeouts.response, Some(Duration::from_secs(10)));
+        assert_eq!(route.timeouts.idle, None);
+        assert_eq!(route.rewrite("/old/x"), Some("/new/x".to_owned()));
+        assert_eq!(route.rewrite("/x"), Some("/root/x".to_owned()));
+
+        let unnamed = Route::parse("host=Example.com,mode=tunnel").unwrap();
+        assert_eq!(unnamed.name, "example.com");
+        assert!(unnamed.mode == Mode::Tunnel);
+    }
+
+    #[test]
+    fn invalid_routes_are_rejected() {
+        for invalid in [
+            "mode=tunnel",
+            "host=a.com,mode=teleport",
+            "host=a.com,idle-timeout=soon",
+            "host=a.com,colour=blue",
+            "host=a.com,name=",
+            "host=a.com,mode=tunnel,rewrite=/a=/b",
+            "host=a.com,tunnel",
+        ] {
+            assert!(Route::parse(invalid).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn find_returns_the_first_matching_route() {
+        let routes = [
+            Route::parse("host=api.example.com,name=api").unwrap(),
+            Route::parse("host=*.example.com,name=rest").unwrap(),
+        ];
+        let name = |host| find(&routes, host).map(|route| route.name.as_str());
+        assert_eq!(name("api.example.com"), Some("api"));
+        assert_eq!(name("www.example.com"), Some("rest"));
+        assert_eq!(name("example.org"), None);
+    }
+}
@@ -0,0 +1,137 @@
+use std::{
+    io,
+    net::{SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+/// Local ports outgoing connections are made from
+struct PortRange {
+    start: u16,
+    end: u16,
+    /// where the search for a free port starts next, so consecutive connections don't all
+    /// contend for the first ports in the range
+    next: AtomicU16,
+}
+
+/// The range outgoing connections bind to, once set at startup
+static PORT_RANGE: OnceLock<PortRange> = OnceLock::new();
+
+/// Parses a port range given as `start-end`, both inclusive
+pub fn parse_range(range: &str) -> Result<(u16, u16), String> {
+    let invalid = || format!("Source port range must be start-end: {range:?}");
+    let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+    let start: u16 = start.trim().parse().map_err(|_| invalid())?;
+    let end: u16 = end.trim().parse().map_err(|_| invalid())?;
+    if start == 0 || start > end {
+        return Err(format!(
+            "Source port range must run from a nonzero port to one no lower: {range:?}"
+        ));
+    }
+    Ok((start, end))
+}
+
+/// Makes every later outgoing connection bind to a local port from `start` to `end`
+pub fn restrict_ports(start: u16, end: u16) {
+    let _ = PORT_RANGE.set(PortRange {
+        start,
+        end,
+        next: AtomicU16::new(start),
+    });
+}
+
+/// Connects to `addr` from a free port in the range set with `restrict_ports`, or returns `None`
+/// if no range was set
+pub fn connect(addr: SocketAddr, timeout: Option<Duration>) -> Option<io::Result<TcpStream>> {
+    let range = PORT_RANGE.get()?;
+    let ports = usize::from(range.end - range.start) + 1;
+    let first = range.next.fetch_add(1, Ordering::Relaxed);
+    for offset in 0..ports {
+        // wrapping keeps the search inside the range whatever the counter has reached
+        let port =
+            range.start + ((usize::from(first.wrapping_sub(range.start)) + offset) % ports) as u16;
+        match connect_from(port, addr, timeout) {
+            Err(e) if port_taken(&e) => continue,
+            result => return Some(result),
+        }
+    }
+    Some(Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!(
+            "Every source port from {} to {} is in use",
+            range.start, range.end
+        ),
+    )))
+}
+
+/// Whether connecting failed only because the local port is in use, so another should be tried
+fn port_taken(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable
+    )
+}
+
+/// Opens a connection to `addr` from local port `port`.
+///
+/// The standard library can't bind a socket before connecting it, so this makes the calls itself.
+/// Linux gives up on a blocking connect once the socket's send timeout passes, which is how
+/// `timeout` is applied.
+#[cfg(target_os = "linux")]
+fn connect_from(port: u16, addr: SocketAddr, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    use crate::sys::{self, with_sockaddr};
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr},
+        os::unix::io::{AsRawFd, FromRawFd},
+    };
+
+    let (domain, local) = match addr {
+        SocketAddr::V4(_) => (
+            sys::AF_INET,
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)),
+        ),
+        SocketAddr::V6(_) => (
+            sys::AF_INET6,
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
+        ),
+    };
+    // SAFETY: `socket` takes no pointers
+    let fd = unsafe { sys::socket(domain, sys::SOCK_STREAM | sys::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` is a newly opened socket owned by nothing else, which the stream now closes
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+    let fd = stream.as_raw_fd();
+
+    // SAFETY: the address points to a sockaddr of the given length that outlives the call
+    with_sockaddr(local, |sockaddr, len| unsafe {
+        sys::bind(fd, sockaddr, len)
+    })?;
+    stream.set_write_timeout(timeout)?;
+    // SAFETY: as for `bind`
+    with_sockaddr(addr, |sockaddr, len| unsafe {
+        sys::connect(fd, sockaddr, len)
+    })
+    .map_err(|e| match e.raw_os_error() {
+        Some(sys::EINPROGRESS) => io::Error::from(io::ErrorKind::TimedOut),
+        _ => e,
+    })?;
+    stream.set_write_timeout(None)?;
+    Ok(stream)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn connect_from(
+    _port: u16,
+    _addr: SocketAddr,
+    _timeout: Option<Duration>,
+) -> io::Result<TcpStream> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Source port ranges are only supported on Linux",
+    ))
+}
@@ -0,0 +1,240 @@
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fmt::Write,
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+/// Most connections kept in the registry of active connections; connections beyond it are still
+/// handled and counted, but not listed
+const MAX_LISTED_CONNECTIONS: usize = 1024;
+
+/// Number of connections currently being handled
+pub static ACTIVE_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of client connections accepted since the proxy started
+pub static TOTAL_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of bytes forwarded between clients and servers, in both directions
+pub static BYTES_FORWARDED: AtomicU64 = AtomicU64::new(0);
+
+/// Number of connections turned away because the proxy was handling its maximum
+pub static OVERFLOW_REJECTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of connections closed unread because their source address is not allowed
+pub static SOURCE_REJECTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of idle server connections waiting in the pool
+pub static POOLED_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of free relay buffers waiting in the buffer pool
+pub static POOLED_BUFFERS: AtomicUsize = AtomicUsize::new(0);
+
+/// A statistic as reported by the stats server and logger
+pub struct Counter {
+    pub name: &'static str,
+    pub value: u64,
+    /// whether the value only ever grows, rather than being a current level
+    pub cumulative: bool,
+}
+
+/// The current value of every statistic
+pub fn counters() -> [Counter; 7] {
+    let counter = |name, value, cumulative| Counter {
+        name,
+        value,
+        cumulative,
+    };
+    [
+        counter(
+            "total_connections",
+            TOTAL_CONNECTIONS.load(Ordering::SeqCst),
+            true,
+        ),
+        counter(
+            "active_connections",
+            ACTIVE_CONNECTIONS.load(Ordering::SeqCst) as u64,
+            false,
+        ),
+        counter(
+            "bytes_forwarded",
+            BYTES_FORWARDED.load(Ordering::Relaxed),
+            true,
+        ),
+        counter(
+            "overflow_rejections",
+            OVERFLOW_REJECTIONS.load(Ordering::SeqCst),
+            true,
+        ),
+        counter(
+            "source_rejections",
+            SOURCE_REJECTIONS.load(Ordering::SeqCst),
+            true,
+        ),
+        counter(
+            "pooled_connections",
+            POOLED_CONNECTIONS.load(Ordering::SeqCst) as u64,
+            false,
+        ),
+        counter(
+            "pooled_buffers",
+            POOLED_BUFFERS.load(Ordering::SeqCst) as u64,
+            false,
+        ),
+    ]
+}
+
+/// What is known about a connection being handled
+struct ConnectionInfo {
+    id: u64,
+    client: SocketAddr,
+    started: Instant,
+    /// method, host, and matched route of the request being handled, once one has been read
+    request: Mutex<Option<(String, String, Option<String>)>>,
+    /// bytes forwarded in either direction so far
+    bytes: AtomicU64,
+    /// handles to the client connection and the server connection last used for it, shut down
+    /// to close the connection from the stats server
+    client_stream: Option<TcpStream>,
+    server_stream: Mutex<Option<TcpStream>>,
+    /// set once the connection has been asked to close
+    closing: AtomicBool,
+}
+
+/// Active connections by id, in the order they were accepted
+static CONNECTIONS: Mutex<BTreeMap<u64, Arc<ConnectionInfo>>> = Mutex::new(BTreeMap::new());
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    /// The connection handled by the current thread, which requests and transfers are recorded
+    /// against
+    static CURRENT: RefCell<Option<Arc<ConnectionInfo>>> = const { RefCell::new(None) };
+}
+
+/// Counts a connection in `ACTIVE_CONNECTIONS`, and lists it among the active connections, for as
+/// long as it is held
+pub struct ActiveConnection(Arc<ConnectionInfo>);
+
+impl ActiveConnection {
+    pub fn start(stream: &TcpStream, client: SocketAddr) -> ActiveConnection {
+        ACTIVE_CONNECTIONS.fetch_add(1, Ordering::SeqCst);
+        TOTAL_CONNECTIONS.fetch_add(1, Ordering::SeqCst);
+        let info = Arc::new(ConnectionInfo {
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::SeqCst),
+            client,
+            started: Instant::now(),
+            request: Mutex::new(None),
+            bytes: AtomicU64::new(0),
+            client_stream: stream.try_clone().ok(),
+            server_stream: Mutex::new(None),
+            closing: AtomicBool::new(false),
+        });
+        let mut connections = CONNECTIONS.lock().unwrap();
+        if connections.len() < MAX_LISTED_CONNECTIONS {
+            connections.insert(info.id, Arc::clone(&info));
+        }
+        ActiveConnection(info)
+    }
+
+    /// Makes this the connection that the calling thread's requests and transfers are recorded
+    /// against
+    pub fn enter(&self) {
+        CURRENT.with(|current| *current.borrow_mut() = Some(Arc::clone(&self.0)));
+    }
+
+    /// Whether the connection has been asked to close from the stats server
+    pub fn closing(&self) -> bool {
+        self.0.closing.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+        CONNECTIONS.lock().unwrap().remove(&self.0.id);
+    }
+}
+
+/// Records that the current thread's connection is handling a `method` request to `host`, which
+/// matched the route named `route`
+pub fn record_request(method: &str, host: &str, route: Option<&str>) {
+    CURRENT.with(|current| {
+        if let Some(info) = &*current.borrow() {
+            *info.request.lock().unwrap() =
+                Some((method.to_owned(), host.to_owned(), route.map(str::to_owned)));
+        }
+    });
+}
+
+/// Records that the current thread's connection is using `stream` to reach a server, so that
+/// closing the connection closes it too
+pub fn record_server(stream: &TcpStream) {
+    CURRENT.with(|current| {
+        if let Some(info) = &*current.borrow() {
+            *info.server_stream.lock().unwrap() = stream.try_clone().ok();
+        }
+    });
+}
+
+/// Closes the active connection with id `id` by shutting down its client and server sockets,
+/// which ends whatever is reading or writing them. Returns whether there was such a connection.
+pub fn close(id: u64) -> bool {
+    let Some(info) = CONNECTIONS.lock().unwrap().get(&id).cloned() else {
+        return false;
+    };
+    info.closing.store(true, Ordering::SeqCst);
+    if let Some(stream) = &info.client_stream {
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+    if let Some(stream) = &*info.server_stream.lock().unwrap() {
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+    true
+}
+
+/// Adds `bytes` forwarded by the current thread's connection to its total
+pub fn record_transfer(bytes: usize) {
+    BYTES_FORWARDED.fetch_add(bytes as u64, Ordering::Relaxed);
+    CURRENT.with(|current| {
+        if let Some(info) = &*current.borrow() {
+            info.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    });
+}
+
+/// The active connections, one line each giving its id, client address, current request and the
+/// route it matched, bytes forwarded, and age in seconds, followed by `closing` once it has been
+/// asked to close
+pub fn connections() -> String {
+    let mut listing = String::new();
+    for info in CONNECTIONS.lock().unwrap().values() {
+        let request = info.request.lock().unwrap();
+        let (method, host, route) = request.as_ref().map_or(("-", "-", "-"), |request| {
+            (
+                &*request.0,
+                &*request.1,
+                request.2.as_deref().unwrap_or("-"),
+            )
+        });
+        let _ = writeln!(
+            listing,
+            "id={} client={} method={method} host={host} route={route} bytes={} age={:.1}{}",
+            info.id,
+            info.client,
+            info.bytes.load(Ordering::Relaxed),
+            info.started.elapsed().as_secs_f64(),
+            if info.closing.load(Ordering::SeqCst) {
+                " closing"
+            } else {
+                ""
+            },
+        );
+    }
+    listing
+}
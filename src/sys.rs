@@ -0,0 +1,175 @@
+use std::os::raw::{c_int, c_void};
+#[cfg(target_os = "linux")]
+use std::{io, net::SocketAddr};
+
+pub const SIGHUP: c_int = 1;
+pub const SIGINT: c_int = 2;
+pub const SIGTERM: c_int = 15;
+
+#[cfg(target_os = "linux")]
+pub const SOL_SOCKET: c_int = 1;
+#[cfg(target_os = "linux")]
+pub const SO_REUSEADDR: c_int = 2;
+#[cfg(target_os = "linux")]
+pub const SO_LINGER: c_int = 13;
+#[cfg(not(target_os = "linux"))]
+pub const SOL_SOCKET: c_int = 0xffff;
+#[cfg(not(target_os = "linux"))]
+pub const SO_LINGER: c_int = 0x80;
+
+#[cfg(target_os = "linux")]
+pub const RLIMIT_NOFILE: c_int = 7;
+#[cfg(not(target_os = "linux"))]
+pub const RLIMIT_NOFILE: c_int = 8;
+
+#[cfg(target_os = "linux")]
+pub const AF_INET: c_int = 2;
+#[cfg(target_os = "linux")]
+pub const AF_INET6: c_int = 10;
+#[cfg(target_os = "linux")]
+pub const SOCK_STREAM: c_int = 1;
+#[cfg(target_os = "linux")]
+pub const SOCK_CLOEXEC: c_int = 0o2000000;
+
+#[cfg(target_os = "linux")]
+pub const EINPROGRESS: i32 = 115;
+/// the process has as many files open as it may
+#[cfg(target_os = "linux")]
+pub const EMFILE: i32 = 24;
+/// the system has as many files open as it may
+#[cfg(target_os = "linux")]
+pub const ENFILE: i32 = 23;
+
+/// `rlim_t`, which is as wide as a `long` on Linux
+#[cfg(target_os = "linux")]
+pub type RlimT = std::os::raw::c_ulong;
+#[cfg(not(target_os = "linux"))]
+pub type RlimT = u64;
+
+#[cfg(target_os = "linux")]
+pub const RLIM_INFINITY: RlimT = RlimT::MAX;
+#[cfg(not(target_os = "linux"))]
+pub const RLIM_INFINITY: RlimT = i64::MAX as RlimT;
+
+#[repr(C)]
+pub struct Rlimit {
+    pub current: RlimT,
+    pub max: RlimT,
+}
+
+#[repr(C)]
+pub struct Linger {
+    pub onoff: c_int,
+    /// in seconds
+    pub linger: c_int,
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+pub struct SockaddrIn {
+    pub family: u16,
+    /// in network byte order, like the address
+    pub port: u16,
+    pub addr: [u8; 4],
+    pub zero: [u8; 8],
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+pub struct SockaddrIn6 {
+    pub family: u16,
+    pub port: u16,
+    pub flowinfo: u32,
+    pub addr: [u8; 16],
+    pub scope_id: u32,
+}
+
+extern "C" {
+    pub fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+    pub fn setsockopt(
+        socket: c_int,
+        level: c_int,
+        name: c_int,
+        value: *const c_void,
+        len: u32,
+    ) -> c_int;
+    pub fn getrlimit(resource: c_int, rlimit: *mut Rlimit) -> c_int;
+}
+
+// only the tests read socket options back
+#[cfg(test)]
+extern "C" {
+    pub fn getsockopt(
+        socket: c_int,
+        level: c_int,
+        name: c_int,
+        value: *mut c_void,
+        len: *mut u32,
+    ) -> c_int;
+}
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    pub fn socket(domain: c_int, kind: c_int, protocol: c_int) -> c_int;
+    pub fn bind(socket: c_int, addr: *const c_void, len: u32) -> c_int;
+    pub fn connect(socket: c_int, addr: *const c_void, len: u32) -> c_int;
+    pub fn listen(socket: c_int, backlog: c_int) -> c_int;
+}
+
+/// Calls the socket function `call` with `addr` in its C form
+#[cfg(target_os = "linux")]
+pub fn with_sockaddr(
+    addr: SocketAddr,
+    call: impl FnOnce(*const c_void, u32) -> c_int,
+) -> io::Result<()> {
+    let result = match addr {
+        SocketAddr::V4(addr) => {
+            let sockaddr = SockaddrIn {
+                family: AF_INET as u16,
+                port: addr.port().to_be(),
+                addr: addr.ip().octets(),
+                zero: [0; 8],
+            };
+            call(
+                (&sockaddr as *const SockaddrIn).cast(),
+                size_of::<SockaddrIn>() as u32,
+            )
+        }
+        SocketAddr::V6(addr) => {
+            let sockaddr = SockaddrIn6 {
+                family: AF_INET6 as u16,
+                port: addr.port().to_be(),
+                flowinfo: addr.flowinfo(),
+                addr: addr.ip().octets(),
+                scope_id: addr.scope_id(),
+            };
+            call(
+                (&sockaddr as *const SockaddrIn6).cast(),
+                size_of::<SockaddrIn6>() as u32,
+            )
+        }
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Sets the integer socket option `name` at `level` on `socket` to `value`
+#[cfg(target_os = "linux")]
+pub fn set_int_option(socket: c_int, level: c_int, name: c_int, value: c_int) -> io::Result<()> {
+    // SAFETY: the value points to an int of the given length that outlives the call
+    let result = unsafe {
+        setsockopt(
+            socket,
+            level,
+            name,
+            (&value as *const c_int).cast(),
+            size_of::<c_int>() as u32,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,127 @@
+use crate::{log, stats};
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    net::{SocketAddr, TcpStream},
+    sync::mpsc::{self, SyncSender},
+    thread,
+};
+
+/// Most chunks of traffic waiting to be written to the tee sink before new ones are dropped
+const TEE_QUEUE_LENGTH: usize = 1024;
+
+/// Which side of a connection data was sent by
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// from the client to the server
+    Request,
+    /// from the server to the client
+    Response,
+}
+
+/// Which traffic is copied to the tee sink
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TeeDirection {
+    /// only what clients send to servers
+    Requests,
+    /// traffic in both directions
+    Both,
+}
+
+impl TeeDirection {
+    pub fn parse(direction: &str) -> Result<TeeDirection, String> {
+        match direction {
+            "request" => Ok(TeeDirection::Requests),
+            "both" => Ok(TeeDirection::Both),
+            _ => Err(format!("Unknown tee direction: {direction}")),
+        }
+    }
+}
+
+/// A copy of proxied traffic sent to a file or TCP endpoint, for debugging and capture.
+///
+/// Data from every connection is written in the order it is forwarded, undelimited. Writing
+/// happens on a thread of its own, so a slow sink only loses data rather than holding up the
+/// connections being copied, and a failing sink is dropped.
+pub struct Tee {
+    sender: SyncSender<Vec<u8>>,
+    direction: TeeDirection,
+}
+
+impl Tee {
+    /// Opens the sink at `target`, which is a `host:port` socket address to connect to or
+    /// otherwise a file path to append to
+    pub fn open(target: &str, direction: TeeDirection) -> Result<Tee, String> {
+        let mut sink: Box<dyn Write + Send> = match target.parse::<SocketAddr>() {
+            Ok(addr) => Box::new(
+                TcpStream::connect(addr)
+                    .map_err(|e| format!("Could not connect to tee sink {target}: {e}"))?,
+            ),
+            Err(_) => Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(target)
+                    .map_err(|e| format!("Could not open tee file {target}: {e}"))?,
+            ),
+        };
+
+        let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(TEE_QUEUE_LENGTH);
+        let target = target.to_owned();
+        thread::spawn(move || {
+            for data in receiver {
+                if let Err(e) = sink.write_all(&data) {
+                    // dropping the receiver makes every later copy a no-op
+                    log::write_line(&format!("Stopped teeing traffic to {target}: {e}"));
+                    return;
+                }
+            }
+        });
+
+        Ok(Tee { sender, direction })
+    }
+
+    /// Copies `data` sent in `direction` to the sink, unless it is not wanted or the sink is
+    /// falling behind
+    pub fn copy(&self, direction: Direction, data: &[u8]) {
+        if data.is_empty()
+            || (direction == Direction::Response && self.direction == TeeDirection::Requests)
+        {
+            return;
+        }
+        let _ = self.sender.try_send(data.to_vec());
+    }
+}
+
+/// A writer that copies whatever is written through it to a tee, if there is one, and counts it
+/// as forwarded by the current connection
+pub struct Teed<'a, W> {
+    inner: W,
+    tee: Option<&'a Tee>,
+    direction: Direction,
+}
+
+impl<'a, W: Write> Teed<'a, W> {
+    pub fn new(inner: W, tee: Option<&'a Tee>, direction: Direction) -> Teed<'a, W> {
+        Teed {
+            inner,
+            tee,
+            direction,
+        }
+    }
+}
+
+impl<W: Write> Write for Teed<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        stats::record_transfer(written);
+        if let Some(tee) = self.tee {
+            tee.copy(self.direction, &buf[..written]);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
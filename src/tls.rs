@@ -0,0 +1,299 @@
+use crate::log;
+
+/// Content type of a TLS record carrying handshake messages
+const HANDSHAKE: u8 = 0x16;
+
+/// Handshake message type of a ClientHello
+const CLIENT_HELLO: u8 = 1;
+
+const SERVER_NAME_EXTENSION: u16 = 0;
+const ALPN_EXTENSION: u16 = 16;
+const SUPPORTED_VERSIONS_EXTENSION: u16 = 43;
+
+/// Returns how many bytes the first TLS record at the start of `bytes` takes up, counting only its
+/// header while that hasn't all arrived, or `None` if `bytes` doesn't start a handshake record
+pub fn first_record_len(bytes: &[u8]) -> Option<usize> {
+    match bytes {
+        [] => Some(1),
+        [HANDSHAKE, _, _, high, low, ..] => {
+            Some(5 + usize::from(u16::from_be_bytes([*high, *low])))
+        }
+        [HANDSHAKE, ..] => Some(5),
+        _ => None,
+    }
+}
+
+/// What a client offered in the ClientHello that opens its TLS session
+pub struct ClientHello {
+    /// host name from the server name indication extension
+    pub server_name: Option<String>,
+    /// application protocols offered with ALPN, e.g. `h2`
+    pub protocols: Vec<String>,
+    /// TLS versions offered, newest first as the client listed them
+    pub versions: Vec<u16>,
+}
+
+impl ClientHello {
+    /// Parses the ClientHello in the first TLS record of `bytes`, returning `None` if there isn't
+    /// a complete one there. Only the first record is looked at, which is where clients put the
+    /// whole ClientHello unless it is unusually large.
+    pub fn parse(bytes: &[u8]) -> Option<ClientHello> {
+        let record_len = first_record_len(bytes)?;
+        let mut record = Reader(bytes.get(5..record_len)?);
+
+        if record.u8()? != CLIENT_HELLO {
+            return None;
+        }
+        // the message length, already bounded by the record's
+        record.take(3)?;
+        let legacy_version = record.u16()?;
+        // the random value
+        record.take(32)?;
+        // the session id, cipher suites, and compression methods
+        record.prefixed(1)?;
+        record.prefixed(2)?;
+        record.prefixed(1)?;
+
+        let mut hello = ClientHello {
+            server_name: None,
+            protocols: Vec::new(),
+            versions: Vec::new(),
+        };
+        // extensions are optional before TLS 1.3; without them the version field is the offer
+        let mut extensions = Reader(record.prefixed(2).unwrap_or_default());
+        while let (Some(kind), Some(data)) = (extensions.u16(), extensions.prefixed(2)) {
+            let mut data = Reader(data);
+            match kind {
+                SERVER_NAME_EXTENSION => {
+                    let mut names = Reader(data.prefixed(2)?);
+                    while let (Some(name_type), Some(name)) = (names.u8(), names.prefixed(2)) {
+                        // type 0 is a DNS host name, the only type defined
+                        if name_type == 0 {
+                            hello.server_name = Some(String::from_utf8_lossy(name).into_owned());
+                        }
+                    }
+                }
+                ALPN_EXTENSION => {
+                    let mut protocols = Reader(data.prefixed(2)?);
+                    while let Some(protocol) = protocols.prefixed(1) {
+                        hello
+                            .protocols
+                            .push(String::from_utf8_lossy(protocol).into_owned());
+                    }
+                }
+                SUPPORTED_VERSIONS_EXTENSION => {
+                    let mut versions = Reader(data.prefixed(1)?);
+                    while let Some(version) = versions.u16() {
+                        if !is_grease(version) {
+                            hello.versions.push(version);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        if hello.versions.is_empty() {
+            hello.versions.push(legacy_version);
+        }
+        Some(hello)
+    }
+
+    /// Describes the offer as `sni=HOST alpn=PROTOCOL,... versions=VERSION,...`, with `-` for
+    /// anything not offered. The host name and protocols are whatever the client sent, so they
+    /// are escaped like other client-supplied values in the log.
+    pub fn summary(&self) -> String {
+        let protocols: Vec<_> = self
+            .protocols
+            .iter()
+            .map(|protocol| log::escape(protocol.as_bytes()))
+            .collect();
+        let protocols = match protocols.join(",") {
+            protocols if protocols.is_empty() => "-".to_owned(),
+            protocols => protocols,
+        };
+        let versions: Vec<_> = self.versions.iter().map(|&v| version_name(v)).collect();
+        format!(
+            "sni={} alpn={protocols} versions={}",
+            self.server_name
+                .as_ref()
+                .map_or("-".to_owned(), |name| log::escape(name.as_bytes())),
+            versions.join(",")
+        )
+    }
+}
+
+fn version_name(version: u16) -> String {
+    match version {
+        0x0300 => "SSL3.0".to_owned(),
+        0x0301 => "TLS1.0".to_owned(),
+        0x0302 => "TLS1.1".to_owned(),
+        0x0303 => "TLS1.2".to_owned(),
+        0x0304 => "TLS1.3".to_owned(),
+        version => format!("0x{version:04x}"),
+    }
+}
+
+/// Whether `value` is one of the reserved values clients send to keep servers tolerant of unknown
+/// ones, which offer nothing
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 12 == (value >> 4) & 0xf
+}
+
+/// Reads big-endian fields off the front of a handshake message
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// Reads a field preceded by its length in `len_size` bytes
+    fn prefixed(&mut self, len_size: usize) -> Option<&'a [u8]> {
+        let len = self
+            .take(len_size)?
+            .iter()
+            .fold(0, |len, &byte| len << 8 | usize::from(byte));
+        self.take(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ClientHello sent by OpenSSL for `example.com`, offering h2 and http/1.1
+    const CAPTURED: &str = concat!(
+        "1603010200010001fc0303b018a9c176e825c0f1f7b87d7c02cc80c6650c4d6e585a35ab17d56837189f20",
+        "20e96ed4698657a1fc83a35c70dacdbee7335426910ff05c1b760589a1af6601a80024130213031301c02c",
+        "c030c02bc02fcca9cca8c024c028c023c027009f009e006b006700ff0100018f00000010000e00000b6578",
+        "616d706c652e636f6d000b000403000102000a000400020017002300000010000e000c02683208687474",
+        "702f312e310016000000170000000d002a0028040305030603080708080809080a080b08040805080604",
+        "0105010601030303010302040205020602002b00050403040303002d0002010100330047004500170041",
+        "043163ebd49776d97f0b59fc7a826ceb4bf950029fd2ca27010f5939096a7e8925abf3efe9d1887335e2",
+        "7611f7e562ebd6b7e20d167eec2deb42ecbb84bf825d35001500c1",
+    );
+
+    fn captured() -> Vec<u8> {
+        let mut bytes: Vec<u8> = (0..CAPTURED.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&CAPTURED[i..i + 2], 16).unwrap())
+            .collect();
+        // the rest of the record is the padding extension's zeroes
+        bytes.resize(first_record_len(&bytes).unwrap(), 0);
+        bytes
+    }
+
+    /// A TLS 1.2 ClientHello record carrying `extensions`, or none at all if `None`
+    fn hello_with(extensions: Option<&[(u16, Vec<u8>)]>) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend([0; 32]);
+        // no session id, one cipher suite, and the null compression method
+        body.extend([0, 0, 2, 0x13, 0x01, 1, 0]);
+        if let Some(extensions) = extensions {
+            let mut encoded = Vec::new();
+            for (kind, data) in extensions {
+                encoded.extend(kind.to_be_bytes());
+                encoded.extend((data.len() as u16).to_be_bytes());
+                encoded.extend(data);
+            }
+            body.extend((encoded.len() as u16).to_be_bytes());
+            body.extend(encoded);
+        }
+        let mut message = vec![CLIENT_HELLO, 0];
+        message.extend((body.len() as u16).to_be_bytes());
+        message.extend(body);
+        let mut record = vec![HANDSHAKE, 3, 1];
+        record.extend((message.len() as u16).to_be_bytes());
+        record.extend(message);
+        record
+    }
+
+    fn server_name(name: &[u8]) -> (u16, Vec<u8>) {
+        let mut data = ((name.len() + 3) as u16).to_be_bytes().to_vec();
+        data.push(0);
+        data.extend((name.len() as u16).to_be_bytes());
+        data.extend(name);
+        (SERVER_NAME_EXTENSION, data)
+    }
+
+    #[test]
+    fn captured_hello_gives_its_server_name_protocols_and_versions() {
+        let hello = ClientHello::parse(&captured()).unwrap();
+        assert_eq!(hello.server_name.as_deref(), Some("example.com"));
+        assert_eq!(hello.protocols, ["h2", "http/1.1"]);
+        assert_eq!(hello.versions, [0x0304, 0x0303]);
+        assert_eq!(
+            hello.summary(),
+            "sni=example.com alpn=h2,http/1.1 versions=TLS1.3,TLS1.2"
+        );
+    }
+
+    #[test]
+    fn grease_versions_are_left_out() {
+        let versions = vec![6, 0x7a, 0x7a, 0x03, 0x04, 0x03, 0x03];
+        let hello = hello_with(Some(&[(SUPPORTED_VERSIONS_EXTENSION, versions)]));
+        let hello = ClientHello::parse(&hello).unwrap();
+        assert_eq!(hello.versions, [0x0304, 0x0303]);
+        assert!(is_grease(0x0a0a) && is_grease(0xfafa));
+        assert!(!is_grease(0x0a1a) && !is_grease(0x0303));
+    }
+
+    #[test]
+    fn hello_without_extensions_offers_its_legacy_version() {
+        let hello = ClientHello::parse(&hello_with(None)).unwrap();
+        assert_eq!(hello.summary(), "sni=- alpn=- versions=TLS1.2");
+    }
+
+    #[test]
+    fn client_supplied_names_are_escaped_in_the_summary() {
+        let alpn = vec![0, 6, 5, b'h', b'2', b'\n', b'x', b'"'];
+        let hello = hello_with(Some(&[
+            server_name(b"a.test\nforged line"),
+            (ALPN_EXTENSION, alpn),
+        ]));
+        let summary = ClientHello::parse(&hello).unwrap().summary();
+        assert_eq!(
+            summary,
+            "sni=a.test\\x0aforged line alpn=h2\\x0ax\\\" versions=TLS1.2"
+        );
+    }
+
+    #[test]
+    fn truncated_record_is_not_parsed() {
+        let hello = captured();
+        assert_eq!(first_record_len(&hello[..3]), Some(5));
+        assert_eq!(first_record_len(&hello[..100]), Some(hello.len()));
+        assert!(ClientHello::parse(&hello[..100]).is_none());
+        assert!(ClientHello::parse(&hello[..hello.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn records_other_than_a_handshake_are_not_parsed() {
+        assert_eq!(first_record_len(b"GET / HTTP/1.1\r\n"), None);
+        assert!(ClientHello::parse(b"GET / HTTP/1.1\r\n").is_none());
+
+        let mut application_data = captured();
+        application_data[0] = 0x17;
+        assert_eq!(first_record_len(&application_data), None);
+        assert!(ClientHello::parse(&application_data).is_none());
+
+        // a handshake record holding some other message, here a ServerHello
+        let mut server_hello = captured();
+        server_hello[5] = 2;
+        assert!(ClientHello::parse(&server_hello).is_none());
+    }
+}
@@ -0,0 +1,210 @@
+use crate::{
+    clock::{Clock, SystemClock},
+    log,
+    resolve::Resolver,
+};
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// How long an upstream proxy that could not be reached is passed over in favor of the others
+const RETRY_AFTER: Duration = Duration::from_secs(30);
+
+/// Maximum size of the response head an upstream proxy may send when opening a tunnel
+const MAX_TUNNEL_RESPONSE_HEAD: usize = 8192;
+
+/// The order in which upstream proxies are tried
+#[derive(Clone, Copy)]
+pub enum UpstreamPolicy {
+    /// always start from the first upstream, moving on only when it is unreachable
+    Failover,
+    /// start each connection from the upstream after the one the previous connection started from
+    RoundRobin,
+}
+
+impl UpstreamPolicy {
+    pub fn parse(policy: &str) -> Result<UpstreamPolicy, String> {
+        match policy {
+            "failover" => Ok(UpstreamPolicy::Failover),
+            "round-robin" => Ok(UpstreamPolicy::RoundRobin),
+            _ => Err(format!("Unknown upstream policy: {policy}")),
+        }
+    }
+}
+
+/// Parent proxies that connections are forwarded through instead of reaching servers directly
+pub struct UpstreamProxies<C: Clock = SystemClock> {
+    clock: C,
+    addrs: Vec<String>,
+    policy: UpstreamPolicy,
+    /// index of the upstream the next round-robin connection starts from
+    next: AtomicUsize,
+    /// when each upstream last failed to accept a connection
+    failed_at: Mutex<Vec<Option<Instant>>>,
+}
+
+impl UpstreamProxies {
+    /// Parses a comma-separated list of `host:port` upstream proxy addresses
+    pub fn parse(list: &str, policy: UpstreamPolicy) -> Result<UpstreamProxies, String> {
+        UpstreamProxies::parse_with_clock(list, policy, SystemClock)
+    }
+}
+
+impl<C: Clock> UpstreamProxies<C> {
+    /// The `host:port` addresses of the upstream proxies, in the order given
+    pub fn addrs(&self) -> &[String] {
+        &self.addrs
+    }
+
+    /// Parses a list like `parse`, timing how long failed upstreams are passed over by `clock`
+    pub fn parse_with_clock(
+        list: &str,
+        policy: UpstreamPolicy,
+        clock: C,
+    ) -> Result<UpstreamProxies<C>, String> {
+        let addrs: Vec<String> = list.split(',').map(|addr| addr.trim().to_owned()).collect();
+        if let Some(addr) = addrs.iter().find(|addr| !addr.contains(':')) {
+            return Err(format!(
+                "Upstream proxy must be given as host:port: {addr:?}"
+            ));
+        }
+
+        Ok(UpstreamProxies {
+            clock,
+            failed_at: Mutex::new(vec![None; addrs.len()]),
+            addrs,
+            policy,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Connects to an upstream proxy, trying each in the configured order.
+    ///
+    /// Upstreams that recently failed are only tried once every healthy one has been.
+    pub fn connect(
+        &self,
+        resolver: &Resolver,
+        timeout: Option<Duration>,
+    ) -> Result<TcpStream, String> {
+        let start = match self.policy {
+            UpstreamPolicy::Failover => 0,
+            UpstreamPolicy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed),
+        };
+        let mut order: Vec<usize> = (0..self.addrs.len())
+            .map(|offset| (start + offset) % self.addrs.len())
+            .collect();
+        {
+            let now = self.clock.now();
+            let failed_at = self.failed_at.lock().unwrap();
+            // stable sort, so the policy order is kept within healthy and failed upstreams
+            order.sort_by_key(|&index| {
+                failed_at[index].is_some_and(|failed| now.duration_since(failed) < RETRY_AFTER)
+            });
+        }
+
+        let mut last_error = String::new();
+        for index in order {
+            let addr = &self.addrs[index];
+            let connected = resolver
+                .resolve_authority(addr)
+                .and_then(|addrs| crate::connect(&*addrs, timeout));
+            match connected {
+                Ok(stream) => {
+                    self.failed_at.lock().unwrap()[index] = None;
+                    return Ok(stream);
+                }
+                Err(e) => {
+                    log::write_line(&format!("Upstream proxy {addr} is unreachable: {e}"));
+                    self.failed_at.lock().unwrap()[index] = Some(self.clock.now());
+                    last_error = format!("No upstream proxy is reachable: {e}");
+                }
+            }
+        }
+        Err(last_error)
+    }
+}
+
+/// Asks the upstream proxy connected on `stream` to open a tunnel to `authority`
+pub fn open_tunnel(stream: &mut TcpStream, authority: &str) -> Result<(), String> {
+    let request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n\r\n");
+    err_to_str!(stream.write_all(request.as_bytes()))?;
+
+    // read a byte at a time so none of the tunneled data following the head is consumed
+    let mut head = Vec::new();
+    let mut byte = [0];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_TUNNEL_RESPONSE_HEAD {
+            return Err("Upstream proxy response head too large".to_owned());
+        }
+        match err_to_str!(stream.read(&mut byte))? {
+            0 => return Err("Upstream proxy closed the connection".to_owned()),
+            _ => head.push(byte[0]),
+        }
+    }
+
+    let mut headers = [httparse::EMPTY_HEADER; 16];
+    let mut response = httparse::Response::new(&mut headers);
+    err_to_str!(response.parse(&head))?;
+    match response.code {
+        Some(200..=299) => Ok(()),
+        Some(code) => Err(format!(
+            "Upstream proxy refused the tunnel with status {code}"
+        )),
+        None => Err("Invalid response from upstream proxy".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::ManualClock, resolve::Family};
+    use std::{collections::HashMap, net::TcpListener};
+
+    fn resolver() -> Resolver {
+        Resolver {
+            limit: None,
+            overrides: HashMap::new(),
+            remaps: HashMap::new(),
+            family: Family::Any,
+        }
+    }
+
+    #[test]
+    fn failed_upstream_is_passed_over_until_the_retry_window_ends() {
+        let clock = ManualClock::new();
+        let first = TcpListener::bind("127.0.0.1:0").unwrap();
+        let first_addr = first.local_addr().unwrap();
+        drop(first);
+        let second = TcpListener::bind("127.0.0.1:0").unwrap();
+        let second_addr = second.local_addr().unwrap();
+        let upstreams = UpstreamProxies::parse_with_clock(
+            &format!("{first_addr},{second_addr}"),
+            UpstreamPolicy::Failover,
+            &clock,
+        )
+        .unwrap();
+        let resolver = resolver();
+        let connect = || {
+            upstreams
+                .connect(&resolver, None)
+                .unwrap()
+                .peer_addr()
+                .unwrap()
+        };
+
+        assert_eq!(connect(), second_addr);
+        // back up, but still within the retry window, so the healthy upstream is tried first
+        let _first = TcpListener::bind(first_addr).unwrap();
+        clock.advance(RETRY_AFTER - Duration::from_secs(1));
+        assert_eq!(connect(), second_addr);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(connect(), first_addr);
+    }
+}
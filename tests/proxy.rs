@@ -0,0 +1,1768 @@
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// A proxy server process listening on a free loopback port and logging to a file of its own,
+/// killed when dropped
+struct Proxy {
+    child: Child,
+    address: SocketAddr,
+    log_path: PathBuf,
+}
+
+impl Proxy {
+    fn start(args: &[&str]) -> Proxy {
+        static STARTED: AtomicUsize = AtomicUsize::new(0);
+        let log_path = std::env::temp_dir().join(format!(
+            "proxy_server-test-{}-{}.log",
+            std::process::id(),
+            STARTED.fetch_add(1, Ordering::SeqCst)
+        ));
+        let address = free_address();
+        let child = Command::new(env!("CARGO_BIN_EXE_proxy_server"))
+            .arg("--listen")
+            .arg(address.to_string())
+            .arg("--log-file")
+            .arg(&log_path)
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Could not start the proxy server");
+        wait_for(address);
+        Proxy {
+            child,
+            address,
+            log_path,
+        }
+    }
+
+    /// Waits for a line containing `text` to be logged, returning it
+    fn logged(&self, text: &str) -> String {
+        let mut found = None;
+        eventually(&format!("Nothing containing {text:?} was logged"), || {
+            let log = std::fs::read_to_string(&self.log_path).unwrap_or_default();
+            found = log
+                .lines()
+                .find(|line| line.contains(text))
+                .map(str::to_owned);
+            found.is_some()
+        });
+        found.unwrap()
+    }
+
+    fn connect(&self) -> TcpStream {
+        let stream = TcpStream::connect(self.address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_file(&self.log_path);
+    }
+}
+
+fn free_address() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+fn wait_for(address: SocketAddr) {
+    let started = Instant::now();
+    while TcpStream::connect(address).is_err() {
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "The proxy server never started listening"
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Starts a server on a loopback port that hands each accepted connection to `handle`
+fn origin(handle: impl Fn(TcpStream) + Send + Sync + 'static) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let handle = std::sync::Arc::new(handle);
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let handle = handle.clone();
+            thread::spawn(move || handle(stream));
+        }
+    });
+    address
+}
+
+/// Reads from `stream` until the read so far ends with `end`, or the stream closes
+fn read_until(stream: &mut impl Read, end: &[u8]) -> Vec<u8> {
+    let mut read = Vec::new();
+    let mut byte = [0; 1];
+    while !read.ends_with(end) {
+        match stream.read(&mut byte) {
+            Ok(1) => read.push(byte[0]),
+            _ => break,
+        }
+    }
+    read
+}
+
+/// Reads one response with a `Content-Length` body from `stream`
+fn read_response(stream: &mut impl Read) -> String {
+    let mut response = read_until(stream, b"\r\n\r\n");
+    let head = String::from_utf8_lossy(&response).to_ascii_lowercase();
+    let length = head
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length:"))
+        .map_or(0, |length| length.trim().parse().unwrap());
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).unwrap();
+    response.extend(body);
+    String::from_utf8(response).unwrap()
+}
+
+#[test]
+fn content_length_is_not_forwarded_with_transfer_encoding() {
+    let (seen_tx, seen_rx) = std::sync::mpsc::channel();
+    let seen_tx = std::sync::Mutex::new(seen_tx);
+    let origin = origin(move |mut stream| {
+        let request = read_until(&mut stream, b"0\r\n\r\n");
+        let _ = seen_tx.lock().unwrap().send(request);
+        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+    });
+    let proxy = Proxy::start(&[]);
+
+    let mut client = proxy.connect();
+    write!(
+        client,
+        "POST http://{origin}/ HTTP/1.1\r\nHost: {origin}\r\nTransfer-Encoding: chunked\r\n\
+         Content-Length: 100\r\n\r\n4\r\nbody\r\n0\r\n\r\n"
+    )
+    .unwrap();
+    assert!(read_response(&mut client).starts_with("HTTP/1.1 200"));
+
+    let seen = String::from_utf8(seen_rx.recv_timeout(Duration::from_secs(5)).unwrap()).unwrap();
+    let seen = seen.to_ascii_lowercase();
+    assert!(seen.contains("transfer-encoding: chunked\r\n"));
+    assert!(!seen.contains("content-length"));
+    assert!(seen.ends_with("\r\n\r\n4\r\nbody\r\n0\r\n\r\n"));
+}
+
+#[test]
+fn origin_form_request_is_answered_with_400() {
+    let proxy = Proxy::start(&[]);
+    let mut client = proxy.connect();
+    client
+        .write_all(b"GET /page HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .unwrap();
+    assert!(read_response(&mut client).starts_with("HTTP/1.1 400"));
+}
+
+#[test]
+fn strict_mode_rejects_info_page_request_without_host() {
+    let proxy = Proxy::start(&["--reject-on-missing-host"]);
+    let mut client = proxy.connect();
+    client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    assert!(read_response(&mut client).starts_with("HTTP/1.1 400"));
+}
+
+/// Sends `request` to the stats server at `address`, returning its response
+fn ask_stats(address: SocketAddr, request: &str) -> String {
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn stats_server_refuses_connection_control_outside_its_allowed_networks() {
+    let stats = free_address();
+    let _proxy = Proxy::start(&[
+        "--stats-listen",
+        &stats.to_string(),
+        "--stats-allow-cidr",
+        "10.0.0.0/8",
+    ]);
+    wait_for(stats);
+
+    assert!(ask_stats(stats, "GET /connections HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 403"));
+    assert!(
+        ask_stats(stats, "POST /connections/1/close HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 403")
+    );
+    assert!(ask_stats(stats, "GET /health HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 200"));
+}
+
+#[test]
+fn stats_server_lets_loopback_clients_list_connections_by_default() {
+    let stats = free_address();
+    let _proxy = Proxy::start(&["--stats-listen", &stats.to_string()]);
+    wait_for(stats);
+
+    assert!(ask_stats(stats, "GET /connections HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 200"));
+}
+
+#[test]
+fn server_receives_exactly_the_bytes_the_client_sent() {
+    let (seen_tx, seen_rx) = std::sync::mpsc::channel();
+    let seen_tx = std::sync::Mutex::new(seen_tx);
+    let origin = origin(move |mut stream| {
+        let mut request = read_until(&mut stream, b"\r\n\r\nhello");
+        // anything sent after the body, such as padding from a fixed-size buffer, shows up here
+        stream
+            .set_read_timeout(Some(Duration::from_millis(300)))
+            .unwrap();
+        let mut extra = [0; 1024];
+        if let Ok(bytes) = stream.read(&mut extra) {
+            request.extend_from_slice(&extra[..bytes]);
+        }
+        let _ = seen_tx.lock().unwrap().send(request);
+        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+    });
+    let proxy = Proxy::start(&[]);
+
+    let mut client = proxy.connect();
+    write!(
+        client,
+        "POST http://{origin}/ HTTP/1.1\r\nHost: {origin}\r\nContent-Length: 5\r\n\r\nhello"
+    )
+    .unwrap();
+    assert!(read_response(&mut client).starts_with("HTTP/1.1 200"));
+
+    let seen = seen_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(
+        String::from_utf8(seen).unwrap(),
+        format!("POST / HTTP/1.1\r\nHost: {origin}\r\nContent-Length: 5\r\n\r\nhello")
+    );
+}
+
+#[test]
+fn chunk_extension_with_a_bare_carriage_return_is_answered_with_400() {
+    let origin = origin(|mut stream| {
+        read_until(&mut stream, b"0\r\n\r\n");
+        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+    });
+    let proxy = Proxy::start(&[]);
+
+    let mut client = proxy.connect();
+    write!(
+        client,
+        "POST http://{origin}/ HTTP/1.1\r\nHost: {origin}\r\nTransfer-Encoding: chunked\r\n\r\n\
+         4;a\rb\r\nbody\r\n0\r\n\r\n"
+    )
+    .unwrap();
+    assert!(read_response(&mut client).starts_with("HTTP/1.1 400"));
+}
+
+/// Starts a server answering each request on a connection with its request line, until the client
+/// closes
+fn echo_origin() -> SocketAddr {
+    origin(|mut stream| loop {
+        let head = read_until(&mut stream, b"\r\n\r\n");
+        if !head.ends_with(b"\r\n\r\n") {
+            break;
+        }
+        let line = String::from_utf8_lossy(&head);
+        let line = line.lines().next().unwrap_or_default();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{line}",
+            line.len()
+        );
+        if stream.write_all(response.as_bytes()).is_err() {
+            break;
+        }
+    })
+}
+
+#[test]
+fn pipelined_requests_are_answered_in_order() {
+    let origin = echo_origin();
+    let proxy = Proxy::start(&[]);
+
+    let mut client = proxy.connect();
+    write!(
+        client,
+        "GET http://{origin}/a HTTP/1.1\r\nHost: {origin}\r\n\r\n\
+         GET http://{origin}/b HTTP/1.1\r\nHost: {origin}\r\n\r\n\
+         GET http://{origin}/c HTTP/1.1\r\nHost: {origin}\r\n\r\n"
+    )
+    .unwrap();
+    for path in ["/a", "/b", "/c"] {
+        let response = read_response(&mut client);
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(
+            response.ends_with(&format!("GET {path} HTTP/1.1")),
+            "{response}"
+        );
+    }
+}
+
+#[test]
+fn keep_alive_connection_carries_several_requests() {
+    let origin = echo_origin();
+    let proxy = Proxy::start(&[]);
+
+    let mut client = proxy.connect();
+    for path in ["/first", "/second"] {
+        write!(
+            client,
+            "GET http://{origin}{path} HTTP/1.1\r\nHost: {origin}\r\n\r\n"
+        )
+        .unwrap();
+        let response = read_response(&mut client);
+        assert!(
+            response.ends_with(&format!("GET {path} HTTP/1.1")),
+            "{response}"
+        );
+    }
+}
+
+#[test]
+fn connection_close_ends_the_connection_after_the_response() {
+    let origin = echo_origin();
+    let proxy = Proxy::start(&[]);
+
+    let mut client = proxy.connect();
+    write!(
+        client,
+        "GET http://{origin}/ HTTP/1.1\r\nHost: {origin}\r\nProxy-Connection: close\r\n\r\n"
+    )
+    .unwrap();
+    let response = read_response(&mut client);
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    let mut rest = Vec::new();
+    client.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+}
+
+#[test]
+fn server_connection_close_ends_the_client_connection_after_the_response() {
+    let origin = origin(|mut stream| {
+        read_until(&mut stream, b"\r\n\r\n");
+        let _ = stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\nConnection: close\r\n\r\nbye!");
+        // stay open, so only the header tells the proxy to close
+        let _ = stream.read(&mut [0; 1]);
+    });
+    let proxy = Proxy::start(&[]);
+
+    let mut client = proxy.connect();
+    write!(
+        client,
+        "GET http://{origin}/ HTTP/1.1\r\nHost: {origin}\r\nProxy-Connection: keep-alive\r\n\r\n"
+    )
+    .unwrap();
+    let response = read_response(&mut client);
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    assert!(response.ends_with("\r\n\r\nbye!"), "{response}");
+    let started = Instant::now();
+    let mut rest = Vec::new();
+    client.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+    assert!(started.elapsed() < Duration::from_secs(4));
+}
+
+#[test]
+fn header_value_over_the_limit_is_answered_with_431() {
+    let origin = echo_origin();
+    let proxy = Proxy::start(&["--max-header-value-size", "16"]);
+
+    let mut client = proxy.connect();
+    write!(
+        client,
+        "GET http://{origin}/ HTTP/1.1\r\nHost: {origin}\r\nX-Fits: {}\r\n\r\n",
+        "a".repeat(16)
+    )
+    .unwrap();
+    let response = read_response(&mut client);
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+
+    let mut client = proxy.connect();
+    write!(
+        client,
+        "GET http://{origin}/ HTTP/1.1\r\nHost: {origin}\r\nX-Too-Long: {}\r\n\r\n",
+        "a".repeat(17)
+    )
+    .unwrap();
+    let response = read_response(&mut client);
+    assert!(
+        response.starts_with("HTTP/1.1 431 Request Header Fields Too Large"),
+        "{response}"
+    );
+    assert!(
+        response.contains("Header X-Too-Long is too large"),
+        "{response}"
+    );
+}
+
+#[test]
+fn connections_speaking_other_protocols_are_closed_unanswered() {
+    let proxy = Proxy::start(&[]);
+    for greeting in [
+        &b"\x16\x03\x01\x00\x05hello"[..],
+        b"SSH-2.0-OpenSSH_9.0\r\n",
+    ] {
+        let mut client = proxy.connect();
+        client.write_all(greeting).unwrap();
+        let mut answer = Vec::new();
+        client.read_to_end(&mut answer).unwrap();
+        assert!(answer.is_empty());
+    }
+}
+
+#[test]
+fn large_request_body_is_streamed_to_the_server() {
+    const LENGTH: usize = 4 * 1024 * 1024;
+    let origin = origin(|mut stream| {
+        read_until(&mut stream, b"\r\n\r\n");
+        let mut body = vec![0; LENGTH];
+        stream.read_exact(&mut body).unwrap();
+        let ok = body.iter().enumerate().all(|(i, &byte)| byte == i as u8);
+        let answer = if ok { "intact" } else { "corrupted" };
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{answer}",
+            answer.len()
+        );
+        stream.write_all(response.as_bytes()).unwrap();
+    });
+    let proxy = Proxy::start(&[]);
+
+    let mut client = proxy.connect();
+    write!(
+        client,
+        "POST http://{origin}/ HTTP/1.1\r\nHost: {origin}\r\nContent-Length: {LENGTH}\r\n\r\n"
+    )
+    .unwrap();
+    let body: Vec<u8> = (0..LENGTH).map(|i| i as u8).collect();
+    client.write_all(&body).unwrap();
+    assert!(read_response(&mut client).ends_with("\r\n\r\nintact"));
+}
+
+#[test]
+fn clients_outside_the_allowed_networks_are_closed_unread() {
+    let proxy = Proxy::start(&["--allow-client-cidr", "10.0.0.0/8"]);
+    let mut client = proxy.connect();
+    let _ = client.write_all(b"GET / HTTP/1.1\r\n\r\n");
+    let mut answer = Vec::new();
+    let _ = client.read_to_end(&mut answer);
+    assert!(answer.is_empty());
+
+    let proxy = Proxy::start(&["--allow-client-cidr", "127.0.0.0/8"]);
+    let mut client = proxy.connect();
+    client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    assert!(read_response(&mut client).starts_with("HTTP/1.1 200"));
+}
+
+#[test]
+fn invalid_allowed_network_stops_the_proxy_from_starting() {
+    let address = free_address();
+    for network in ["10.0.0.0/33", "10.0.0/8", "example.com"] {
+        let output = Command::new(env!("CARGO_BIN_EXE_proxy_server"))
+            .args(["--listen", &address.to_string()])
+            .args(["--allow-client-cidr", "127.0.0.0/8"])
+            .args(["--allow-client-cidr", network])
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(2), "{network}");
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert_eq!(
+            stderr.lines().next(),
+            Some(format!("Invalid client network: {network}").as_str())
+        );
+        assert!(TcpStream::connect(address).is_err());
+    }
+}
+
+#[test]
+fn route_rewrites_the_forwarded_path() {
+    let origin = echo_origin();
+    let proxy = Proxy::start(&["--route", "host=127.0.0.1,rewrite=/old/*=/new/$1"]);
+
+    let mut client = proxy.connect();
+    write!(
+        client,
+        "GET http://{origin}/old/page?q=1 HTTP/1.1\r\nHost: {origin}\r\n\r\n"
+    )
+    .unwrap();
+    let response = read_response(&mut client);
+    assert!(
+        response.ends_with("GET /new/page?q=1 HTTP/1.1"),
+        "{response}"
+    );
+}
+
+#[test]
+fn tunnel_route_passes_the_request_head_through_untouched() {
+    let origin = echo_origin();
+    let proxy = Proxy::start(&["--route", "host=127.0.0.1,mode=tunnel"]);
+
+    let mut client = proxy.connect();
+    write!(
+        client,
+        "GET http://{origin}/page HTTP/1.1\r\nHost: {origin}\r\nProxy-Connection: keep-alive\r\n\r\n"
+    )
+    .unwrap();
+    let response = read_response(&mut client);
+    assert!(
+        response.ends_with(&format!("GET http://{origin}/page HTTP/1.1")),
+        "{response}"
+    );
+}
+
+/// Starts a server that reads requests but never answers them
+fn silent_origin() -> SocketAddr {
+    origin(|mut stream| {
+        let mut buffer = [0; 1024];
+        while matches!(stream.read(&mut buffer), Ok(bytes) if bytes > 0) {}
+    })
+}
+
+#[test]
+fn silent_server_gets_a_504_after_the_response_timeout() {
+    let origin = silent_origin();
+    let proxy = Proxy::start(&["--response-timeout", "1"]);
+
+    let mut client = proxy.connect();
+    write!(
+        client,
+        "GET http://{origin}/ HTTP/1.1\r\nHost: {origin}\r\n\r\n"
+    )
+    .unwrap();
+    let started = Instant::now();
+    assert!(read_response(&mut client).starts_with("HTTP/1.1 504"));
+    assert!(started.elapsed() < Duration::from_secs(4));
+}
+
+#[test]
+fn route_timeouts_replace_the_global_ones_for_its_hosts() {
+    let origin = silent_origin();
+    let proxy = Proxy::start(&[
+        "--response-timeout",
+        "60",
+        "--route",
+        "host=127.0.0.1,response-timeout=1",
+    ]);
+
+    let mut client = proxy.connect();
+    write!(
+        client,
+        "GET http://{origin}/ HTTP/1.1\r\nHost: {origin}\r\n\r\n"
+    )
+    .unwrap();
+    assert!(read_response(&mut client).starts_with("HTTP/1.1 504"));
+}
+
+/// Starts a server sending back whatever it is sent, until the client closes
+fn raw_echo_origin() -> SocketAddr {
+    origin(|mut stream| {
+        let mut chunk = [0; 4096];
+        while let Ok(len @ 1..) = stream.read(&mut chunk) {
+            if stream.write_all(&chunk[..len]).is_err() {
+                break;
+            }
+        }
+    })
+}
+
+#[test]
+fn connect_tunnel_carries_data_sent_with_the_request_and_after_it() {
+    let server = raw_echo_origin();
+    let proxy = Proxy::start(&[]);
+
+    let mut client = proxy.connect();
+    write!(
+        client,
+        "CONNECT {server} HTTP/1.1\r\nHost: {server}\r\n\r\nearly"
+    )
+    .unwrap();
+    assert_eq!(
+        read_until(&mut client, b"\r\n\r\n"),
+        b"HTTP/1.1 200 OK\r\n\r\n"
+    );
+    let mut echoed = [0; 5];
+    client.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"early");
+
+    client.write_all(b"later").unwrap();
+    client.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"later");
+}
+
+#[test]
+fn connect_is_answered_in_the_clients_http_version() {
+    let server = raw_echo_origin();
+    let proxy = Proxy::start(&[]);
+
+    let mut client = proxy.connect();
+    write!(client, "CONNECT {server} HTTP/1.0\r\n\r\n").unwrap();
+    assert_eq!(
+        read_until(&mut client, b"\r\n\r\n"),
+        b"HTTP/1.0 200 OK\r\n\r\n"
+    );
+}
+
+#[test]
+fn connect_to_a_target_that_is_not_host_and_port_is_answered_with_400() {
+    let proxy = Proxy::start(&[]);
+
+    for target in ["/index.html", "example.com", "http://example.com:80/"] {
+        let mut client = proxy.connect();
+        write!(
+            client,
+            "CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n\r\n"
+        )
+        .unwrap();
+        let response = read_response(&mut client);
+        assert!(response.starts_with("HTTP/1.1 400"), "{target}: {response}");
+        assert!(response.contains("CONNECT target must be of the form host:port"));
+    }
+}
+
+#[test]
+fn requests_fail_over_to_the_next_upstream_proxy() {
+    let server = echo_origin();
+    let parent = Proxy::start(&[]);
+    let unreachable = free_address();
+    let upstreams = format!("{unreachable},{}", parent.address);
+    let proxy = Proxy::start(&["--upstream-proxy", &upstreams]);
+
+    let mut client = proxy.connect();
+    write!(
+        client,
+        "GET http://{server}/through HTTP/1.1\r\nHost: {server}\r\n\r\n"
+    )
+    .unwrap();
+    let response = read_response(&mut client);
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    assert!(response.ends_with("GET /through HTTP/1.1"), "{response}");
+    proxy.logged(&format!("Upstream proxy {unreachable} is unreachable"));
+
+    let mut client = proxy.connect();
+    write!(
+        client,
+        "CONNECT {server} HTTP/1.1\r\nHost: {server}\r\n\r\n"
+    )
+    .unwrap();
+    assert!(read_until(&mut client, b"\r\n\r\n").starts_with(b"HTTP/1.1 200"));
+    write!(client, "GET /tunneled HTTP/1.1\r\nHost: {server}\r\n\r\n").unwrap();
+    assert!(read_response(&mut client).ends_with("GET /tunneled HTTP/1.1"));
+}
+
+#[test]
+fn requests_go_directly_to_the_server_when_no_upstream_proxy_is_reachable() {
+    let server = echo_origin();
+    let unreachable = free_address().to_string();
+
+    let proxy = Proxy::start(&["--upstream-proxy", &unreachable]);
+    let mut client = proxy.connect();
+    write!(
+        client,
+        "GET http://{server}/ HTTP/1.1\r\nHost: {server}\r\n\r\n"
+    )
+    .unwrap();
+    assert!(read_response(&mut client).starts_with("HTTP/1.1 502"));
+
+    let proxy = Proxy::start(&["--upstream-proxy", &unreachable, "--fallback-direct"]);
+    let mut client = proxy.connect();
+    write!(
+        client,
+        "GET http://{server}/direct HTTP/1.1\r\nHost: {server}\r\n\r\n"
+    )
+    .unwrap();
+    let response = read_response(&mut client);
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    assert!(response.ends_with("GET /direct HTTP/1.1"), "{response}");
+}
+
+#[test]
+fn stats_server_counts_connections_and_answers_health_checks() {
+    let server = echo_origin();
+    let stats = free_address();
+    let proxy = Proxy::start(&["--stats-listen", &stats.to_string()]);
+    wait_for(stats);
+
+    let mut client = proxy.connect();
+    write!(
+        client,
+        "GET http://{server}/ HTTP/1.1\r\nHost: {server}\r\n\r\n"
+    )
+    .unwrap();
+    read_response(&mut client);
+    let snapshot = ask_stats(stats, "GET /stats HTTP/1.1\r\n\r\n");
+    assert!(snapshot.starts_with("HTTP/1.1 200"), "{snapshot}");
+    let total: u64 = snapshot
+        .lines()
+        .find_map(|line| line.strip_prefix("total_connections "))
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(total >= 1, "{snapshot}");
+
+    assert!(ask_stats(stats, "GET /health HTTP/1.1\r\n\r\n").ends_with("\r\n\r\nok\n"));
+    assert!(ask_stats(stats, "GET /missing HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404"));
+    assert!(ask_stats(stats, "DELETE /stats HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405"));
+}
+
+#[test]
+fn stats_server_closes_a_listed_connection_by_id() {
+    let server = raw_echo_origin();
+    let stats = free_address();
+    let proxy = Proxy::start(&["--stats-listen", &stats.to_string()]);
+    wait_for(stats);
+
+    let mut client = proxy.connect();
+    write!(
+        client,
+        "CONNECT {server} HTTP/1.1\r\nHost: {server}\r\n\r\n"
+    )
+    .unwrap();
+    read_until(&mut client, b"\r\n\r\n");
+    let listing = ask_stats(stats, "GET /connections HTTP/1.1\r\n\r\n");
+    let id = listing
+        .lines()
+        .find(|line| line.contains("method=CONNECT"))
+        .and_then(|line| line.strip_prefix("id="))
+        .and_then(|line| line.split_whitespace().next())
+        .unwrap_or_else(|| panic!("The tunnel isn't listed: {listing}"))
+        .to_owned();
+
+    let close = format!("GET /connections/{id}/close HTTP/1.1\r\n\r\n");
+    assert!(ask_stats(stats, &close).starts_with("HTTP/1.1 405"));
+    let close = format!("POST /connections/{id}/close HTTP/1.1\r\n\r\n");
+    assert!(ask_stats(stats, &close).starts_with("HTTP/1.1 200"));
+    let mut rest = Vec::new();
+    client.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+
+    let unknown = "POST /connections/999999/close HTTP/1.1\r\n\r\n";
+    assert!(ask_stats(stats, unknown).starts_with("HTTP/1.1 404"));
+}
+
+/// Polls `condition` until it holds, failing with `what` if it doesn't within the read timeout
+fn eventually(what: &str, mut condition: impl FnMut() -> bool) {
+    let started = Instant::now();
+    while !condition() {
+        assert!(started.elapsed() < Duration::from_secs(5), "{what}");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn shutdown_waits_for_open_connections_while_failing_health_checks() {
+    let server = raw_echo_origin();
+    let stats = free_address();
+    let mut proxy = Proxy::start(&["--stats-listen", &stats.to_string()]);
+    wait_for(stats);
+
+    let mut client = proxy.connect();
+    write!(
+        client,
+        "CONNECT {server} HTTP/1.1\r\nHost: {server}\r\n\r\n"
+    )
+    .unwrap();
+    read_until(&mut client, b"\r\n\r\n");
+    let signalled = Command::new("kill")
+        .arg("-TERM")
+        .arg(proxy.child.id().to_string())
+        .status()
+        .unwrap();
+    assert!(signalled.success());
+
+    eventually("Health checks kept passing while draining", || {
+        ask_stats(stats, "GET /health HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 503")
+    });
+    eventually("New connections were still accepted while draining", || {
+        TcpStream::connect(proxy.address).is_err()
+    });
+    client.write_all(b"still open").unwrap();
+    let mut echoed = [0; 10];
+    client.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"still open");
+    assert!(proxy.child.try_wait().unwrap().is_none());
+
+    drop(client);
+    eventually(
+        "The proxy kept running after its last connection closed",
+        || proxy.child.try_wait().unwrap().is_some(),
+    );
+}
+
+#[test]
+fn trace_to_the_proxy_echoes_the_request_without_credentials() {
+    let proxy = Proxy::start(&["--enable-trace"]);
+    let address = proxy.address;
+
+    let mut client = proxy.connect();
+    write!(
+        client,
+        "TRACE / HTTP/1.1\r\nHost: {address}\r\nCookie: session=secret\r\nX-Seen: yes\r\n\
+         Proxy-Authorization: Basic c2VjcmV0\r\n\r\n"
+    )
+    .unwrap();
+    let response = read_response(&mut client);
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+    assert_eq!(
+        body,
+        format!("TRACE / HTTP/1.1\r\nHost: {address}\r\nX-Seen: yes\r\n\r\n")
+    );
+}
+
+/// Starts an ftp server holding `files`, answering EPSV only if `epsv` is set, which sends the
+/// commands of each finished session on the returned channel
+fn ftp_origin(
+    files: &'static [(&str, &str)],
+    epsv: bool,
+) -> (SocketAddr, std::sync::mpsc::Receiver<Vec<String>>) {
+    let (commands_tx, commands_rx) = std::sync::mpsc::channel();
+    let commands_tx = std::sync::Mutex::new(commands_tx);
+    let address = origin(move |mut control| {
+        let mut commands = Vec::new();
+        let mut file = None;
+        let mut passive = None;
+        control.write_all(b"220 ready\r\n").unwrap();
+        loop {
+            let line = read_until(&mut control, b"\r\n");
+            let Some(command) = String::from_utf8(line)
+                .unwrap()
+                .strip_suffix("\r\n")
+                .map(str::to_owned)
+            else {
+                break;
+            };
+            let (verb, argument) = command.split_once(' ').unwrap_or((&command, ""));
+            let found = files.iter().find(|(name, _)| *name == argument);
+            let reply = match verb {
+                "USER" => "331 password please".to_owned(),
+                "PASS" => "230 logged in".to_owned(),
+                "TYPE" => "200 binary".to_owned(),
+                "SIZE" => match found {
+                    Some((_, contents)) => {
+                        file = Some(*contents);
+                        format!("213 {}", contents.len())
+                    }
+                    None => "550 no such file".to_owned(),
+                },
+                "EPSV" | "PASV" if verb == "PASV" || epsv => {
+                    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                    let port = listener.local_addr().unwrap().port();
+                    passive = Some(listener);
+                    if verb == "EPSV" {
+                        format!("229 Entering Extended Passive Mode (|||{port}|)")
+                    } else {
+                        // the address given is ignored in favor of the control connection's
+                        format!(
+                            "227 Entering Passive Mode (10,9,8,7,{},{})",
+                            port >> 8,
+                            port & 0xff
+                        )
+                    }
+                }
+                "EPSV" => "500 EPSV not understood".to_owned(),
+                "RETR" => {
+                    control.write_all(b"150 sending\r\n").unwrap();
+                    let (mut data, _) = passive.take().unwrap().accept().unwrap();
+                    data.write_all(file.unwrap().as_bytes()).unwrap();
+                    drop(data);
+                    "226 done".to_owned()
+                }
+                "QUIT" => "221 bye".to_owned(),
+                _ => "502 not implemented".to_owned(),
+            };
+            let quit = verb == "QUIT";
+            commands.push(command);
+            control
+                .write_all(format!("{reply}\r\n").as_bytes())
+                .unwrap();
+            if quit {
+                break;
+            }
+        }
+        let _ = commands_tx.lock().unwrap().send(commands);
+    });
+    (address, commands_rx)
+}
+
+fn get_ftp(proxy: &Proxy, url: &str) -> String {
+    let mut client = proxy.connect();
+    write!(client, "GET {url} HTTP/1.1\r\nHost: ignored\r\n\r\n").unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn ftp_file_is_retrieved_over_a_passive_data_connection() {
+    let proxy = Proxy::start(&["--enable-ftp"]);
+    for epsv in [true, false] {
+        let (server, commands) = ftp_origin(&[("notes.txt", "hello")], epsv);
+
+        let response = get_ftp(&proxy, &format!("ftp://reader:secret@{server}/notes.txt"));
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.contains("Content-Type: text/plain\r\nContent-Length: 5\r\n"));
+        assert!(response.ends_with("\r\n\r\nhello"), "{response}");
+
+        let passive = if epsv {
+            vec!["EPSV"]
+        } else {
+            vec!["EPSV", "PASV"]
+        };
+        let expected: Vec<_> = ["USER reader", "PASS secret", "TYPE I", "SIZE notes.txt"]
+            .into_iter()
+            .chain(passive)
+            .chain(["RETR notes.txt", "QUIT"])
+            .collect();
+        // QUIT is only sent once the server has confirmed the transfer with 226
+        let received = commands.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(received, expected);
+    }
+}
+
+#[test]
+fn missing_ftp_file_is_answered_with_404() {
+    let proxy = Proxy::start(&["--enable-ftp"]);
+    let (server, _commands) = ftp_origin(&[], true);
+
+    let response = get_ftp(&proxy, &format!("ftp://{server}/missing.txt"));
+    assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+    assert!(response.contains("The ftp server has no such file: no such file"));
+}
+
+#[test]
+fn silent_server_gets_a_504_for_the_total_deadline_without_a_response_timeout() {
+    let origin = silent_origin();
+    let proxy = Proxy::start(&["--max-duration", "1"]);
+
+    let mut client = proxy.connect();
+    write!(
+        client,
+        "GET http://{origin}/ HTTP/1.1\r\nHost: {origin}\r\n\r\n"
+    )
+    .unwrap();
+    let started = Instant::now();
+    let response = read_response(&mut client);
+    assert!(response.starts_with("HTTP/1.1 504"), "{response}");
+    assert!(
+        response.ends_with("The request took longer than the proxy allows\n"),
+        "{response}"
+    );
+    assert!(started.elapsed() < Duration::from_secs(4));
+}
+
+#[test]
+fn server_response_with_bad_framing_is_answered_with_502() {
+    for head in [
+        "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nhello!",
+        "HTTP/1.1 200 OK\r\nContent-Length: lots\r\n\r\nhello",
+        "HTTP/1.1 two hundred\r\n\r\n",
+    ] {
+        let server = origin(move |mut stream| {
+            read_until(&mut stream, b"\r\n\r\n");
+            let _ = stream.write_all(head.as_bytes());
+        });
+        let proxy = Proxy::start(&[]);
+
+        let mut client = proxy.connect();
+        write!(
+            client,
+            "GET http://{server}/ HTTP/1.1\r\nHost: {server}\r\n\r\n"
+        )
+        .unwrap();
+        let response = read_response(&mut client);
+        assert!(response.starts_with("HTTP/1.1 502"), "{head:?}: {response}");
+        assert!(response.ends_with("The server sent a malformed response\n"));
+    }
+}
+
+#[test]
+fn request_and_response_pass_through_a_server_reading_and_writing_in_small_pieces() {
+    const BODY_LEN: usize = 256 * 1024;
+    let server = origin(|mut stream| {
+        read_until(&mut stream, b"\r\n\r\n");
+        let mut body = Vec::new();
+        let mut piece = [0; 7];
+        while body.len() < BODY_LEN {
+            match stream.read(&mut piece) {
+                Ok(len @ 1..) => body.extend_from_slice(&piece[..len]),
+                _ => return,
+            }
+        }
+        let intact = body.iter().all(|&byte| byte == b'x');
+        let message = format!("received {} intact={intact}", body.len());
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{message}",
+            message.len()
+        );
+        for piece in response.as_bytes().chunks(3) {
+            stream.write_all(piece).unwrap();
+            thread::sleep(Duration::from_millis(1));
+        }
+    });
+    let proxy = Proxy::start(&[]);
+
+    let mut client = proxy.connect();
+    let head = format!(
+        "POST http://{server}/ HTTP/1.1\r\nHost: {server}\r\nContent-Length: {BODY_LEN}\r\n\r\n"
+    );
+    client.write_all(head.as_bytes()).unwrap();
+    client.write_all(&vec![b'x'; BODY_LEN]).unwrap();
+    let response = read_response(&mut client);
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    assert!(
+        response.ends_with(&format!("received {BODY_LEN} intact=true")),
+        "{response}"
+    );
+}
+
+#[test]
+fn stats_server_answers_health_checks_while_a_client_trickles_its_request() {
+    let stats = free_address();
+    let _proxy = Proxy::start(&["--stats-listen", &stats.to_string()]);
+    wait_for(stats);
+
+    let mut slow = TcpStream::connect(stats).unwrap();
+    slow.write_all(b"GET /sta").unwrap();
+    let started = Instant::now();
+    assert!(ask_stats(stats, "GET /health HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 200"));
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+/// Opens a connection through `proxy` to `server` and keeps it open, once the proxy's
+/// connection limit leaves room for it past the one made waiting for the proxy to start
+fn hold_only_connection(proxy: &Proxy, server: SocketAddr) -> TcpStream {
+    let mut held = None;
+    eventually("The first connection was never served", || {
+        let mut client = proxy.connect();
+        write!(
+            client,
+            "GET http://{server}/ HTTP/1.1\r\nHost: {server}\r\n\r\n"
+        )
+        .unwrap();
+        let served = read_response(&mut client).starts_with("HTTP/1.1 200");
+        held = served.then_some(client);
+        served
+    });
+    held.unwrap()
+}
+
+#[test]
+fn connections_over_the_limit_are_answered_with_503_and_logged() {
+    let server = echo_origin();
+    let proxy = Proxy::start(&["--max-connections", "1", "--overflow", "reject"]);
+
+    let mut held = hold_only_connection(&proxy, server);
+
+    let mut turned_away = proxy.connect();
+    let mut response = String::new();
+    turned_away.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 503"), "{response}");
+    proxy.logged("Rejected connection at the connection limit");
+
+    // the held connection is still served, and once it closes there is room again
+    write!(
+        held,
+        "GET http://{server}/again HTTP/1.1\r\nHost: {server}\r\n\r\n"
+    )
+    .unwrap();
+    assert!(read_response(&mut held).ends_with("GET /again HTTP/1.1"));
+    drop(held);
+    eventually("No room was made once the held connection closed", || {
+        let mut client = proxy.connect();
+        write!(
+            client,
+            "GET http://{server}/ HTTP/1.1\r\nHost: {server}\r\n\r\n"
+        )
+        .unwrap();
+        read_response(&mut client).starts_with("HTTP/1.1 200")
+    });
+}
+
+#[test]
+fn connections_over_the_limit_wait_for_room_by_default() {
+    let server = echo_origin();
+    let proxy = Proxy::start(&["--max-connections", "1"]);
+
+    let held = hold_only_connection(&proxy, server);
+
+    let mut waiting = proxy.connect();
+    write!(
+        waiting,
+        "GET http://{server}/waited HTTP/1.1\r\nHost: {server}\r\n\r\n"
+    )
+    .unwrap();
+    waiting
+        .set_read_timeout(Some(Duration::from_millis(300)))
+        .unwrap();
+    assert!(
+        waiting.read(&mut [0; 1]).is_err(),
+        "The waiting connection was answered"
+    );
+
+    drop(held);
+    waiting
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    assert!(read_response(&mut waiting).ends_with("GET /waited HTTP/1.1"));
+}
+
+/// Makes a request for `path` from `server` over a new connection to `proxy`, returning the
+/// response
+fn get_through(proxy: &Proxy, server: SocketAddr, path: &str) -> String {
+    let mut client = proxy.connect();
+    write!(
+        client,
+        "GET http://{server}{path} HTTP/1.1\r\nHost: {server}\r\n\r\n"
+    )
+    .unwrap();
+    read_response(&mut client)
+}
+
+#[test]
+fn connections_over_the_accept_rate_are_refused_with_the_reject_policy() {
+    let server = echo_origin();
+    let proxy = Proxy::start(&["--accept-rate", "2", "--accept-rate-policy", "reject"]);
+
+    let started = Instant::now();
+    let responses: Vec<_> = (0..6).map(|_| get_through(&proxy, server, "/")).collect();
+    assert!(started.elapsed() < Duration::from_millis(500));
+    let refused = responses
+        .iter()
+        .filter(|response| response.starts_with("HTTP/1.1 503"))
+        .count();
+    // the burst of two covers at most the startup probe and one more
+    assert!(refused >= 4, "{responses:?}");
+    proxy.logged("Rejected connection at the accept rate limit");
+}
+
+#[test]
+fn connections_over_the_accept_rate_are_delayed_by_default() {
+    let server = echo_origin();
+    let proxy = Proxy::start(&["--accept-rate", "4"]);
+
+    let started = Instant::now();
+    for _ in 0..8 {
+        assert!(get_through(&proxy, server, "/").starts_with("HTTP/1.1 200"));
+    }
+    // four may go at once, and each after that waits a quarter of a second for its turn
+    assert!(
+        started.elapsed() >= Duration::from_millis(750),
+        "{:?}",
+        started.elapsed()
+    );
+}
+
+#[test]
+fn teed_bytes_match_the_bytes_forwarded_each_way() {
+    let (seen_tx, seen_rx) = std::sync::mpsc::channel();
+    let seen_tx = std::sync::Mutex::new(seen_tx);
+    let server = origin(move |mut stream| {
+        let request = read_until(&mut stream, b"\r\n\r\n");
+        seen_tx.lock().unwrap().send(request).unwrap();
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nX-From: origin\r\n\r\nhello")
+            .unwrap();
+    });
+    let tee_path = std::env::temp_dir().join(format!("proxy_server-tee-{}", std::process::id()));
+    let _ = std::fs::remove_file(&tee_path);
+    let proxy = Proxy::start(&[
+        "--tee",
+        tee_path.to_str().unwrap(),
+        "--tee-direction",
+        "both",
+    ]);
+
+    let mut client = proxy.connect();
+    write!(
+        client,
+        "GET http://{server}/teed HTTP/1.1\r\nHost: {server}\r\nProxy-Connection: keep-alive\r\n\r\n"
+    )
+    .unwrap();
+    let response = read_response(&mut client);
+    let forwarded = seen_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    let mut expected = forwarded;
+    expected.extend_from_slice(response.as_bytes());
+    let mut teed = Vec::new();
+    eventually("The tee file never caught up", || {
+        teed = std::fs::read(&tee_path).unwrap_or_default();
+        teed.len() >= expected.len()
+    });
+    assert_eq!(
+        String::from_utf8_lossy(&teed),
+        String::from_utf8_lossy(&expected)
+    );
+    std::fs::remove_file(&tee_path).unwrap();
+}
+
+#[test]
+fn full_tunnel_limit_leaves_plain_requests_alone() {
+    let tunnel_server = raw_echo_origin();
+    let server = echo_origin();
+    let proxy = Proxy::start(&["--max-tunnels", "1"]);
+
+    let mut tunnel = proxy.connect();
+    write!(tunnel, "CONNECT {tunnel_server} HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(
+        read_until(&mut tunnel, b"\r\n\r\n"),
+        b"HTTP/1.1 200 OK\r\n\r\n"
+    );
+
+    let mut refused = proxy.connect();
+    write!(refused, "CONNECT {tunnel_server} HTTP/1.1\r\n\r\n").unwrap();
+    let response = read_response(&mut refused);
+    assert!(response.starts_with("HTTP/1.1 503"), "{response}");
+    proxy.logged("at the limit of 1 tunnels");
+
+    for path in ["/one", "/two"] {
+        let response = get_through(&proxy, server, path);
+        assert!(
+            response.ends_with(&format!("GET {path} HTTP/1.1")),
+            "{response}"
+        );
+    }
+
+    tunnel.write_all(b"still open").unwrap();
+    let mut echoed = [0; 10];
+    tunnel.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"still open");
+}
+
+#[test]
+fn matched_route_name_is_logged_with_the_request() {
+    let server = echo_origin();
+    let proxy = Proxy::start(&[
+        "--log-format",
+        "combined",
+        "--log-route",
+        "--route",
+        "host=127.0.0.1,name=loopback",
+    ]);
+    assert!(get_through(&proxy, server, "/routed").starts_with("HTTP/1.1 200"));
+    let line = proxy.logged("GET http://127.0.0.1");
+    assert!(line.contains("/routed HTTP/1.1\" 200 "), "{line}");
+    assert!(line.ends_with(" \"loopback\""), "{line}");
+
+    let proxy = Proxy::start(&["--log-route", "--route", "host=127.0.0.1,name=loopback"]);
+    assert!(get_through(&proxy, server, "/").starts_with("HTTP/1.1 200"));
+    proxy.logged("Matched route loopback");
+}
+
+/// Waits for `proxy` to log the end of the connection from `client`, returning the line
+fn connection_logged(proxy: &Proxy, client: SocketAddr) -> String {
+    proxy.logged(&format!("connection client={client} "))
+}
+
+#[test]
+fn each_way_a_connection_ends_is_logged_with_its_outcome() {
+    let server = echo_origin();
+    let proxy = Proxy::start(&["--log-connections"]);
+
+    let mut served = proxy.connect();
+    write!(
+        served,
+        "GET http://{server}/ HTTP/1.1\r\nHost: {server}\r\n\r\n"
+    )
+    .unwrap();
+    read_response(&mut served);
+    let served_addr = served.local_addr().unwrap();
+    drop(served);
+    assert!(connection_logged(&proxy, served_addr).contains(" outcome=served "));
+
+    let gone = proxy.connect();
+    let gone_addr = gone.local_addr().unwrap();
+    drop(gone);
+    assert!(connection_logged(&proxy, gone_addr).contains(" outcome=client-gone "));
+
+    let mut malformed = proxy.connect();
+    malformed
+        .write_all(b"GET /page HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .unwrap();
+    assert!(read_response(&mut malformed).starts_with("HTTP/1.1 400"));
+    let line = connection_logged(&proxy, malformed.local_addr().unwrap());
+    assert!(line.contains(" outcome=protocol-error "), "{line}");
+
+    let unreachable = free_address();
+    let mut failed = proxy.connect();
+    write!(
+        failed,
+        "GET http://{unreachable}/ HTTP/1.1\r\nHost: {unreachable}\r\n\r\n"
+    )
+    .unwrap();
+    assert!(read_response(&mut failed).starts_with("HTTP/1.1 502"));
+    let line = connection_logged(&proxy, failed.local_addr().unwrap());
+    assert!(line.contains(" outcome=error "), "{line}");
+
+    let proxy = Proxy::start(&["--log-connections", "--egress-cidr", "10.0.0.0/8"]);
+    let mut denied = proxy.connect();
+    write!(
+        denied,
+        "GET http://{server}/ HTTP/1.1\r\nHost: {server}\r\n\r\n"
+    )
+    .unwrap();
+    assert!(read_response(&mut denied).starts_with("HTTP/1.1 403"));
+    let line = connection_logged(&proxy, denied.local_addr().unwrap());
+    assert!(line.contains(" outcome=denied-acl "), "{line}");
+
+    let proxy = Proxy::start(&["--log-connections", "--allow-client-cidr", "10.0.0.0/8"]);
+    let mut refused = proxy.connect();
+    let _ = refused.read_to_end(&mut Vec::new());
+    let line = connection_logged(&proxy, refused.local_addr().unwrap());
+    assert!(line.contains(" outcome=rejected-source "), "{line}");
+
+    let proxy = Proxy::start(&[
+        "--log-connections",
+        "--max-connections",
+        "1",
+        "--overflow",
+        "reject",
+    ]);
+    let _held = hold_only_connection(&proxy, server);
+    let mut turned_away = proxy.connect();
+    let _ = turned_away.read_to_end(&mut Vec::new());
+    let line = connection_logged(&proxy, turned_away.local_addr().unwrap());
+    assert!(line.contains(" outcome=rejected-limit "), "{line}");
+}
+
+#[test]
+fn what_closed_a_connection_is_logged_as_its_reason() {
+    let server = echo_origin();
+    let unreachable = free_address();
+    let silent = silent_origin();
+    let closing = origin(drop);
+    let proxy = Proxy::start(&[
+        "--log-connections",
+        "--idle-timeout",
+        "1",
+        "--response-timeout",
+        "1",
+    ]);
+    let reason = |client: &TcpStream| {
+        let line = connection_logged(&proxy, client.local_addr().unwrap());
+        line.split(" reason=")
+            .nth(1)
+            .and_then(|rest| rest.split(' ').next())
+            .unwrap()
+            .to_owned()
+    };
+
+    let mut closed_by_client = proxy.connect();
+    write!(
+        closed_by_client,
+        "GET http://{server}/ HTTP/1.1\r\nHost: {server}\r\n\r\n"
+    )
+    .unwrap();
+    read_response(&mut closed_by_client);
+    closed_by_client.shutdown(std::net::Shutdown::Both).unwrap();
+    assert_eq!(reason(&closed_by_client), "client-eof");
+
+    let idle = proxy.connect();
+    assert_eq!(reason(&idle), "idle-timeout");
+
+    let mut refused = proxy.connect();
+    write!(
+        refused,
+        "GET http://{unreachable}/ HTTP/1.1\r\nHost: {unreachable}\r\n\r\n"
+    )
+    .unwrap();
+    assert!(read_response(&mut refused).starts_with("HTTP/1.1 502"));
+    assert_eq!(reason(&refused), "upstream-connect-fail");
+
+    let mut unanswered = proxy.connect();
+    write!(
+        unanswered,
+        "GET http://{silent}/ HTTP/1.1\r\nHost: {silent}\r\n\r\n"
+    )
+    .unwrap();
+    assert!(read_response(&mut unanswered).starts_with("HTTP/1.1 504"));
+    assert_eq!(reason(&unanswered), "response-timeout");
+
+    let mut tunnel = proxy.connect();
+    write!(tunnel, "CONNECT {closing} HTTP/1.1\r\n\r\n").unwrap();
+    read_until(&mut tunnel, b"\r\n\r\n");
+    // the server's end is passed on, and the client closes in turn
+    tunnel.read_to_end(&mut Vec::new()).unwrap();
+    tunnel.shutdown(std::net::Shutdown::Both).unwrap();
+    assert_eq!(reason(&tunnel), "server-eof");
+
+    let proxy = Proxy::start(&["--log-connections", "--egress-cidr", "10.0.0.0/8"]);
+    let mut denied = proxy.connect();
+    write!(
+        denied,
+        "GET http://{server}/ HTTP/1.1\r\nHost: {server}\r\n\r\n"
+    )
+    .unwrap();
+    assert!(read_response(&mut denied).starts_with("HTTP/1.1 403"));
+    let line = connection_logged(&proxy, denied.local_addr().unwrap());
+    assert!(line.contains(" reason=acl-deny "), "{line}");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn connections_to_servers_come_from_the_source_port_range() {
+    let server = origin(|mut stream| {
+        read_until(&mut stream, b"\r\n\r\n");
+        let port = stream.peer_addr().unwrap().port().to_string();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{port}",
+            port.len()
+        );
+        let _ = stream.write_all(response.as_bytes());
+    });
+    let proxy = Proxy::start(&["--source-port-range", "47100-47149"]);
+
+    for _ in 0..3 {
+        let response = get_through(&proxy, server, "/");
+        let port: u16 = response.rsplit("\r\n").next().unwrap().parse().unwrap();
+        assert!((47100..=47149).contains(&port), "{response}");
+    }
+}
+
+#[test]
+fn stats_are_logged_every_interval_with_their_growth() {
+    let server = echo_origin();
+    let proxy = Proxy::start(&["--stats-interval", "1"]);
+    let total = |line: &str| -> u64 {
+        let value = line.split("total_connections=").nth(1).unwrap();
+        value.split(' ').next().unwrap().parse().unwrap()
+    };
+
+    let first = proxy.logged("Stats: ");
+    assert!(first.contains(" active_connections="), "{first}");
+    assert!(first.contains(" bytes_forwarded="), "{first}");
+    for _ in 0..2 {
+        assert!(get_through(&proxy, server, "/").starts_with("HTTP/1.1 200"));
+    }
+
+    let mut grown = None;
+    eventually("No later stats line counted the new connections", || {
+        let log = std::fs::read_to_string(&proxy.log_path).unwrap();
+        grown = log
+            .lines()
+            .filter(|line| line.contains("Stats: "))
+            .find(|line| total(line) >= total(&first) + 2)
+            .map(str::to_owned);
+        grown.is_some()
+    });
+    let grown = grown.unwrap();
+    assert!(grown.contains(" (+"), "{grown}");
+}
+
+#[test]
+fn requests_without_a_user_agent_are_refused_when_one_is_required() {
+    let server = echo_origin();
+    let proxy = Proxy::start(&["--require-user-agent"]);
+
+    for user_agent in ["", "User-Agent:  \r\n"] {
+        let mut client = proxy.connect();
+        write!(
+            client,
+            "GET http://{server}/ HTTP/1.1\r\nHost: {server}\r\n{user_agent}\r\n"
+        )
+        .unwrap();
+        let response = read_response(&mut client);
+        assert!(response.starts_with("HTTP/1.1 403"), "{response}");
+        assert!(
+            response.contains("must have a User-Agent header"),
+            "{response}"
+        );
+    }
+
+    let mut client = proxy.connect();
+    write!(
+        client,
+        "GET http://{server}/ HTTP/1.1\r\nHost: {server}\r\nUser-Agent: test/1.0\r\n\r\n"
+    )
+    .unwrap();
+    assert!(read_response(&mut client).starts_with("HTTP/1.1 200"));
+}
+
+#[test]
+fn plain_request_for_an_https_url_is_answered_with_400() {
+    let (seen_tx, seen_rx) = std::sync::mpsc::channel();
+    let seen_tx = std::sync::Mutex::new(seen_tx);
+    let server = origin(move |_| {
+        let _ = seen_tx.lock().unwrap().send(());
+    });
+    let proxy = Proxy::start(&[]);
+
+    let mut client = proxy.connect();
+    write!(
+        client,
+        "GET https://{server}/ HTTP/1.1\r\nHost: {server}\r\n\r\n"
+    )
+    .unwrap();
+    let response = read_response(&mut client);
+    assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+    assert!(
+        response.contains("https URLs must be requested through a CONNECT tunnel"),
+        "{response}"
+    );
+    assert!(
+        seen_rx.recv_timeout(Duration::from_millis(300)).is_err(),
+        "The proxy connected to the server"
+    );
+}
+
+#[test]
+fn servers_are_only_reached_inside_the_egress_networks() {
+    let server = echo_origin();
+    let tunnel_server = raw_echo_origin();
+
+    let inside = Proxy::start(&[
+        "--egress-cidr",
+        "10.0.0.0/8",
+        "--egress-cidr",
+        "127.0.0.0/8",
+    ]);
+    assert!(get_through(&inside, server, "/").starts_with("HTTP/1.1 200"));
+    let mut tunnel = inside.connect();
+    write!(tunnel, "CONNECT {tunnel_server} HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(
+        read_until(&mut tunnel, b"\r\n\r\n"),
+        b"HTTP/1.1 200 OK\r\n\r\n"
+    );
+
+    let outside = Proxy::start(&["--egress-cidr", "10.0.0.0/8"]);
+    let response = get_through(&outside, server, "/");
+    assert!(response.starts_with("HTTP/1.1 403"), "{response}");
+    assert!(response.contains("The proxy may not connect to this server"));
+    let mut tunnel = outside.connect();
+    write!(tunnel, "CONNECT {tunnel_server} HTTP/1.1\r\n\r\n").unwrap();
+    let response = read_response(&mut tunnel);
+    assert!(response.starts_with("HTTP/1.1 403"), "{response}");
+    outside.logged("which is outside the egress networks");
+}
+
+#[test]
+fn connection_asking_for_too_many_hosts_is_closed() {
+    let server = echo_origin();
+    let port = server.port();
+    let proxy = Proxy::start(&["--max-hosts-per-conn", "1"]);
+
+    let mut client = proxy.connect();
+    for path in ["/first", "/again"] {
+        write!(
+            client,
+            "GET http://{server}{path} HTTP/1.1\r\nHost: {server}\r\n\r\n"
+        )
+        .unwrap();
+        let response = read_response(&mut client);
+        assert!(
+            response.ends_with(&format!("GET {path} HTTP/1.1")),
+            "{response}"
+        );
+    }
+    write!(
+        client,
+        "GET http://other.test:{port}/ HTTP/1.1\r\nHost: other.test:{port}\r\n\r\n"
+    )
+    .unwrap();
+    let mut rest = Vec::new();
+    client.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty(), "{}", String::from_utf8_lossy(&rest));
+    proxy.logged("after requests for more than 1 hosts");
+
+    // the count is per connection, so a new one starts over
+    assert!(get_through(&proxy, server, "/").starts_with("HTTP/1.1 200"));
+}
+
+#[test]
+fn busy_tunnel_is_closed_at_its_maximum_duration() {
+    let server = raw_echo_origin();
+    let proxy = Proxy::start(&["--max-tunnel-duration", "1", "--max-duration", "60"]);
+
+    let mut tunnel = proxy.connect();
+    write!(tunnel, "CONNECT {server} HTTP/1.1\r\n\r\n").unwrap();
+    read_until(&mut tunnel, b"\r\n\r\n");
+    let started = Instant::now();
+    // traffic keeps the tunnel from going idle until the duration runs out
+    let mut echoed = [0; 4];
+    while tunnel.write_all(b"ping").is_ok() && tunnel.read_exact(&mut echoed).is_ok() {
+        assert!(
+            started.elapsed() < Duration::from_secs(4),
+            "The tunnel outlived its maximum duration"
+        );
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(started.elapsed() >= Duration::from_millis(900));
+    proxy.logged("Connection closed after reaching its maximum duration");
+}
+
+#[test]
+fn idle_tunnel_outlasts_the_http_idle_timeout_until_its_own() {
+    let server = raw_echo_origin();
+    let proxy = Proxy::start(&["--idle-timeout", "1", "--tunnel-idle-timeout", "2"]);
+
+    let mut tunnel = proxy.connect();
+    write!(tunnel, "CONNECT {server} HTTP/1.1\r\n\r\n").unwrap();
+    read_until(&mut tunnel, b"\r\n\r\n");
+    thread::sleep(Duration::from_millis(1500));
+    tunnel.write_all(b"ping").unwrap();
+    let mut echoed = [0; 4];
+    tunnel.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"ping");
+
+    let quiet_since = Instant::now();
+    let mut rest = Vec::new();
+    tunnel.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+    let quiet = quiet_since.elapsed();
+    assert!(
+        quiet >= Duration::from_millis(1800) && quiet < Duration::from_secs(4),
+        "{quiet:?}"
+    );
+}
+
+/// The processor time the process `pid` has used, in clock ticks
+#[cfg(target_os = "linux")]
+fn cpu_ticks(pid: u32) -> u64 {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).unwrap();
+    // the fields after the parenthesized command name, starting from the state
+    let fields: Vec<_> = stat
+        .rsplit_once(')')
+        .unwrap()
+        .1
+        .split_whitespace()
+        .collect();
+    fields[11].parse::<u64>().unwrap() + fields[12].parse::<u64>().unwrap()
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn quiet_tunnel_does_not_spin() {
+    let server = raw_echo_origin();
+    let proxy = Proxy::start(&[]);
+
+    let mut tunnel = proxy.connect();
+    write!(tunnel, "CONNECT {server} HTTP/1.1\r\n\r\n").unwrap();
+    read_until(&mut tunnel, b"\r\n\r\n");
+    let before = cpu_ticks(proxy.child.id());
+    thread::sleep(Duration::from_secs(1));
+    let used = cpu_ticks(proxy.child.id()) - before;
+    // a busy loop takes as much of the second as it is given, up to a hundred ticks
+    assert!(used < 20, "{used} ticks used by an idle tunnel");
+
+    tunnel.write_all(b"awake").unwrap();
+    let mut echoed = [0; 5];
+    tunnel.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"awake");
+}
+
+#[test]
+fn lookups_queued_at_the_resolve_limit_are_all_served() {
+    let server = echo_origin();
+    let port = server.port();
+    let proxy = Proxy::start(&["--max-concurrent-resolves", "1"]);
+
+    // every request needs a lookup of its own, and each waits its turn for the one permit
+    let responses: Vec<_> = thread::scope(|scope| {
+        let requests: Vec<_> = (0..8)
+            .map(|i| {
+                let proxy = &proxy;
+                scope.spawn(move || {
+                    let mut client = proxy.connect();
+                    write!(
+                        client,
+                        "GET http://localhost:{port}/{i} HTTP/1.1\r\nHost: localhost:{port}\r\n\r\n"
+                    )
+                    .unwrap();
+                    read_response(&mut client)
+                })
+            })
+            .collect();
+        requests
+            .into_iter()
+            .map(|request| request.join().unwrap())
+            .collect()
+    });
+    for (i, response) in responses.iter().enumerate() {
+        assert!(
+            response.ends_with(&format!("GET /{i} HTTP/1.1")),
+            "{response}"
+        );
+    }
+}
+
+/// Starts a server whose accept queue is full, so connecting to it hangs until it times out
+#[cfg(target_os = "linux")]
+fn stalled_origin() -> SocketAddr {
+    use std::os::{fd::AsRawFd, raw::c_int};
+    extern "C" {
+        fn listen(socket: c_int, backlog: c_int) -> c_int;
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    // listening again shrinks the queue to the one connection that then fills it
+    // SAFETY: the descriptor belongs to the listener, which stays open
+    assert_eq!(unsafe { listen(listener.as_raw_fd(), 0) }, 0);
+    let queued = TcpStream::connect(address).unwrap();
+    thread::spawn(move || {
+        let _kept = (listener, queued);
+        thread::sleep(Duration::from_secs(60));
+    });
+    address
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn setups_past_the_limit_wait_while_open_tunnels_keep_flowing() {
+    let server = raw_echo_origin();
+    let stalled = stalled_origin();
+    let proxy = Proxy::start(&["--max-concurrent-setups", "1", "--connect-timeout", "2"]);
+
+    let mut tunnel = proxy.connect();
+    write!(tunnel, "CONNECT {server} HTTP/1.1\r\n\r\n").unwrap();
+    read_until(&mut tunnel, b"\r\n\r\n");
+
+    // this takes the only setup permit until its connect times out
+    let mut stuck = proxy.connect();
+    write!(
+        stuck,
+        "GET http://{stalled}/ HTTP/1.1\r\nHost: {stalled}\r\n\r\n"
+    )
+    .unwrap();
+    thread::sleep(Duration::from_millis(300));
+
+    let started = Instant::now();
+    let mut queued = proxy.connect();
+    write!(queued, "CONNECT {server} HTTP/1.1\r\n\r\n").unwrap();
+    queued
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    assert!(
+        queued.read(&mut [0; 1]).is_err(),
+        "A setup went ahead past the limit"
+    );
+
+    tunnel.write_all(b"flowing").unwrap();
+    let mut echoed = [0; 7];
+    tunnel.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"flowing");
+
+    queued
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    assert_eq!(
+        read_until(&mut queued, b"\r\n\r\n"),
+        b"HTTP/1.1 200 OK\r\n\r\n"
+    );
+    assert!(started.elapsed() >= Duration::from_secs(1));
+    assert!(read_response(&mut stuck).starts_with("HTTP/1.1 50"));
+}